@@ -0,0 +1,204 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::config;
+use crate::reload::{self, Reloader};
+use crate::sessions::SessionTable;
+
+/// 管理接口的监听地址：回环地址上的 TCP 端口，或 `unix:` 开头的 Unix 套接字路径
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for AdminAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Unix 套接字路径不能为空".to_string());
+            }
+            return Ok(AdminAddr::Unix(PathBuf::from(path)));
+        }
+
+        let addr: SocketAddr = s.parse().map_err(|_| format!("无效的管理接口地址: {}", s))?;
+        // 管理接口可以终止会话，不允许暴露到网络上
+        if !addr.ip().is_loopback() {
+            return Err(format!("管理接口只能监听回环地址或 Unix 套接字: {}", s));
+        }
+        Ok(AdminAddr::Tcp(addr))
+    }
+}
+
+impl fmt::Display for AdminAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminAddr::Tcp(addr) => write!(f, "{}", addr),
+            AdminAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for AdminAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AdminAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        config::deserialize_from_str(deserializer)
+    }
+}
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<str>,
+    sessions: Arc<SessionTable>,
+    reloader: Reloader,
+}
+
+#[derive(Deserialize)]
+struct SessionFilter {
+    user: Option<String>,
+}
+
+/// 启动管理接口，所有请求都要带 `Authorization: Bearer <token>`
+///
+/// - `GET /sessions[?user=名字]`：列出活跃会话及其流
+/// - `DELETE /sessions/{id}`：终止一个会话
+/// - `DELETE /users/{name}/sessions`：终止用户的所有会话
+/// - `POST /reload`：重新加载配置和用户数据库，与 SIGHUP 相同
+pub async fn serve(addr: AdminAddr, token: String, sessions: Arc<SessionTable>, reloader: Reloader) -> Result<()> {
+    if token.is_empty() {
+        return Err(anyhow!("管理接口的 token 不能为空"));
+    }
+
+    let state = AdminState {
+        token: token.into(),
+        sessions,
+        reloader,
+    };
+    let app = Router::new()
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{id}", delete(terminate_session))
+        .route("/users/{name}/sessions", delete(terminate_user))
+        .route("/reload", post(reload))
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state);
+
+    info!("管理接口监听在 {}", addr);
+    match addr {
+        AdminAddr::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("管理接口无法监听 {}", addr))?;
+            axum::serve(listener, app).await?;
+        }
+        #[cfg(unix)]
+        AdminAddr::Unix(path) => {
+            use std::os::unix::fs::PermissionsExt;
+
+            // 上次运行留下的套接字文件会导致绑定失败
+            let _ = std::fs::remove_file(&path);
+            let listener = tokio::net::UnixListener::bind(&path)
+                .with_context(|| format!("管理接口无法监听 {}", path.display()))?;
+            // 只有服务器的运行用户可以连接
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            axum::serve(listener, app).await?;
+        }
+        #[cfg(not(unix))]
+        AdminAddr::Unix(_) => return Err(anyhow!("当前平台不支持 Unix 套接字")),
+    }
+    Ok(())
+}
+
+async fn require_token(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), state.token.as_bytes()));
+
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "无效的管理 token" }))).into_response();
+    }
+    next.run(request).await
+}
+
+async fn list_sessions(State(state): State<AdminState>, Query(filter): Query<SessionFilter>) -> impl IntoResponse {
+    Json(state.sessions.snapshot(filter.user.as_deref()))
+}
+
+async fn terminate_session(State(state): State<AdminState>, Path(id): Path<String>) -> Response {
+    if state.sessions.terminate(&id) {
+        info!("管理接口终止会话 {}", id);
+        Json(json!({ "terminated": 1 })).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, Json(json!({ "error": "会话不存在" }))).into_response()
+    }
+}
+
+async fn terminate_user(State(state): State<AdminState>, Path(name): Path<String>) -> impl IntoResponse {
+    let terminated = state.sessions.terminate_user(&name);
+    info!("管理接口终止用户 {} 的 {} 个会话", name, terminated);
+    Json(json!({ "terminated": terminated }))
+}
+
+async fn reload(State(state): State<AdminState>) -> Response {
+    info!("管理接口请求重新加载配置");
+    // 重载读取本地文件，不占用异步工作线程
+    let reloader = state.reloader.clone();
+    match tokio::task::spawn_blocking(move || reload::run(&reloader)).await {
+        Ok(Ok(summary)) => Json(summary).into_response(),
+        Ok(Err(e)) => (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({ "error": format!("{:#}", e) }))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))).into_response(),
+    }
+}
+
+/// 比较时间只取决于长度，不泄露 token 的前缀
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_addr() {
+        assert_eq!(
+            "127.0.0.1:9090".parse::<AdminAddr>().unwrap(),
+            AdminAddr::Tcp("127.0.0.1:9090".parse().unwrap())
+        );
+        assert!("[::1]:9090".parse::<AdminAddr>().is_ok());
+        assert_eq!(
+            "unix:/run/leaf.sock".parse::<AdminAddr>().unwrap(),
+            AdminAddr::Unix(PathBuf::from("/run/leaf.sock"))
+        );
+        assert!("0.0.0.0:9090".parse::<AdminAddr>().is_err());
+        assert!("unix:".parse::<AdminAddr>().is_err());
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// SOCKS5 用户名/密码认证方法 (RFC 1929)
+pub const USERNAME_PASSWORD: u8 = 0x02;
+
+/// 用户名/密码子协商的版本号
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0x01;
+
+/// SOCKS5 监听器的用户名/密码凭据
+/// 配置了任意用户后，所有客户端都必须通过认证
+#[derive(Debug, Clone, Default)]
+pub struct SocksAuth {
+    users: HashMap<String, String>,
+}
+
+impl SocksAuth {
+    /// 从 "用户名:密码" 格式的命令行参数构建
+    pub fn from_entries(entries: &[String]) -> Result<Self> {
+        let mut users = HashMap::new();
+        for entry in entries {
+            let (username, password) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("无效的用户凭据，格式应为 用户名:密码"))?;
+            if username.is_empty() || username.len() > 255 || password.len() > 255 {
+                return Err(anyhow!("用户名和密码长度必须在 1 到 255 字节之间"));
+            }
+            users.insert(username.to_string(), password.to_string());
+        }
+
+        Ok(Self { users })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        self.users.get(username).is_some_and(|expected| expected == password)
+    }
+
+    /// 检查监听地址的认证策略
+    /// `require_auth` 打开时，非回环地址上的监听器必须配置用户凭据
+    pub fn check_listener(&self, listen_addr: SocketAddr, require_auth: bool) -> Result<()> {
+        if listen_addr.ip().is_loopback() || self.is_enabled() {
+            return Ok(());
+        }
+
+        if require_auth {
+            return Err(anyhow!(
+                "监听在非回环地址 {} 上时必须配置 SOCKS5 用户凭据",
+                listen_addr
+            ));
+        }
+
+        warn!("SOCKS5 监听在非回环地址 {} 上且未启用认证，局域网内任何人都可以使用代理", listen_addr);
+        Ok(())
+    }
+}
+
+/// 执行用户名/密码子协商，成功时返回用户名
+/// 格式: [VER][ULEN][UNAME][PLEN][PASSWD]，响应: [VER][STATUS]
+pub async fn authenticate<S>(client: &mut S, auth: &SocksAuth) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = client.read_u8().await?;
+    if version != AUTH_VERSION {
+        return Err(anyhow!("不支持的认证子协商版本: {}", version));
+    }
+
+    let username_len = client.read_u8().await? as usize;
+    let mut username = vec![0u8; username_len];
+    client.read_exact(&mut username).await?;
+
+    let password_len = client.read_u8().await? as usize;
+    let mut password = vec![0u8; password_len];
+    client.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username).into_owned();
+    let password = String::from_utf8_lossy(&password);
+
+    if !auth.verify(&username, &password) {
+        client.write_all(&[AUTH_VERSION, AUTH_FAILURE]).await?;
+        return Err(anyhow!("用户 {} 认证失败", username));
+    }
+
+    client.write_all(&[AUTH_VERSION, AUTH_SUCCESS]).await?;
+    info!("用户 {} 认证成功", username);
+    Ok(username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let auth = SocksAuth::from_entries(&["alice:secret".to_string()]).unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+        assert_eq!(authenticate(&mut server, &auth).await.unwrap(), "alice");
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [AUTH_VERSION, AUTH_SUCCESS]);
+
+        client.write_all(b"\x01\x05alice\x05wrong").await.unwrap();
+        assert!(authenticate(&mut server, &auth).await.is_err());
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [AUTH_VERSION, AUTH_FAILURE]);
+    }
+
+    #[test]
+    fn test_listener_policy() {
+        let none = SocksAuth::default();
+        let lan: SocketAddr = "0.0.0.0:1080".parse().unwrap();
+
+        assert!(none.check_listener("127.0.0.1:1080".parse().unwrap(), true).is_ok());
+        assert!(none.check_listener(lan, true).is_err());
+        assert!(none.check_listener(lan, false).is_ok());
+        assert!(SocksAuth::from_entries(&["bob".to_string()]).is_err());
+    }
+}
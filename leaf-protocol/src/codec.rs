@@ -0,0 +1,700 @@
+use bytes::{Buf, BufMut, BytesMut};
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
+};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead, FramedWrite};
+
+use crate::config;
+use crate::crypto::{CryptoManager, SessionCipher, SALT_LEN, TAG_LEN};
+use crate::noise::TransportKeys;
+use crate::replay::ReplayCache;
+
+/// 长度头的字节数
+const LENGTH_LEN: usize = 4;
+
+/// v2 中加密后的长度头字节数
+const SEALED_LENGTH_LEN: usize = LENGTH_LEN + TAG_LEN;
+
+/// v2 两个方向密钥的 HKDF info
+const CLIENT_TO_SERVER_INFO: &[u8] = b"leaf-v2 client->server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"leaf-v2 server->client";
+
+/// 默认的单帧上限（密文，不含长度头）
+/// 足够容纳最大的 UDP 数据报加上地址和 AEAD 开销
+pub const DEFAULT_MAX_FRAME_LEN: usize = 128 * 1024;
+
+/// 握手阶段的单帧上限；对方尚未认证，不允许它让服务器缓冲大帧
+pub const MAX_HANDSHAKE_FRAME_LEN: usize = 4 * 1024;
+
+/// 帧编解码错误
+#[derive(Debug)]
+pub enum FrameError {
+    /// 长度头超过上限；在分配缓冲区之前拒绝，连接应当立即关闭
+    TooLarge { length: usize, max: usize },
+    /// 连接在帧中途关闭
+    Truncated { received: usize, expected: usize },
+    /// 解密失败：密钥不匹配，或数据被篡改、重排、丢弃
+    Decrypt,
+    /// 会话盐已经出现过，连接是录制下来的重放
+    Replay,
+    Io(io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { length, max } => {
+                write!(f, "帧长度 {} 超过上限 {}", length, max)
+            }
+            FrameError::Truncated { received, expected } => {
+                write!(f, "连接在帧中途关闭，已收到 {} / {} 字节", received, expected)
+            }
+            FrameError::Decrypt => write!(f, "帧解密失败"),
+            FrameError::Replay => write!(f, "检测到重放的会话"),
+            FrameError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// 帧格式版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// 静态密钥加随机 nonce，长度头是明文；仅用于兼容旧版本
+    V1,
+    /// 每个会话、每个方向派生独立密钥，nonce 是计数器，长度头也加密
+    V2,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Framing::V1),
+            "v2" => Ok(Framing::V2),
+            _ => Err(format!("未知的帧格式: {}（可选 v1、v2）", s)),
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Framing::V1 => write!(f, "v1"),
+            Framing::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+impl Serialize for Framing {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Framing {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        config::deserialize_from_str(deserializer)
+    }
+}
+
+/// 连接中的角色，决定 v2 中各方向使用哪个密钥
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// v2 的单连接状态；拆分连接后读写两半各自只使用自己方向的部分
+#[derive(Clone, Default)]
+struct SessionState {
+    local_salt: Option<[u8; SALT_LEN]>,
+    peer_salt: Option<[u8; SALT_LEN]>,
+    sealer: Option<SessionCipher>,
+    opener: Option<SessionCipher>,
+    /// 已解密长度头、尚未收齐载荷的帧
+    pending_len: Option<usize>,
+    /// v1 中第一帧已经选定了密钥
+    key_selected: bool,
+    /// 对方的盐在第一个长度头解密成功后才记入重放缓存，
+    /// 没有密钥的扫描者无法用随机数据填满缓存
+    replay_checked: bool,
+}
+
+/// 带长度前缀的加密帧编解码器，握手和多路复用帧共用
+///
+/// v1 格式: [长度 u32 大端][nonce 和密文]
+///
+/// v2 格式: 每个方向先发送 32 字节的盐，之后每帧是
+/// [加密的长度 u32 大端 + 标签][密文 + 标签]。
+/// 客户端到服务器的密钥由客户端的盐派生；服务器到客户端的密钥由两个盐共同派生，
+/// 所以服务器的响应只对这一次请求有效
+///
+/// 使用 Noise 握手时两个方向的密钥来自握手本身，帧格式与 v2 相同但不发送盐
+#[derive(Clone)]
+pub struct FrameCodec {
+    crypto: CryptoManager,
+    /// 服务器端的其他候选密钥（例如用户各自的密钥），由对方的第一帧选定
+    alternate_keys: Arc<Vec<CryptoManager>>,
+    max_frame_len: usize,
+    framing: Framing,
+    role: Role,
+    replay_cache: Option<Arc<ReplayCache>>,
+    session: SessionState,
+}
+
+impl FrameCodec {
+    pub fn new(crypto: CryptoManager) -> Self {
+        Self {
+            crypto,
+            alternate_keys: Arc::new(Vec::new()),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            framing: Framing::V1,
+            role: Role::Client,
+            replay_cache: None,
+            session: SessionState::default(),
+        }
+    }
+
+    /// 设置单帧上限，超过上限的帧在读取载荷之前就被拒绝
+    pub fn max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// 服务器端接受的其他预共享密钥；第一帧用哪个密钥解密成功，整个连接就使用哪个
+    pub fn alternate_keys(mut self, keys: Vec<CryptoManager>) -> Self {
+        self.alternate_keys = Arc::new(keys);
+        self
+    }
+
+    /// 默认密钥和候选密钥的总数，即认证之前一次失败的握手要尝试的密钥数
+    pub fn candidate_count(&self) -> usize {
+        1 + self.alternate_keys.len()
+    }
+
+    /// 默认密钥和所有候选密钥
+    pub fn candidate_keys(&self) -> Vec<CryptoManager> {
+        std::iter::once(self.crypto.clone())
+            .chain(self.alternate_keys.iter().cloned())
+            .collect()
+    }
+
+    /// 服务器端共享的会话盐缓存，只在 v2 中使用
+    pub fn replay_cache(mut self, replay_cache: Arc<ReplayCache>) -> Self {
+        self.replay_cache = Some(replay_cache);
+        self
+    }
+
+    /// 使用 Noise 握手得到的会话密钥；按 v2 格式收发帧，但不再交换盐
+    pub fn transport_keys(mut self, keys: TransportKeys) -> Self {
+        self.crypto = keys.crypto;
+        self.framing = Framing::V2;
+        self.session = SessionState {
+            sealer: Some(keys.sealer),
+            opener: Some(keys.opener),
+            ..SessionState::default()
+        };
+        self
+    }
+
+    pub fn crypto(&self) -> &CryptoManager {
+        &self.crypto
+    }
+
+    pub fn frame_limit(&self) -> usize {
+        self.max_frame_len
+    }
+
+    /// 在连接中途调整单帧上限（例如握手完成之后），不影响会话状态
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    pub fn framing_version(&self) -> Framing {
+        self.framing
+    }
+
+    /// 根据对方的盐派生解密方向的密钥
+    fn peer_cipher(&self, peer_salt: &[u8; SALT_LEN]) -> Result<SessionCipher, FrameError> {
+        match self.role {
+            Role::Server => Ok(self
+                .crypto
+                .derive_session_cipher(peer_salt, CLIENT_TO_SERVER_INFO)),
+            Role::Client => {
+                let local_salt = self
+                    .session
+                    .local_salt
+                    .ok_or_else(|| io::Error::other("在发送请求之前收到了服务器数据"))?;
+                Ok(self
+                    .crypto
+                    .derive_session_cipher(&[local_salt, *peer_salt].concat(), SERVER_TO_CLIENT_INFO))
+            }
+        }
+    }
+
+    /// 生成本方向的盐并派生加密方向的密钥
+    fn local_cipher(&self, local_salt: &[u8; SALT_LEN]) -> Result<SessionCipher, FrameError> {
+        match self.role {
+            Role::Client => Ok(self
+                .crypto
+                .derive_session_cipher(local_salt, CLIENT_TO_SERVER_INFO)),
+            Role::Server => {
+                let peer_salt = self
+                    .session
+                    .peer_salt
+                    .ok_or_else(|| io::Error::other("在收到客户端请求之前发送数据"))?;
+                Ok(self
+                    .crypto
+                    .derive_session_cipher(&[peer_salt, *local_salt].concat(), SERVER_TO_CLIENT_INFO))
+            }
+        }
+    }
+
+    fn check_replay(&mut self) -> Result<(), FrameError> {
+        if self.session.replay_checked {
+            return Ok(());
+        }
+        self.session.replay_checked = true;
+
+        if let (Some(cache), Some(salt)) = (&self.replay_cache, self.session.peer_salt)
+            && !cache.insert(salt)
+        {
+            return Err(FrameError::Replay);
+        }
+        Ok(())
+    }
+
+    fn decode_v1(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, FrameError> {
+        if src.len() < LENGTH_LEN {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if length > self.max_frame_len {
+            return Err(FrameError::TooLarge {
+                length,
+                max: self.max_frame_len,
+            });
+        }
+
+        // 只为已经通过上限检查的帧预留空间
+        if src.len() < LENGTH_LEN + length {
+            src.reserve(LENGTH_LEN + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_LEN);
+        let encrypted = src.split_to(length);
+        let plaintext = match self.crypto.decrypt(&encrypted) {
+            Ok(plaintext) => plaintext,
+            Err(_) if !self.session.key_selected => self.decrypt_with_alternates(&encrypted)?,
+            Err(_) => return Err(FrameError::Decrypt),
+        };
+        self.session.key_selected = true;
+
+        Ok(Some(plaintext))
+    }
+
+    fn decrypt_with_alternates(&mut self, encrypted: &[u8]) -> Result<Vec<u8>, FrameError> {
+        for crypto in self.alternate_keys.iter() {
+            if let Ok(plaintext) = crypto.decrypt(encrypted) {
+                self.crypto = crypto.clone();
+                return Ok(plaintext);
+            }
+        }
+        Err(FrameError::Decrypt)
+    }
+
+    /// 解密对方的第一个长度头；默认密钥失败时依次尝试候选密钥
+    fn open_first_header(&mut self, header: &[u8]) -> Result<Vec<u8>, FrameError> {
+        if let Ok(plaintext) = self.opener()?.open(header) {
+            return Ok(plaintext);
+        }
+
+        // Noise 握手得到的密钥不交换盐，也没有候选密钥可以再试
+        let Some(peer_salt) = self.session.peer_salt else {
+            return Err(FrameError::Decrypt);
+        };
+        for crypto in self.alternate_keys.iter() {
+            let mut opener = crypto.derive_session_cipher(&peer_salt, CLIENT_TO_SERVER_INFO);
+            if let Ok(plaintext) = opener.open(header) {
+                self.crypto = crypto.clone();
+                self.session.opener = Some(opener);
+                return Ok(plaintext);
+            }
+        }
+        Err(FrameError::Decrypt)
+    }
+
+    fn decode_v2(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.session.opener.is_none() {
+            if src.len() < SALT_LEN {
+                return Ok(None);
+            }
+            let mut peer_salt = [0u8; SALT_LEN];
+            src.copy_to_slice(&mut peer_salt);
+            self.session.opener = Some(self.peer_cipher(&peer_salt)?);
+            self.session.peer_salt = Some(peer_salt);
+        }
+
+        let length = match self.session.pending_len {
+            Some(length) => length,
+            None => {
+                if src.len() < SEALED_LENGTH_LEN {
+                    return Ok(None);
+                }
+                let header = src.split_to(SEALED_LENGTH_LEN);
+                let header = if self.session.replay_checked {
+                    self.opener()?.open(&header).map_err(|_| FrameError::Decrypt)?
+                } else {
+                    self.open_first_header(&header)?
+                };
+                self.check_replay()?;
+
+                let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+                if length > self.max_frame_len {
+                    return Err(FrameError::TooLarge {
+                        length,
+                        max: self.max_frame_len,
+                    });
+                }
+                self.session.pending_len = Some(length);
+                length
+            }
+        };
+
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        self.session.pending_len = None;
+        let encrypted = src.split_to(length);
+        let plaintext = self.opener()?.open(&encrypted).map_err(|_| FrameError::Decrypt)?;
+
+        Ok(Some(plaintext))
+    }
+
+    fn opener(&mut self) -> Result<&mut SessionCipher, FrameError> {
+        self.session
+            .opener
+            .as_mut()
+            .ok_or_else(|| io::Error::other("会话密钥尚未建立").into())
+    }
+
+    /// 连接关闭时未收齐的字节数
+    fn expected_len(&self, src: &BytesMut) -> usize {
+        match self.framing {
+            Framing::V1 if src.len() < LENGTH_LEN => LENGTH_LEN,
+            Framing::V1 => LENGTH_LEN + u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize,
+            Framing::V2 if self.session.opener.is_none() => SALT_LEN,
+            Framing::V2 => self.session.pending_len.unwrap_or(SEALED_LENGTH_LEN),
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Vec<u8>;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.framing {
+            Framing::V1 => self.decode_v1(src),
+            Framing::V2 => self.decode_v2(src),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let expected = self.expected_len(src);
+                Err(FrameError::Truncated {
+                    received: src.len(),
+                    expected,
+                })
+            }
+        }
+    }
+}
+
+impl Encoder<&[u8]> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, plaintext: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if self.framing == Framing::V2 {
+            return self.encode_v2(plaintext, dst);
+        }
+
+        let encrypted = self
+            .crypto
+            .encrypt(plaintext)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        if encrypted.len() > self.max_frame_len {
+            return Err(FrameError::TooLarge {
+                length: encrypted.len(),
+                max: self.max_frame_len,
+            });
+        }
+
+        dst.reserve(LENGTH_LEN + encrypted.len());
+        dst.put_u32(encrypted.len() as u32);
+        dst.extend_from_slice(&encrypted);
+
+        Ok(())
+    }
+}
+
+impl FrameCodec {
+    fn encode_v2(&mut self, plaintext: &[u8], dst: &mut BytesMut) -> Result<(), FrameError> {
+        let length = plaintext.len() + TAG_LEN;
+        if length > self.max_frame_len {
+            return Err(FrameError::TooLarge {
+                length,
+                max: self.max_frame_len,
+            });
+        }
+
+        // 第一帧之前先发送本方向的盐
+        if self.session.sealer.is_none() {
+            let mut local_salt = [0u8; SALT_LEN];
+            rand::thread_rng().fill(&mut local_salt);
+            self.session.sealer = Some(self.local_cipher(&local_salt)?);
+            self.session.local_salt = Some(local_salt);
+            dst.extend_from_slice(&local_salt);
+        }
+
+        let sealer = self.session.sealer.as_mut().expect("密钥已建立");
+        let header = sealer
+            .seal(&(length as u32).to_be_bytes())
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let encrypted = sealer
+            .seal(plaintext)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        dst.reserve(header.len() + encrypted.len());
+        dst.extend_from_slice(&header);
+        dst.extend_from_slice(&encrypted);
+
+        Ok(())
+    }
+}
+
+/// 握手完成后拆分连接，保留已经读入缓冲区但尚未解码的数据
+pub fn into_split(
+    framed: Framed<TcpStream, FrameCodec>,
+) -> (
+    FramedRead<OwnedReadHalf, FrameCodec>,
+    FramedWrite<OwnedWriteHalf, FrameCodec>,
+) {
+    let parts = framed.into_parts();
+    let (reader, writer) = parts.io.into_split();
+
+    let mut reader = FramedRead::new(reader, parts.codec.clone());
+    reader.read_buffer_mut().extend_from_slice(&parts.read_buf);
+
+    (reader, FramedWrite::new(writer, parts.codec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec() -> FrameCodec {
+        FrameCodec::new(CryptoManager::new(&CryptoManager::generate_key()).unwrap())
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut codec = codec();
+        let mut buf = BytesMut::new();
+
+        codec.encode(&b"hello"[..], &mut buf).unwrap();
+        codec.encode(&b""[..], &mut buf).unwrap();
+
+        // 逐字节送入，确认不完整的帧不会被提前解码
+        let mut src = BytesMut::new();
+        let mut frames = Vec::new();
+        for byte in buf {
+            src.put_u8(byte);
+            if let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, vec![b"hello".to_vec(), Vec::new()]);
+
+        let mut other = codec.clone();
+        other.crypto = CryptoManager::new(&CryptoManager::generate_key()).unwrap();
+        other.encode(&b"hello"[..], &mut src).unwrap();
+        assert!(matches!(codec.decode(&mut src), Err(FrameError::Decrypt)));
+    }
+
+    #[test]
+    fn test_oversize_and_truncated_frames() {
+        let mut codec = codec().max_frame_len(1024);
+
+        // 4 GiB 的长度头在分配之前就被拒绝
+        let mut src = BytesMut::from(&[0xFF, 0xFF, 0xFF, 0xFF][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(FrameError::TooLarge { length: 0xFFFF_FFFF, max: 1024 })
+        ));
+        assert!(src.capacity() < 1024);
+
+        assert!(matches!(
+            codec.encode(&[0u8; 2048][..], &mut BytesMut::new()),
+            Err(FrameError::TooLarge { .. })
+        ));
+
+        let mut src = BytesMut::from(&[0x00, 0x00, 0x00, 0x20, 0x01][..]);
+        assert!(matches!(
+            codec.decode_eof(&mut src),
+            Err(FrameError::Truncated { received: 5, expected: 36 })
+        ));
+        assert!(codec.decode_eof(&mut BytesMut::new()).unwrap().is_none());
+    }
+
+    /// 一对共享密钥的 v2 客户端和服务器编解码器
+    fn v2_pair(cache: &Arc<ReplayCache>) -> (FrameCodec, FrameCodec) {
+        let crypto = CryptoManager::new(&CryptoManager::generate_key()).unwrap();
+        let client = FrameCodec::new(crypto.clone()).framing(Framing::V2);
+        let server = FrameCodec::new(crypto)
+            .framing(Framing::V2)
+            .role(Role::Server)
+            .replay_cache(cache.clone());
+        (client, server)
+    }
+
+    #[test]
+    fn test_v2_roundtrip_and_reordering() {
+        let cache = Arc::new(ReplayCache::new());
+        let (mut client, mut server) = v2_pair(&cache);
+
+        let mut wire = BytesMut::new();
+        client.encode(&b"request"[..], &mut wire).unwrap();
+        assert_eq!(wire.len(), SALT_LEN + SEALED_LENGTH_LEN + 7 + TAG_LEN);
+        assert_eq!(server.decode(&mut wire).unwrap().unwrap(), b"request");
+        assert_eq!(cache.len(), 1);
+
+        let mut wire = BytesMut::new();
+        server.encode(&b"response"[..], &mut wire).unwrap();
+        assert_eq!(client.decode(&mut wire).unwrap().unwrap(), b"response");
+
+        // 交换两帧的顺序，计数器 nonce 使第一帧解密失败
+        let mut first = BytesMut::new();
+        let mut second = BytesMut::new();
+        client.encode(&b"one"[..], &mut first).unwrap();
+        client.encode(&b"two"[..], &mut second).unwrap();
+        second.extend_from_slice(&first);
+        assert!(matches!(server.clone().decode(&mut second), Err(FrameError::Decrypt)));
+
+        // 篡改载荷
+        let mut tampered = first.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(matches!(server.decode(&mut tampered), Err(FrameError::Decrypt)));
+    }
+
+    #[test]
+    fn test_v2_replayed_session_rejected() {
+        let cache = Arc::new(ReplayCache::new());
+        let (mut client, server) = v2_pair(&cache);
+
+        let mut recorded = BytesMut::new();
+        client.encode(&b"handshake"[..], &mut recorded).unwrap();
+
+        let mut wire = recorded.clone();
+        assert!(server.clone().decode(&mut wire).unwrap().is_some());
+
+        // 同一段录制数据在新连接上重放
+        let mut wire = recorded.clone();
+        assert!(matches!(server.clone().decode(&mut wire), Err(FrameError::Replay)));
+
+        // 没有密钥的随机数据不会进入缓存
+        let mut garbage = BytesMut::from(&[0x42; 128][..]);
+        assert!(matches!(server.clone().decode(&mut garbage), Err(FrameError::Decrypt)));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_alternate_keys_select_per_connection() {
+        let default_key = CryptoManager::new(&CryptoManager::generate_key()).unwrap();
+        let user_key = CryptoManager::new(&CryptoManager::generate_key()).unwrap();
+        let server = FrameCodec::new(default_key.clone())
+            .role(Role::Server)
+            .alternate_keys(vec![user_key.clone()]);
+        assert_eq!(server.candidate_count(), 2);
+
+        for framing in [Framing::V1, Framing::V2] {
+            let mut client = FrameCodec::new(user_key.clone()).framing(framing);
+            let mut server = server.clone().framing(framing);
+
+            let mut wire = BytesMut::new();
+            client.encode(&b"hello"[..], &mut wire).unwrap();
+            client.encode(&b"again"[..], &mut wire).unwrap();
+            assert_eq!(server.decode(&mut wire).unwrap().unwrap(), b"hello");
+            assert_eq!(server.decode(&mut wire).unwrap().unwrap(), b"again");
+            assert!(server.crypto().same_key(&user_key));
+
+            let mut wire = BytesMut::new();
+            server.encode(&b"reply"[..], &mut wire).unwrap();
+            assert_eq!(client.decode(&mut wire).unwrap().unwrap(), b"reply");
+        }
+    }
+
+    #[test]
+    fn test_transport_keys_skip_salt() {
+        let key = [7u8; 32];
+        let keys = || TransportKeys {
+            sealer: SessionCipher::new(&key),
+            opener: SessionCipher::new(&key),
+            crypto: CryptoManager::new(&CryptoManager::generate_key()).unwrap(),
+        };
+        let mut client = codec().transport_keys(keys());
+        let mut server = codec().role(Role::Server).transport_keys(keys());
+
+        let mut wire = BytesMut::new();
+        client.encode(&b"hello"[..], &mut wire).unwrap();
+        assert_eq!(wire.len(), SEALED_LENGTH_LEN + 5 + TAG_LEN);
+        assert_eq!(server.decode(&mut wire).unwrap().unwrap(), b"hello");
+
+        // 第一帧解密失败和其他帧一样是解密错误，服务器据此为来源计费
+        let mut server = codec().role(Role::Server).transport_keys(keys());
+        let mut garbage = BytesMut::from(&[0x42; 64][..]);
+        assert!(matches!(server.decode(&mut garbage), Err(FrameError::Decrypt)));
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use serde::de::{self, DeserializeOwned, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// 读取 TOML 配置文件；未知的配置项、类型错误和无效的值都会指出行号和配置项
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("无法读取配置文件 {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("配置文件 {} 无效", path.display()))
+}
+
+/// 把配置输出为 TOML，供 `--print-default-config` 使用
+pub fn to_toml<T: Serialize>(config: &T) -> Result<String> {
+    Ok(toml::to_string_pretty(config)?)
+}
+
+/// 配置文件中的相对路径按配置文件所在的目录解析
+pub fn resolve_path(config_path: &Path, path: &Path) -> PathBuf {
+    match config_path.parent() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// 按 `FromStr` 解析字符串配置项，解析错误原样报告
+pub fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse().map_err(de::Error::custom)
+}
+
+/// 可以写成单个值或数组的配置项，例如监听地址
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    deserializer.deserialize_any(OneOrMany(PhantomData))
+}
+
+struct OneOrMany<T>(PhantomData<T>);
+
+impl<'de, T: DeserializeOwned> Visitor<'de> for OneOrMany<T> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string or an array")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        T::deserialize(de::value::StrDeserializer::<E>::new(value)).map(|value| vec![value])
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        Vec::deserialize(de::value::SeqAccessDeserializer::new(seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Framing;
+    use std::net::SocketAddr;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Sample {
+        #[serde(deserialize_with = "one_or_many")]
+        listen_addr: Vec<SocketAddr>,
+        #[serde(default = "default_framing")]
+        framing: Framing,
+    }
+
+    fn default_framing() -> Framing {
+        Framing::V2
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let sample: Sample = toml::from_str(r#"listen_addr = "127.0.0.1:1080""#).unwrap();
+        assert_eq!(sample.listen_addr, vec!["127.0.0.1:1080".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(sample.framing, Framing::V2);
+
+        let sample: Sample = toml::from_str("listen_addr = [\"0.0.0.0:8080\", \"[::]:8080\"]\nframing = \"v1\"").unwrap();
+        assert_eq!(sample.listen_addr.len(), 2);
+        assert_eq!(sample.framing, Framing::V1);
+
+        // 错误指出行号和出错的值
+        let error = toml::from_str::<Sample>("listen_addr = \"127.0.0.1:1080\"\nframing = \"v3\"").unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+        assert!(error.to_string().contains("未知的帧格式: v3"), "{}", error);
+        let error = toml::from_str::<Sample>("listen_addr = \"localhost\"").unwrap_err();
+        assert!(error.to_string().contains("invalid socket address"), "{}", error);
+        let error = toml::from_str::<Sample>("listen_addr = []\nport = 1").unwrap_err();
+        assert!(error.to_string().contains("unknown field `port`"), "{}", error);
+
+        assert_eq!(
+            resolve_path(Path::new("/etc/leaf/server.toml"), Path::new("users.toml")),
+            PathBuf::from("/etc/leaf/users.toml")
+        );
+        assert_eq!(resolve_path(Path::new("server.toml"), Path::new("/var/usage.json")), PathBuf::from("/var/usage.json"));
+    }
+}
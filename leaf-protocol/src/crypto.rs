@@ -0,0 +1,190 @@
+use anyhow::{anyhow, Result};
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hkdf::Hkdf;
+use rand::Rng;
+use sha2::Sha256;
+use std::sync::Arc;
+
+/// AES-GCM 认证标签长度
+pub const TAG_LEN: usize = 16;
+
+/// 会话盐长度，每个方向在连接开头发送一次
+pub const SALT_LEN: usize = 32;
+
+/// 基于预共享密钥的 AES-256-GCM 加解密
+/// 密文格式: [随机 nonce 12 字节][密文和认证标签]
+#[derive(Clone)]
+pub struct CryptoManager {
+    cipher: Arc<Aes256Gcm>,
+    /// 预共享密钥，用于派生会话密钥
+    psk: Arc<[u8; 32]>,
+}
+
+impl CryptoManager {
+    pub fn new(key: &str) -> Result<Self> {
+        // 解码 base64 密钥
+        let key_bytes = STANDARD.decode(key)?;
+        if key_bytes.len() != 32 {
+            return Err(anyhow!("密钥长度必须是 32 字节"));
+        }
+        
+        let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
+        let cipher = Aes256Gcm::new(key);
+        
+        Ok(Self {
+            cipher: Arc::new(cipher),
+            psk: Arc::new(key_bytes.try_into().expect("长度已检查")),
+        })
+    }
+    
+    /// 用 HKDF-SHA256 从预共享密钥派生一个会话密钥
+    /// 每个连接的盐都不同，所以每个会话、每个方向都有独立的密钥
+    pub fn derive_session_cipher(&self, salt: &[u8], info: &[u8]) -> SessionCipher {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), self.psk.as_slice())
+            .expand(info, &mut key)
+            .expect("32 字节不超过 HKDF 输出上限");
+        SessionCipher::new(&key)
+    }
+    
+    pub(crate) fn psk(&self) -> &[u8; 32] {
+        &self.psk
+    }
+    
+    /// 两者是否使用同一个预共享密钥
+    pub fn same_key(&self, other: &CryptoManager) -> bool {
+        self.psk == other.psk
+    }
+    
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        // 生成随机 nonce
+        let mut nonce_bytes = [0u8; 12];
+        rand::thread_rng().fill(&mut nonce_bytes);
+        let nonce = Nonce::from_slice(&nonce_bytes);
+        
+        // 加密数据
+        let ciphertext = self.cipher.encrypt(nonce, data)
+            .map_err(|e| anyhow!("加密失败: {}", e))?;
+        
+        // 组合 nonce + ciphertext
+        let mut result = Vec::with_capacity(12 + ciphertext.len());
+        result.extend_from_slice(&nonce_bytes);
+        result.extend_from_slice(&ciphertext);
+        
+        Ok(result)
+    }
+    
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < 12 {
+            return Err(anyhow!("数据长度不足"));
+        }
+        
+        // 分离 nonce 和 ciphertext
+        let nonce_bytes = &data[..12];
+        let ciphertext = &data[12..];
+        
+        let nonce = Nonce::from_slice(nonce_bytes);
+        
+        // 解密数据
+        let plaintext = self.cipher.decrypt(nonce, ciphertext)
+            .map_err(|e| anyhow!("解密失败: {}", e))?;
+        
+        Ok(plaintext)
+    }
+    
+    pub fn generate_key() -> String {
+        let mut key_bytes = [0u8; 32];
+        rand::thread_rng().fill(&mut key_bytes);
+        STANDARD.encode(key_bytes)
+    }
+}
+
+/// 单个方向的会话密钥，nonce 是从 0 开始递增的计数器
+/// 双方按相同顺序加解密，重放、重排或丢弃任何一帧都会使后续解密失败
+#[derive(Clone)]
+pub struct SessionCipher {
+    cipher: Aes256Gcm,
+    counter: u64,
+}
+
+impl SessionCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    /// 加密一段数据，输出密文和认证标签
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|e| anyhow!("加密失败: {}", e))
+    }
+
+    pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|e| anyhow!("解密失败: {}", e))
+    }
+
+    /// 12 字节 nonce: 计数器小端序放在前 8 字节
+    fn next_nonce(&mut self) -> Result<[u8; 12]> {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("nonce 计数器耗尽"))?;
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = CryptoManager::generate_key();
+        let crypto = CryptoManager::new(&key).unwrap();
+        
+        let original_data = b"Hello, World!";
+        let encrypted = crypto.encrypt(original_data).unwrap();
+        let decrypted = crypto.decrypt(&encrypted).unwrap();
+        
+        assert_eq!(original_data, decrypted.as_slice());
+    }
+    
+    #[test]
+    fn test_session_cipher_rejects_reordering() {
+        let crypto = CryptoManager::new(&CryptoManager::generate_key()).unwrap();
+        let mut sealer = crypto.derive_session_cipher(b"salt", b"info");
+        let mut opener = crypto.derive_session_cipher(b"salt", b"info");
+
+        let first = sealer.seal(b"first").unwrap();
+        let second = sealer.seal(b"second").unwrap();
+        assert!(opener.clone().open(&second).is_err());
+        assert_eq!(opener.open(&first).unwrap(), b"first");
+        assert_eq!(opener.open(&second).unwrap(), b"second");
+
+        // 不同的盐得到不同的密钥
+        let mut other = crypto.derive_session_cipher(b"other", b"info");
+        assert!(other.open(&first).is_err());
+    }
+    
+    #[test]
+    fn test_key_generation() {
+        let key1 = CryptoManager::generate_key();
+        let key2 = CryptoManager::generate_key();
+        
+        assert_ne!(key1, key2);
+        assert_eq!(key1.len(), 44); // base64 编码的 32 字节
+    }
+} 
\ No newline at end of file
@@ -0,0 +1,45 @@
+//! leaf 代理各组件共享的协议实现
+//!
+//! - [`admin`]：服务器的管理 HTTP 接口
+//! - [`challenge`]：基于 HMAC 的 token 质询-响应认证
+//! - [`config`]：各程序共用的 TOML 配置文件读取
+//! - [`crypto`]：基于预共享密钥的 AES-GCM 加解密
+//! - [`codec`]：TCP 协议中带长度前缀的加密帧（v1 和防重放的 v2）
+//! - [`metrics`]：Prometheus 指标和 `/metrics` 接口
+//! - [`mux`]：TCP 连接上的多路复用会话、流和按流的流量控制，客户端和服务器共用
+//! - [`noise`]：前向安全的 Noise IK 握手
+//! - [`pool`]：客户端的多服务器选择、健康检查和故障转移
+//! - [`reload`]：服务器配置和凭据的热重载
+//! - [`replay`]：服务器端的会话盐缓存和握手时间窗口
+//! - [`routing`]：客户端按域名、IP 网段、端口和用户选择代理、直连或拒绝
+//! - [`sessions`]：服务器的活跃会话表和按流的流量统计
+//! - [`throttle`]：服务器按来源地址限制认证之前的试解密
+//! - [`protocol`]：握手、代理请求和多路复用帧等消息类型
+//! - [`quota`]：按用户的流量统计、配额和带宽限制
+//! - [`users`]：服务器端的多用户数据库和握手认证
+//! - [`ws`]：WebSocket 传输的消息格式
+//! - [`socks5`] / [`auth`]：SOCKS5 服务端解析和用户名/密码认证
+
+pub mod admin;
+pub mod auth;
+pub mod challenge;
+pub mod codec;
+pub mod config;
+pub mod crypto;
+pub mod metrics;
+pub mod mux;
+pub mod noise;
+pub mod pool;
+pub mod protocol;
+pub mod quota;
+pub mod reload;
+pub mod replay;
+pub mod routing;
+pub mod sessions;
+pub mod socks5;
+pub mod throttle;
+pub mod users;
+pub mod ws;
+
+pub use crypto::CryptoManager;
+pub use protocol::{ProxyCommand, ProxyRequest, ProxyResponse, TargetAddr};
@@ -0,0 +1,292 @@
+use anyhow::{Context, Result};
+use axum::{extract::State, http::header, routing::get, Router};
+use log::{error, info};
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::codec::FrameError;
+use crate::quota::Direction;
+use crate::users::AuthError;
+
+/// 一个组件 (服务器或客户端) 的 Prometheus 指标，名称统一以 `leaf_` 开头并带 `component` 标签
+///
+/// 服务器上“会话”指已认证的客户端连接，客户端上指正在处理的本地入站连接
+pub struct Metrics {
+    registry: Registry,
+    active_sessions: IntGauge,
+    handshakes: IntCounterVec,
+    connect_duration: HistogramVec,
+    relayed_bytes: IntCounterVec,
+    frame_errors: IntCounterVec,
+    user_sessions: IntCounterVec,
+    user_bytes: IntCounterVec,
+    server_healthy: IntGaugeVec,
+    server_latency: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new(component: &str) -> Self {
+        let labels = HashMap::from([("component".to_string(), component.to_string())]);
+        let registry = Registry::new_custom(Some("leaf".to_string()), Some(labels)).expect("指标前缀和标签是常量");
+
+        let active_sessions = IntGauge::new("active_sessions", "活跃会话数").unwrap();
+        let handshakes = IntCounterVec::new(
+            Opts::new("handshakes_total", "握手次数，按结果和失败原因分类"),
+            &["result", "reason"],
+        )
+        .unwrap();
+        let connect_duration = HistogramVec::new(
+            HistogramOpts::new("connect_duration_seconds", "打开代理流到目标连接建立的耗时"),
+            &["result"],
+        )
+        .unwrap();
+        let relayed_bytes = IntCounterVec::new(
+            Opts::new("relayed_bytes_total", "转发的字节数，upload 为客户端发往目标的方向"),
+            &["direction"],
+        )
+        .unwrap();
+        let frame_errors = IntCounterVec::new(
+            Opts::new("frame_errors_total", "加密帧错误次数，按类型分类"),
+            &["kind"],
+        )
+        .unwrap();
+        let user_sessions = IntCounterVec::new(
+            Opts::new("user_sessions_total", "各用户认证成功的会话数"),
+            &["user"],
+        )
+        .unwrap();
+        let user_bytes = IntCounterVec::new(
+            Opts::new("user_relayed_bytes_total", "各用户转发的字节数"),
+            &["user", "direction"],
+        )
+        .unwrap();
+        let server_healthy = IntGaugeVec::new(
+            Opts::new("server_healthy", "客户端服务器池中各服务器是否可用"),
+            &["server"],
+        )
+        .unwrap();
+        let server_latency = GaugeVec::new(
+            Opts::new("server_latency_seconds", "健康检查测得的平滑延迟，handshake 为握手，connect 为经服务器连接探测目标"),
+            &["server", "kind"],
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(active_sessions.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(handshakes.clone()),
+            Box::new(connect_duration.clone()),
+            Box::new(relayed_bytes.clone()),
+            Box::new(frame_errors.clone()),
+            Box::new(user_sessions.clone()),
+            Box::new(user_bytes.clone()),
+            Box::new(server_healthy.clone()),
+            Box::new(server_latency.clone()),
+        ] {
+            registry.register(collector).expect("指标名称不重复");
+        }
+
+        Self {
+            registry,
+            active_sessions,
+            handshakes,
+            connect_duration,
+            relayed_bytes,
+            frame_errors,
+            user_sessions,
+            user_bytes,
+            server_healthy,
+            server_latency,
+        }
+    }
+
+    /// 记录一个会话开始；返回的句柄释放时会话结束
+    pub fn track_session(self: &Arc<Self>) -> SessionGuard {
+        self.active_sessions.inc();
+        SessionGuard {
+            metrics: self.clone(),
+        }
+    }
+
+    /// 握手成功；服务器传入认证的用户名
+    pub fn handshake_succeeded(&self, user: Option<&str>) {
+        self.handshakes.with_label_values(&["success", ""]).inc();
+        if let Some(user) = user {
+            self.user_sessions.with_label_values(&[user]).inc();
+        }
+    }
+
+    pub fn handshake_failed(&self, reason: &str) {
+        self.handshakes.with_label_values(&["failure", reason]).inc();
+    }
+
+    /// 按错误类型记录握手失败，帧错误同时计入 `frame_errors_total`
+    pub fn handshake_error(&self, error: &anyhow::Error) {
+        self.frame_error(error);
+        self.handshake_failed(failure_reason(error));
+    }
+
+    /// 错误来自加密帧时计数，其他错误忽略
+    pub fn frame_error(&self, error: &anyhow::Error) {
+        if let Some(error) = error.downcast_ref::<FrameError>()
+            && !matches!(error, FrameError::Io(_))
+        {
+            self.frame_errors.with_label_values(&[frame_error_kind(error)]).inc();
+        }
+    }
+
+    pub fn observe_connect(&self, elapsed: Duration, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.connect_duration
+            .with_label_values(&[result])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// 记录转发的字节；`user` 为服务器上认证的用户
+    pub fn relayed(&self, user: Option<&str>, direction: Direction, bytes: usize) {
+        let direction = match direction {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        };
+        self.relayed_bytes.with_label_values(&[direction]).inc_by(bytes as u64);
+        if let Some(user) = user {
+            self.user_bytes.with_label_values(&[user, direction]).inc_by(bytes as u64);
+        }
+    }
+
+    /// 记录服务器池中一台服务器的状态，尚未测得的延迟不输出
+    pub fn server_status(&self, server: &str, healthy: bool, handshake: Option<Duration>, connect: Option<Duration>) {
+        self.server_healthy.with_label_values(&[server]).set(healthy as i64);
+        for (kind, latency) in [("handshake", handshake), ("connect", connect)] {
+            match latency {
+                Some(latency) => self
+                    .server_latency
+                    .with_label_values(&[server, kind])
+                    .set(latency.as_secs_f64()),
+                None => {
+                    let _ = self.server_latency.remove_label_values(&[server, kind]);
+                }
+            }
+        }
+    }
+
+    /// Prometheus 文本格式
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("写入内存缓冲区不会失败");
+        String::from_utf8(buf).expect("Prometheus 文本格式是 UTF-8")
+    }
+}
+
+/// 活跃会话的计数句柄
+pub struct SessionGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.metrics.active_sessions.dec();
+    }
+}
+
+/// 握手失败原因的固定标识
+pub fn failure_reason(error: &anyhow::Error) -> &'static str {
+    if let Some(error) = error.downcast_ref::<AuthError>() {
+        return error.reason();
+    }
+    match error.downcast_ref::<FrameError>() {
+        Some(FrameError::Io(_)) => "io",
+        Some(error) => frame_error_kind(error),
+        None if error.downcast_ref::<std::io::Error>().is_some() => "io",
+        None => "other",
+    }
+}
+
+fn frame_error_kind(error: &FrameError) -> &'static str {
+    match error {
+        FrameError::TooLarge { .. } => "too_large",
+        FrameError::Truncated { .. } => "truncated",
+        FrameError::Decrypt => "decrypt",
+        FrameError::Replay => "replay",
+        FrameError::Io(_) => "io",
+    }
+}
+
+/// 在 `GET /metrics` 上提供指标，出错时只记录日志
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) {
+    if let Err(e) = run(addr, metrics).await {
+        error!("指标接口出错: {:#}", e);
+    }
+}
+
+async fn run(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(render_metrics))
+        .with_state(metrics);
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("指标接口无法监听 {}", addr))?;
+    info!("指标接口监听在 http://{}/metrics", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn render_metrics(State(metrics): State<Arc<Metrics>>) -> impl axum::response::IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 按名称和标签查找样本值，标签的顺序不固定
+    fn sample<'a>(text: &'a str, name: &str, labels: &[&str]) -> Option<&'a str> {
+        text.lines()
+            .filter(|line| line.starts_with(&format!("{}{{", name)))
+            .find(|line| labels.iter().all(|label| line.contains(label)))
+            .and_then(|line| line.rsplit(' ').next())
+    }
+
+    #[test]
+    fn test_metrics_render() {
+        let metrics = Arc::new(Metrics::new("test"));
+        let guard = metrics.track_session();
+        metrics.handshake_succeeded(Some("alice"));
+        metrics.handshake_error(&anyhow::Error::new(AuthError::Disabled));
+        metrics.handshake_error(&anyhow::Error::new(FrameError::Decrypt));
+        metrics.relayed(Some("alice"), Direction::Download, 1500);
+        metrics.observe_connect(Duration::from_millis(20), true);
+        metrics.server_status("hk", true, Some(Duration::from_millis(250)), None);
+
+        let text = metrics.render();
+        assert_eq!(sample(&text, "leaf_active_sessions", &[r#"component="test""#]), Some("1"));
+        assert_eq!(
+            sample(&text, "leaf_handshakes_total", &[r#"reason="disabled""#, r#"result="failure""#]),
+            Some("1")
+        );
+        assert_eq!(sample(&text, "leaf_frame_errors_total", &[r#"kind="decrypt""#]), Some("1"));
+        assert_eq!(
+            sample(&text, "leaf_user_relayed_bytes_total", &[r#"user="alice""#, r#"direction="download""#]),
+            Some("1500")
+        );
+        assert_eq!(sample(&text, "leaf_connect_duration_seconds_count", &[r#"result="success""#]), Some("1"));
+        assert_eq!(sample(&text, "leaf_server_healthy", &[r#"server="hk""#]), Some("1"));
+        assert_eq!(
+            sample(&text, "leaf_server_latency_seconds", &[r#"server="hk""#, r#"kind="handshake""#]),
+            Some("0.25")
+        );
+        assert_eq!(sample(&text, "leaf_server_latency_seconds", &[r#"kind="connect""#]), None);
+
+        drop(guard);
+        assert_eq!(sample(&metrics.render(), "leaf_active_sessions", &[]), Some("0"));
+        assert_eq!(failure_reason(&anyhow::anyhow!("连接已关闭")), "other");
+    }
+}
@@ -0,0 +1,1019 @@
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, warn};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, Notify, Semaphore},
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::codec::{FrameCodec, Role};
+use crate::metrics::Metrics;
+use crate::protocol::{FrameType, MuxFrame, ProxyRequest};
+use crate::quota::Direction;
+use crate::sessions::StreamTraffic;
+
+/// 每个流的初始发送窗口（字节）
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// 单个数据帧的最大载荷
+pub const MAX_DATA_CHUNK: usize = 8192;
+
+/// 每个流在写出队列中最多排队的数据帧数，排满时流等待写出，形成对本地连接读取的背压
+const STREAM_QUEUE: usize = 8;
+
+/// 每个流在写出队列中最多排队的 UDP 数据报数，排满时新的数据报被丢弃
+const DATAGRAM_QUEUE: usize = 64;
+
+/// 流收到的事件
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// 对端对打开请求的答复
+    Reply(Vec<u8>),
+    /// 流数据
+    Data(Vec<u8>),
+    /// UDP 数据报载荷
+    Datagram(Vec<u8>),
+    /// 对端关闭了流，附带对端给出的原因
+    Close(Option<String>),
+}
+
+/// 流转发的字节计入哪里：服务器计入用户的用量和配额，客户端只计入指标
+pub trait StreamMeter: Sync {
+    /// 记录一个方向转发的字节，数据写出之前调用；返回错误时转发结束，错误随 Close 帧告诉对端
+    fn record(&self, direction: Direction, bytes: usize) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl StreamMeter for Metrics {
+    async fn record(&self, direction: Direction, bytes: usize) -> Result<()> {
+        self.relayed(None, direction, bytes);
+        Ok(())
+    }
+}
+
+impl StreamMeter for StreamTraffic {
+    async fn record(&self, direction: Direction, bytes: usize) -> Result<()> {
+        StreamTraffic::record(self, direction, bytes)
+            .await
+            .map_err(|e| anyhow!("用户 {} 的{}", self.user(), e))
+    }
+}
+
+/// 会话的写出队列
+///
+/// 打开、答复和窗口更新等控制帧优先写出；数据帧、数据报和关闭帧进入各自流的队列，按流轮转每次写出一帧。
+/// 关闭帧排在同一个流的数据之后，不会截断流；大流量的流也不会让交互式的流排在它整个发送窗口之后
+#[derive(Default)]
+struct OutboundQueue {
+    control: VecDeque<MuxFrame>,
+    streams: HashMap<u32, VecDeque<MuxFrame>>,
+    /// 有待写出帧的流，按轮转顺序
+    ready: VecDeque<u32>,
+    closed: bool,
+}
+
+impl OutboundQueue {
+    /// 流的数据帧已经排满
+    fn is_full(&self, frame: &MuxFrame) -> bool {
+        frame.frame_type == FrameType::Data
+            && self.streams.get(&frame.stream_id).is_some_and(|queue| queue.len() >= STREAM_QUEUE)
+    }
+
+    fn push(&mut self, frame: MuxFrame) {
+        match frame.frame_type {
+            FrameType::Open | FrameType::OpenAck | FrameType::WindowUpdate => self.control.push_back(frame),
+            FrameType::Data | FrameType::Datagram | FrameType::Close => {
+                let queue = self.streams.entry(frame.stream_id).or_default();
+                if frame.frame_type == FrameType::Datagram && queue.len() >= DATAGRAM_QUEUE {
+                    debug!("流 {} 写出队列已满，丢弃 UDP 数据报", frame.stream_id);
+                    return;
+                }
+                if queue.is_empty() {
+                    self.ready.push_back(frame.stream_id);
+                }
+                queue.push_back(frame);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<MuxFrame> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+
+        let stream_id = self.ready.pop_front()?;
+        let queue = self.streams.get_mut(&stream_id)?;
+        let frame = queue.pop_front();
+        if queue.is_empty() {
+            self.streams.remove(&stream_id);
+        } else {
+            self.ready.push_back(stream_id);
+        }
+        frame
+    }
+}
+
+/// 会话和写出任务共享的写出队列
+#[derive(Default)]
+struct Outbound {
+    queue: Mutex<OutboundQueue>,
+    /// 有帧入队或队列关闭时通知写出任务
+    pushed: Notify,
+    /// 写出一帧后通知等待队列空位的流
+    popped: Notify,
+}
+
+impl Outbound {
+    /// 入队一帧，流的数据帧排满时等待写出任务取走
+    async fn send(&self, frame: MuxFrame) -> Result<()> {
+        loop {
+            let popped = self.popped.notified();
+            tokio::pin!(popped);
+            popped.as_mut().enable();
+
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.closed {
+                    return Err(anyhow!("多路复用会话已关闭"));
+                }
+                if !queue.is_full(&frame) {
+                    queue.push(frame);
+                    self.pushed.notify_one();
+                    return Ok(());
+                }
+            }
+            popped.await;
+        }
+    }
+
+    /// 不等待地入队，用于关闭帧和数据报
+    fn push(&self, frame: MuxFrame) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return Err(anyhow!("多路复用会话已关闭"));
+        }
+        queue.push(frame);
+        self.pushed.notify_one();
+        Ok(())
+    }
+
+    /// 取出下一帧；队列关闭且已写完时返回 `None`
+    async fn pop(&self) -> Option<MuxFrame> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(frame) = queue.pop() {
+                    self.popped.notify_waiters();
+                    return Some(frame);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.pushed.notified().await;
+        }
+    }
+
+    /// 不再接受新帧，已经入队的帧仍会写出
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.pushed.notify_one();
+        self.popped.notify_waiters();
+    }
+}
+
+struct StreamHandle {
+    events: mpsc::UnboundedSender<StreamEvent>,
+    send_window: Arc<Semaphore>,
+    /// 已收到、还没有用窗口更新归还给对端的字节数，超过接收窗口说明对端没有遵守流量控制
+    unacked: Arc<AtomicUsize>,
+}
+
+/// 一个已认证连接上的多路复用会话，客户端和服务器共用
+///
+/// 客户端打开流，服务器接受对端打开的流；之后两端的流对称地转发数据和归还窗口
+pub struct MuxSession {
+    role: Role,
+    outbound: Arc<Outbound>,
+    streams: Mutex<HashMap<u32, StreamHandle>>,
+    closed: AtomicBool,
+    /// 通知读取任务停止，释放连接
+    closing: Notify,
+    next_stream_id: AtomicU32,
+    metrics: Arc<Metrics>,
+}
+
+impl MuxSession {
+    /// 创建会话并启动写出任务
+    pub fn new<W>(writer: FramedWrite<W, FrameCodec>, role: Role, metrics: Arc<Metrics>) -> Arc<Self>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let outbound = Arc::new(Outbound::default());
+        let session = Arc::new(Self {
+            role,
+            outbound: outbound.clone(),
+            streams: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            closing: Notify::new(),
+            next_stream_id: AtomicU32::new(1),
+            metrics,
+        });
+
+        tokio::spawn(write_frames(writer, outbound, Arc::downgrade(&session)));
+        session
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// 会话上当前打开的流数
+    pub fn stream_count(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// 打开一个新流并发送代理请求
+    pub async fn open_stream(self: &Arc<Self>, request: &ProxyRequest) -> Result<MuxStream> {
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let mut stream = self.register_stream(stream_id)?;
+        stream.opened_at = Some(Instant::now());
+
+        let payload = serde_json::to_vec(request)?;
+        self.send(MuxFrame::new(FrameType::Open, stream_id, payload))
+            .await?;
+
+        Ok(stream)
+    }
+
+    /// 为流注册句柄；对端打开的流由调用者在读取下一帧之前注册，保证后续帧能找到它
+    /// ID 已被仍在转发的流占用时返回错误，不替换已有的流
+    pub fn register_stream(self: &Arc<Self>, stream_id: u32) -> Result<MuxStream> {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let send_window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        let unacked = Arc::new(AtomicUsize::new(0));
+
+        let mut streams = self.streams.lock().unwrap();
+        if streams.contains_key(&stream_id) {
+            return Err(anyhow!("流 {} 已经打开", stream_id));
+        }
+        streams.insert(
+            stream_id,
+            StreamHandle {
+                events: events_tx,
+                send_window: send_window.clone(),
+                unacked: unacked.clone(),
+            },
+        );
+        drop(streams);
+
+        Ok(MuxStream {
+            stream_id,
+            session: self.clone(),
+            events,
+            send_window,
+            unacked,
+            opened_at: None,
+            close_reason: None,
+        })
+    }
+
+    /// 拒绝对端打开的流，向对端发送带原因的 Close
+    pub fn reject_stream(&self, stream_id: u32, reason: &str) {
+        let frame = MuxFrame::new(FrameType::Close, stream_id, reason.as_bytes().to_vec());
+        let _ = self.outbound.push(frame);
+    }
+
+    /// 移除流的句柄。句柄因违反流量控制被提前移除后，同一 ID 可能已经注册了新流，
+    /// 只移除发送窗口相同的句柄，保留新流
+    fn remove_stream(&self, stream_id: u32, send_window: &Arc<Semaphore>) {
+        let mut streams = self.streams.lock().unwrap();
+        if streams
+            .get(&stream_id)
+            .is_some_and(|handle| Arc::ptr_eq(&handle.send_window, send_window))
+            && let Some(handle) = streams.remove(&stream_id)
+        {
+            handle.send_window.close();
+        }
+    }
+
+    pub async fn send(&self, frame: MuxFrame) -> Result<()> {
+        self.outbound.send(frame).await
+    }
+
+    /// 把入站帧分发给对应的流
+    /// `Open` 帧不属于任何已有的流，原样返回给调用者处理
+    pub fn dispatch(&self, frame: MuxFrame) -> Option<MuxFrame> {
+        if frame.frame_type == FrameType::Open {
+            return Some(frame);
+        }
+
+        let mut streams = self.streams.lock().unwrap();
+        let Some(handle) = streams.get(&frame.stream_id) else {
+            debug!("忽略未知流 {} 的 {:?} 帧", frame.stream_id, frame.frame_type);
+            return None;
+        };
+
+        match frame.frame_type {
+            FrameType::WindowUpdate => match frame.window_increment() {
+                // 合法的增量不会超过初始窗口
+                Ok(increment) => handle
+                    .send_window
+                    .add_permits(increment.min(INITIAL_WINDOW) as usize),
+                Err(e) => warn!("流 {}: {}", frame.stream_id, e),
+            },
+            FrameType::OpenAck if self.role == Role::Server => {
+                warn!("流 {}: 客户端不应发送打开答复", frame.stream_id)
+            }
+            FrameType::OpenAck => {
+                let _ = handle.events.send(StreamEvent::Reply(frame.payload));
+            }
+            FrameType::Data => {
+                let unacked = handle.unacked.fetch_add(frame.payload.len(), Ordering::AcqRel) + frame.payload.len();
+                if unacked > INITIAL_WINDOW as usize {
+                    // 移除句柄后流的事件通道关闭、转发结束，流释放时向对端发送 Close，之后的帧按未知流忽略
+                    warn!("流 {} 收到 {} 字节未确认的数据，超出接收窗口，重置流", frame.stream_id, unacked);
+                    if let Some(handle) = streams.remove(&frame.stream_id) {
+                        handle.send_window.close();
+                    }
+                    return None;
+                }
+                let _ = handle.events.send(StreamEvent::Data(frame.payload));
+            }
+            FrameType::Datagram => {
+                let _ = handle.events.send(StreamEvent::Datagram(frame.payload));
+            }
+            FrameType::Close => {
+                let reason = (!frame.payload.is_empty()).then(|| String::from_utf8_lossy(&frame.payload).into_owned());
+                let _ = handle.events.send(StreamEvent::Close(reason));
+            }
+            FrameType::Open => unreachable!(),
+        }
+
+        None
+    }
+
+    /// 关闭会话，通知所有流结束
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        for handle in streams.into_values() {
+            let _ = handle.events.send(StreamEvent::Close(None));
+            handle.send_window.close();
+        }
+    }
+
+    /// 主动关闭会话：通知所有流结束并停止读取，最后一个引用释放后连接关闭
+    pub fn close(&self) {
+        self.shutdown();
+        self.closing.notify_one();
+    }
+}
+
+impl Drop for MuxSession {
+    fn drop(&mut self) {
+        // 写出任务写完剩余的帧后结束，连接随之关闭
+        self.outbound.close();
+    }
+}
+
+/// 多路复用会话上的一个流
+pub struct MuxStream {
+    stream_id: u32,
+    session: Arc<MuxSession>,
+    events: mpsc::UnboundedReceiver<StreamEvent>,
+    send_window: Arc<Semaphore>,
+    unacked: Arc<AtomicUsize>,
+    /// 本端打开的流在收到第一个答复后清空，用于统计连接耗时
+    opened_at: Option<Instant>,
+    /// 流释放时随 Close 帧告诉对端的关闭原因
+    close_reason: Option<String>,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// 所在会话的指标
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.session.metrics
+    }
+
+    /// 记录从打开流到对端答复的耗时，只记录第一个答复
+    pub fn record_connect(&mut self, success: bool) {
+        if let Some(opened_at) = self.opened_at.take() {
+            self.session.metrics.observe_connect(opened_at.elapsed(), success);
+        }
+    }
+
+    /// 向对端答复打开请求
+    pub async fn send_reply(&self, payload: Vec<u8>) -> Result<()> {
+        self.session
+            .send(MuxFrame::new(FrameType::OpenAck, self.stream_id, payload))
+            .await
+    }
+
+    /// 等待对端对打开请求的答复
+    pub async fn recv_reply(&mut self) -> Result<Vec<u8>> {
+        match self.events.recv().await {
+            Some(StreamEvent::Reply(payload)) => Ok(payload),
+            Some(StreamEvent::Data(_) | StreamEvent::Datagram(_)) => {
+                Err(anyhow!("流 {} 在答复前收到数据", self.stream_id))
+            }
+            Some(StreamEvent::Close(Some(reason))) => Err(anyhow!("流 {} 在答复前被关闭: {}", self.stream_id, reason)),
+            Some(StreamEvent::Close(None)) | None => Err(anyhow!("流 {} 在答复前被关闭", self.stream_id)),
+        }
+    }
+
+    /// 发送一个 UDP 数据报
+    /// 数据报不受流量控制，写出队列已满时直接丢弃
+    pub fn send_datagram(&self, payload: Vec<u8>) -> Result<()> {
+        let frame = MuxFrame::new(FrameType::Datagram, self.stream_id, payload);
+        self.session.outbound.push(frame)
+    }
+
+    /// 接收下一个 UDP 数据报，流关闭时返回 `None`
+    pub async fn recv_datagram(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.events.recv().await? {
+                StreamEvent::Datagram(payload) => return Some(payload),
+                StreamEvent::Close(_) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    /// 设置关闭原因，流释放时随 Close 帧发给对端
+    pub fn set_close_reason(&mut self, reason: String) {
+        self.close_reason = Some(reason);
+    }
+
+    /// 等待对端关闭流，期间收到的数据被丢弃
+    pub async fn wait_closed(&mut self) {
+        while let Some(event) = self.events.recv().await {
+            if let StreamEvent::Close(_) = event {
+                break;
+            }
+        }
+    }
+
+    /// 在本地连接和流之间双向转发数据，转发的字节计入 `meter`
+    ///
+    /// `meter` 返回错误 (如服务器上用户的配额用尽) 时结束转发，对端从 Close 帧中得知原因；
+    /// 对端带着原因关闭流时返回错误
+    pub async fn relay<S, M>(mut self, socket: S, meter: &M) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        M: StreamMeter,
+    {
+        let (mut local_read, mut local_write) = tokio::io::split(socket);
+        let stream_id = self.stream_id;
+        let session = self.session.clone();
+        let send_window = self.send_window.clone();
+        let unacked = self.unacked.clone();
+        let events = &mut self.events;
+        // 方向以客户端为准：客户端从本地连接读出的是上传，服务器从目标读出的是下载
+        let (outgoing, incoming) = match session.role {
+            Role::Client => (Direction::Upload, Direction::Download),
+            Role::Server => (Direction::Download, Direction::Upload),
+        };
+
+        let local_to_remote = async {
+            let mut buf = vec![0u8; MAX_DATA_CHUNK];
+            loop {
+                let n = match local_read.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                meter.record(outgoing, n).await?;
+
+                // 等待对端授予足够的窗口
+                match send_window.acquire_many(n as u32).await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+
+                let frame = MuxFrame::new(FrameType::Data, stream_id, buf[..n].to_vec());
+                if session.send(frame).await.is_err() {
+                    break;
+                }
+            }
+            Ok::<_, anyhow::Error>(None)
+        };
+
+        let remote_to_local = async {
+            let mut consumed: u32 = 0;
+            while let Some(event) = events.recv().await {
+                match event {
+                    StreamEvent::Data(data) => {
+                        meter.record(incoming, data.len()).await?;
+                        if local_write.write_all(&data).await.is_err() {
+                            break;
+                        }
+
+                        // 数据写出后归还窗口
+                        consumed += data.len() as u32;
+                        if consumed >= INITIAL_WINDOW / 2 {
+                            // 先扣除再发送更新，对端收到更新后发来的数据不会被误判为超出窗口
+                            unacked.fetch_sub(consumed as usize, Ordering::AcqRel);
+                            let frame = MuxFrame::window_update(stream_id, consumed);
+                            if session.send(frame).await.is_err() {
+                                break;
+                            }
+                            consumed = 0;
+                        }
+                    }
+                    StreamEvent::Close(reason) => return Ok(reason),
+                    StreamEvent::Reply(_) | StreamEvent::Datagram(_) => {}
+                }
+            }
+            Ok(None)
+        };
+
+        let result = tokio::select! {
+            result = local_to_remote => {
+                debug!("流 {} 本地到远端的数据传输完成", stream_id);
+                result
+            }
+            result = remote_to_local => {
+                debug!("流 {} 远端到本地的数据传输完成", stream_id);
+                result
+            }
+        };
+
+        match result {
+            Ok(None) => Ok(()),
+            Ok(Some(reason)) => Err(anyhow!("对端关闭了流 {}: {}", stream_id, reason)),
+            Err(e) => {
+                self.set_close_reason(e.to_string());
+                Err(anyhow!("流 {} 停止转发: {}", stream_id, e))
+            }
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.session.remove_stream(self.stream_id, &self.send_window);
+
+        let reason = self.close_reason.take().map(String::into_bytes).unwrap_or_default();
+        let frame = MuxFrame::new(FrameType::Close, self.stream_id, reason);
+        let _ = self.session.outbound.push(frame);
+    }
+}
+
+/// 读取一个加密的多路复用帧
+pub async fn read_frame<R>(reader: &mut FramedRead<R, FrameCodec>) -> Result<MuxFrame>
+where
+    R: AsyncRead + Unpin,
+{
+    let decrypted = reader.next().await.ok_or_else(|| anyhow!("连接已关闭"))??;
+    MuxFrame::decode(decrypted)
+}
+
+/// 读取对端发来的帧并分发给各个流，对端的打开请求交给 `accept`，在读取下一帧之前处理
+///
+/// 会话被 [`MuxSession::close`] 关闭时返回 `Ok`，连接断开或读取出错时返回错误；返回前通知所有流结束
+pub async fn read_frames<R, F>(mut reader: FramedRead<R, FrameCodec>, session: Arc<MuxSession>, mut accept: F) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(MuxFrame),
+{
+    let result = loop {
+        let result = tokio::select! {
+            result = read_frame(&mut reader) => result,
+            _ = session.closing.notified() => break Ok(()),
+        };
+        match result {
+            Ok(frame) => {
+                if let Some(open) = session.dispatch(frame) {
+                    accept(open);
+                }
+            }
+            Err(e) => {
+                session.metrics.frame_error(&e);
+                break Err(e);
+            }
+        }
+    };
+    session.shutdown();
+    result
+}
+
+async fn write_frames<W>(
+    mut writer: FramedWrite<W, FrameCodec>,
+    outbound: Arc<Outbound>,
+    session: Weak<MuxSession>,
+) where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(frame) = outbound.pop().await {
+        if let Err(e) = writer.send(frame.encode().as_slice()).await {
+            error!("写出多路复用帧时出错: {}", e);
+            outbound.close();
+            if let Some(session) = session.upgrade() {
+                session.shutdown();
+            }
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+    use crate::quota::{Limits, QuotaManager};
+    use crate::sessions::SessionTable;
+    use crate::users::UserDb;
+    use crate::{CryptoManager, ProxyCommand};
+
+    /// 按帧收发的对端，直接构造和检查多路复用帧
+    struct Peer {
+        reader: FramedRead<ReadHalf<DuplexStream>, FrameCodec>,
+        writer: FramedWrite<WriteHalf<DuplexStream>, FrameCodec>,
+    }
+
+    impl Peer {
+        async fn send(&mut self, frame_type: FrameType, stream_id: u32, payload: &[u8]) {
+            self.send_frame(MuxFrame::new(frame_type, stream_id, payload.to_vec())).await;
+        }
+
+        async fn send_frame(&mut self, frame: MuxFrame) {
+            self.writer.send(frame.encode().as_slice()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> MuxFrame {
+            read_frame(&mut self.reader).await.unwrap()
+        }
+
+        /// 接收一个流的数据帧直到累计 `len` 字节，期间收到的其他帧为错误
+        async fn recv_data(&mut self, stream_id: u32, len: usize) -> Vec<u8> {
+            let mut data = Vec::new();
+            while data.len() < len {
+                let frame = self.recv().await;
+                assert_eq!((frame.frame_type, frame.stream_id), (FrameType::Data, stream_id));
+                data.extend_from_slice(&frame.payload);
+            }
+            data
+        }
+    }
+
+    /// 在内存连接上建立会话和对端，会话收到的打开请求从返回的通道取出
+    fn session_pair(role: Role) -> (Arc<MuxSession>, mpsc::UnboundedReceiver<MuxFrame>, Peer) {
+        session_pair_with_buffer(role, 1024 * 1024)
+    }
+
+    /// 同 [`session_pair`]，`buffer` 是内存连接每个方向的缓冲区大小
+    fn session_pair_with_buffer(role: Role, buffer: usize) -> (Arc<MuxSession>, mpsc::UnboundedReceiver<MuxFrame>, Peer) {
+        let codec = FrameCodec::new(CryptoManager::new(&CryptoManager::generate_key()).unwrap());
+        let (local, remote) = tokio::io::duplex(buffer);
+        let (local_read, local_write) = tokio::io::split(local);
+        let (remote_read, remote_write) = tokio::io::split(remote);
+
+        let session = MuxSession::new(FramedWrite::new(local_write, codec.clone()), role, metrics());
+        let (opens_tx, opens) = mpsc::unbounded_channel();
+        tokio::spawn(read_frames(FramedRead::new(local_read, codec.clone()), session.clone(), move |open| {
+            let _ = opens_tx.send(open);
+        }));
+
+        let peer = Peer {
+            reader: FramedRead::new(remote_read, codec.clone()),
+            writer: FramedWrite::new(remote_write, codec),
+        };
+        (session, opens, peer)
+    }
+
+    fn metrics() -> Arc<Metrics> {
+        Arc::new(Metrics::new("test"))
+    }
+
+    fn stream_traffic(stream_id: u32, limits: Limits) -> StreamTraffic {
+        let users = UserDb::parse("[[users]]\nname = \"alice\"\ntoken = \"alice-token\"\n").unwrap();
+        let quota = QuotaManager::load(None).unwrap();
+        let table = SessionTable::new(metrics());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let session = table.register(
+            "s".into(),
+            "c".into(),
+            peer,
+            users.get("alice").unwrap().clone(),
+            quota.meter("alice", limits),
+        );
+        session.open_stream(stream_id, "example.com:80", None)
+    }
+
+    /// 服务器接受对端的打开请求并开始在内存连接和流之间转发，返回目标一侧的连接
+    async fn accept_stream(
+        session: &Arc<MuxSession>,
+        opens: &mut mpsc::UnboundedReceiver<MuxFrame>,
+        peer: &mut Peer,
+        stream_id: u32,
+    ) -> (DuplexStream, tokio::task::JoinHandle<Result<()>>) {
+        peer.send(FrameType::Open, stream_id, b"{}").await;
+        let open = opens.recv().await.unwrap();
+        assert_eq!(open.stream_id, stream_id);
+
+        let stream = session.register_stream(stream_id).unwrap();
+        stream.send_reply(b"ok".to_vec()).await.unwrap();
+        let reply = peer.recv().await;
+        assert_eq!((reply.frame_type, reply.stream_id, reply.payload.as_slice()), (FrameType::OpenAck, stream_id, &b"ok"[..]));
+
+        let (target, remote) = tokio::io::duplex(64 * 1024);
+        let metrics = stream.metrics().clone();
+        let relay = tokio::spawn(async move { stream.relay(target, &*metrics).await });
+        (remote, relay)
+    }
+
+    /// 客户端打开一个流，由对端答复后开始在内存连接和流之间转发，返回本地一侧的连接
+    async fn open_stream(session: &Arc<MuxSession>, peer: &mut Peer) -> (u32, DuplexStream, tokio::task::JoinHandle<Result<()>>) {
+        let request = ProxyRequest {
+            target_addr: "example.com:80".parse().unwrap(),
+            command: ProxyCommand::Connect,
+            user: None,
+        };
+        let mut stream = session.open_stream(&request).await.unwrap();
+
+        let open = peer.recv().await;
+        assert_eq!((open.frame_type, open.stream_id), (FrameType::Open, stream.id()));
+        let received: ProxyRequest = serde_json::from_slice(&open.payload).unwrap();
+        assert_eq!(received.target_addr, request.target_addr);
+
+        peer.send(FrameType::OpenAck, stream.id(), b"ok").await;
+        assert_eq!(stream.recv_reply().await.unwrap(), b"ok");
+
+        let (socket, local) = tokio::io::duplex(64 * 1024);
+        let stream_id = stream.id();
+        let metrics = stream.metrics().clone();
+        let relay = tokio::spawn(async move { stream.relay(socket, &*metrics).await });
+        (stream_id, local, relay)
+    }
+
+    #[tokio::test]
+    async fn test_open_relay_close() {
+        let (session, mut opens, mut peer) = session_pair(Role::Server);
+        let (mut remote, relay) = accept_stream(&session, &mut opens, &mut peer, 1).await;
+
+        peer.send(FrameType::Data, 1, b"ping").await;
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        remote.write_all(b"pong").await.unwrap();
+        assert_eq!(peer.recv_data(1, 4).await, b"pong");
+
+        // 客户端关闭后转发结束，服务器回复 Close 并移除流
+        peer.send(FrameType::Close, 1, b"").await;
+        relay.await.unwrap().unwrap();
+        let close = peer.recv().await;
+        assert_eq!((close.frame_type, close.stream_id), (FrameType::Close, 1));
+        assert_eq!(session.stream_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_open_stream_and_local_close() {
+        let (session, _opens, mut peer) = session_pair(Role::Client);
+        let (stream_id, mut local, relay) = open_stream(&session, &mut peer).await;
+
+        local.write_all(b"ping").await.unwrap();
+        assert_eq!(peer.recv_data(stream_id, 4).await, b"ping");
+
+        peer.send(FrameType::Data, stream_id, b"pong").await;
+        let mut buf = [0u8; 4];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // 本地连接关闭后转发结束，客户端发送 Close 并移除流
+        drop(local);
+        relay.await.unwrap().unwrap();
+        let close = peer.recv().await;
+        assert_eq!((close.frame_type, close.stream_id), (FrameType::Close, stream_id));
+        assert_eq!(session.stream_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_quota_exhausted_during_relay() {
+        let (session, mut opens, mut peer) = session_pair(Role::Server);
+        peer.send(FrameType::Open, 1, b"{}").await;
+        opens.recv().await.unwrap();
+        let stream = session.register_stream(1).unwrap();
+        let (target, mut remote) = tokio::io::duplex(64 * 1024);
+        let limits = Limits {
+            daily_quota: Some(4),
+            ..Limits::default()
+        };
+        let traffic = stream_traffic(1, limits);
+        let relay = tokio::spawn(async move { stream.relay(target, &traffic).await });
+
+        // 第一块数据用完配额，之后的数据不再转发
+        peer.send(FrameType::Data, 1, b"ping").await;
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        peer.send(FrameType::Data, 1, b"more").await;
+
+        let error = relay.await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("配额"), "{:#}", error);
+
+        // 客户端从 Close 帧中得知原因
+        let close = peer.recv().await;
+        assert_eq!((close.frame_type, close.stream_id), (FrameType::Close, 1));
+        let reason = String::from_utf8(close.payload).unwrap();
+        assert!(reason.contains("alice") && reason.contains("每日流量配额"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn test_close_reason() {
+        let (session, _opens, mut peer) = session_pair(Role::Client);
+        let (stream_id, _local, relay) = open_stream(&session, &mut peer).await;
+
+        // 服务器因配额用尽关闭流时，转发以服务器给出的原因失败
+        peer.send(FrameType::Close, stream_id, "用户 alice 的每日流量配额已用尽".as_bytes()).await;
+        let error = relay.await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("每日流量配额已用尽"), "{:#}", error);
+        assert_eq!(peer.recv().await.frame_type, FrameType::Close);
+
+        // 正常关闭的流没有原因
+        let (stream_id, _local, relay) = open_stream(&session, &mut peer).await;
+        peer.send(FrameType::Close, stream_id, b"").await;
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_window_stall_and_update() {
+        let (session, mut opens, mut peer) = session_pair(Role::Server);
+        let (remote, _relay) = accept_stream(&session, &mut opens, &mut peer, 1).await;
+        let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+
+        let total = INITIAL_WINDOW as usize + MAX_DATA_CHUNK;
+        let sent: Vec<u8> = (0..total).map(|i| i as u8).collect();
+        let writer = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                remote_write.write_all(&sent).await.unwrap();
+                remote_write
+            }
+        });
+
+        // 没有窗口更新时最多发出一个初始窗口的数据
+        let mut received = Vec::new();
+        loop {
+            let Ok(frame) = tokio::time::timeout(Duration::from_millis(200), peer.recv()).await else {
+                break;
+            };
+            assert_eq!(frame.frame_type, FrameType::Data);
+            received.extend_from_slice(&frame.payload);
+        }
+        assert!(received.len() <= INITIAL_WINDOW as usize);
+        assert!(received.len() > INITIAL_WINDOW as usize - MAX_DATA_CHUNK);
+
+        // 窗口更新后剩余的数据继续发出
+        peer.send_frame(MuxFrame::window_update(1, MAX_DATA_CHUNK as u32)).await;
+        received.extend(peer.recv_data(1, total - received.len()).await);
+        assert_eq!(received, sent);
+        let _remote_write = writer.await.unwrap();
+
+        // 反方向：本地连接读走半个窗口的数据后归还窗口
+        for _ in 0..INITIAL_WINDOW as usize / 2 / MAX_DATA_CHUNK {
+            peer.send(FrameType::Data, 1, &[7; MAX_DATA_CHUNK]).await;
+        }
+        let mut buf = vec![0u8; INITIAL_WINDOW as usize / 2];
+        remote_read.read_exact(&mut buf).await.unwrap();
+        let update = peer.recv().await;
+        assert_eq!((update.frame_type, update.stream_id), (FrameType::WindowUpdate, 1));
+        assert_eq!(update.window_increment().unwrap(), INITIAL_WINDOW / 2);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_stream_does_not_starve_others() {
+        let (session, mut opens, mut peer) = session_pair_with_buffer(Role::Server, 16 * 1024);
+        let (mut bulk, _bulk_relay) = accept_stream(&session, &mut opens, &mut peer, 1).await;
+        let (mut small, _small_relay) = accept_stream(&session, &mut opens, &mut peer, 3).await;
+
+        // 对端暂不读取，大流量的流填满连接的缓冲区和自己在写出队列中的份额
+        tokio::spawn(async move {
+            bulk.write_all(&vec![0u8; INITIAL_WINDOW as usize]).await.unwrap();
+            bulk
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        small.write_all(b"hi").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // 小流的数据只排在已经写进连接的几帧之后，而不是整个发送窗口之后
+        let mut bulk_frames = 0;
+        loop {
+            let frame = peer.recv().await;
+            assert_eq!(frame.frame_type, FrameType::Data);
+            if frame.stream_id == 3 {
+                assert_eq!(frame.payload, b"hi");
+                break;
+            }
+            bulk_frames += 1;
+        }
+        assert!(bulk_frames < 8, "小流的数据排在 {} 个数据帧之后", bulk_frames);
+    }
+
+    #[tokio::test]
+    async fn test_many_streams() {
+        let (session, mut opens, mut peer) = session_pair(Role::Server);
+
+        // 每个流的目标原样回显收到的数据
+        let mut relays = Vec::new();
+        for stream_id in 1..=32 {
+            let (remote, relay) = accept_stream(&session, &mut opens, &mut peer, stream_id).await;
+            tokio::spawn(async move {
+                let (mut read, mut write) = tokio::io::split(remote);
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+            relays.push(relay);
+        }
+
+        // 交错发送各流的数据，回显的数据不会串到别的流
+        for round in 0..3u8 {
+            for stream_id in 1..=32u32 {
+                peer.send(FrameType::Data, stream_id, &[stream_id as u8 ^ round; 1000]).await;
+            }
+        }
+        let mut echoed: HashMap<u32, Vec<u8>> = HashMap::new();
+        while echoed.values().map(Vec::len).sum::<usize>() < 32 * 3000 {
+            let frame = peer.recv().await;
+            assert_eq!(frame.frame_type, FrameType::Data);
+            echoed.entry(frame.stream_id).or_default().extend(frame.payload);
+        }
+        for (stream_id, data) in &echoed {
+            let expected: Vec<u8> = (0..3u8).flat_map(|round| [*stream_id as u8 ^ round; 1000]).collect();
+            assert_eq!(data, &expected, "流 {}", stream_id);
+        }
+
+        for stream_id in 1..=32 {
+            peer.send(FrameType::Close, stream_id, b"").await;
+        }
+        for relay in relays {
+            relay.await.unwrap().unwrap();
+        }
+        assert_eq!(session.stream_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_receive_window_violation_resets_stream() {
+        let (session, _opens, mut peer) = session_pair(Role::Server);
+        let mut stream = session.register_stream(1).unwrap();
+
+        // 对端不等窗口更新，多发一个数据帧
+        let frames = INITIAL_WINDOW as usize / MAX_DATA_CHUNK + 1;
+        for _ in 0..frames {
+            peer.send(FrameType::Data, 1, &[0; MAX_DATA_CHUNK]).await;
+        }
+
+        // 窗口内的数据照常送达，超出窗口时流被移除，事件通道随之关闭
+        let mut received = 0;
+        while let Some(event) = stream.events.recv().await {
+            let StreamEvent::Data(data) = event else {
+                panic!("意外的事件 {:?}", event);
+            };
+            received += data.len();
+        }
+        assert_eq!(received, INITIAL_WINDOW as usize);
+        assert_eq!(session.stream_count(), 0);
+
+        drop(stream);
+        let close = peer.recv().await;
+        assert_eq!((close.frame_type, close.stream_id), (FrameType::Close, 1));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_stream_id() {
+        let (session, _opens, mut peer) = session_pair(Role::Server);
+        let old = session.register_stream(1).unwrap();
+
+        // 仍在转发的流的 ID 不能再次注册
+        assert!(session.register_stream(1).is_err());
+        assert_eq!(session.stream_count(), 1);
+
+        // 旧流因违反流量控制被移除后 ID 可以重新使用，旧流释放时不会移除新流
+        for _ in 0..INITIAL_WINDOW as usize / MAX_DATA_CHUNK + 1 {
+            peer.send(FrameType::Data, 1, &[0; MAX_DATA_CHUNK]).await;
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while session.stream_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut new = session.register_stream(1).unwrap();
+        drop(old);
+        assert_eq!(session.stream_count(), 1);
+
+        peer.send(FrameType::Data, 1, b"new").await;
+        assert!(matches!(new.events.recv().await, Some(StreamEvent::Data(data)) if data == b"new"));
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use snow::{params::NoiseParams, Builder, HandshakeState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::codec::FrameError;
+use crate::crypto::{CryptoManager, SessionCipher};
+
+/// Noise IK：客户端预先固定服务器的静态公钥，一个往返完成握手。
+/// 预共享密钥在第一条消息末尾混入，没有 `--key` 的扫描者在服务器回复之前就被拒绝
+const NOISE_PARAMS: &str = "Noise_IKpsk1_25519_AESGCM_SHA256";
+
+/// 握手绑定的前言，防止与其他使用相同密钥的 Noise 协议混用
+const PROLOGUE: &[u8] = b"leaf-noise-ik";
+
+/// X25519 密钥长度
+pub const KEY_LEN: usize = 32;
+
+/// 单条握手消息的上限；IK 的两条消息都不到 128 字节
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// 握手完成后两个方向的会话密钥，交给 v2 帧使用
+pub struct TransportKeys {
+    pub sealer: SessionCipher,
+    pub opener: SessionCipher,
+    /// 握手中混入的预共享密钥
+    pub crypto: CryptoManager,
+}
+
+/// 服务器的 Noise 静态私钥
+#[derive(Clone)]
+pub struct ServerKey {
+    private: [u8; KEY_LEN],
+}
+
+impl ServerKey {
+    pub fn from_base64(key: &str) -> Result<Self> {
+        Ok(Self {
+            private: decode_key(key)?,
+        })
+    }
+}
+
+/// 解析客户端固定的服务器公钥
+pub fn parse_public_key(key: &str) -> Result<[u8; KEY_LEN]> {
+    decode_key(key)
+}
+
+/// 生成一对服务器静态密钥，返回 base64 编码的 (私钥, 公钥)
+pub fn generate_keypair() -> Result<(String, String)> {
+    let keypair = Builder::new(params()).generate_keypair()?;
+    Ok((STANDARD.encode(keypair.private), STANDARD.encode(keypair.public)))
+}
+
+/// 客户端发起握手；服务器的静态公钥不匹配时失败
+pub async fn initiate<S>(
+    stream: &mut S,
+    crypto: &CryptoManager,
+    server_public: &[u8; KEY_LEN],
+) -> Result<TransportKeys>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // 客户端身份由之后的 token 认证，静态密钥每个连接重新生成
+    let local = Builder::new(params()).generate_keypair()?;
+    let mut noise = Builder::new(params())
+        .local_private_key(&local.private)
+        .remote_public_key(server_public)
+        .psk(1, crypto.psk())
+        .prologue(PROLOGUE)
+        .build_initiator()?;
+
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    let len = noise.write_message(&[], &mut buf)?;
+    write_message(stream, &buf[..len]).await?;
+
+    let message = read_message(stream).await?;
+    noise
+        .read_message(&message, &mut buf)
+        .map_err(|_| anyhow!("Noise 握手失败：服务器公钥或密钥不匹配"))?;
+
+    Ok(split(&mut noise, crypto))
+}
+
+/// 服务器响应握手；依次用每个候选预共享密钥尝试客户端的第一条消息
+/// 所有密钥都失败时返回的错误可以向下转换为 `FrameError::Decrypt`，与帧的试解密失败一样计入来源的额度
+pub async fn respond<S>(stream: &mut S, candidates: &[CryptoManager], key: &ServerKey) -> Result<TransportKeys>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    let message = read_message(stream).await?;
+
+    for crypto in candidates {
+        let mut noise = Builder::new(params())
+            .local_private_key(&key.private)
+            .psk(1, crypto.psk())
+            .prologue(PROLOGUE)
+            .build_responder()?;
+        if noise.read_message(&message, &mut buf).is_err() {
+            continue;
+        }
+
+        let len = noise.write_message(&[], &mut buf)?;
+        write_message(stream, &buf[..len]).await?;
+        return Ok(split(&mut noise, crypto));
+    }
+
+    Err(anyhow::Error::new(FrameError::Decrypt).context("Noise 握手失败：客户端使用了错误的服务器公钥或密钥"))
+}
+
+fn params() -> NoiseParams {
+    NOISE_PARAMS.parse().expect("Noise 参数是常量")
+}
+
+fn decode_key(key: &str) -> Result<[u8; KEY_LEN]> {
+    STANDARD
+        .decode(key)?
+        .try_into()
+        .map_err(|_| anyhow!("Noise 密钥长度必须是 {} 字节", KEY_LEN))
+}
+
+/// 取出两个方向的密钥；第一个总是客户端到服务器方向
+fn split(noise: &mut HandshakeState, crypto: &CryptoManager) -> TransportKeys {
+    let (initiator, responder) = noise.dangerously_get_raw_split();
+    let (sealer, opener) = if noise.is_initiator() {
+        (initiator, responder)
+    } else {
+        (responder, initiator)
+    };
+    TransportKeys {
+        sealer: SessionCipher::new(&sealer),
+        opener: SessionCipher::new(&opener),
+        crypto: crypto.clone(),
+    }
+}
+
+/// 握手消息格式: [长度 u16 大端][消息]
+async fn write_message<S>(stream: &mut S, message: &[u8]) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u16(message.len() as u16).await?;
+    stream.write_all(message).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_message<S>(stream: &mut S) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await? as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(anyhow!("Noise 握手消息长度 {} 超过上限 {}", len, MAX_MESSAGE_LEN));
+    }
+
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_noise_handshake() {
+        let crypto = CryptoManager::new(&CryptoManager::generate_key()).unwrap();
+        let (private, public) = generate_keypair().unwrap();
+        let server_key = ServerKey::from_base64(&private).unwrap();
+        let server_public = parse_public_key(&public).unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server_crypto = crypto.clone();
+        let responder =
+            tokio::spawn(async move { respond(&mut server, &[server_crypto], &server_key).await });
+        let mut client_keys = initiate(&mut client, &crypto, &server_public).await.unwrap();
+        let mut server_keys = responder.await.unwrap().unwrap();
+
+        let sealed = client_keys.sealer.seal(b"ping").unwrap();
+        assert_eq!(server_keys.opener.open(&sealed).unwrap(), b"ping");
+        let sealed = server_keys.sealer.seal(b"pong").unwrap();
+        assert_eq!(client_keys.opener.open(&sealed).unwrap(), b"pong");
+
+        // 固定了另一个服务器公钥的客户端无法完成握手
+        let (_, other_public) = generate_keypair().unwrap();
+        let other_public = parse_public_key(&other_public).unwrap();
+        let server_key = ServerKey::from_base64(&private).unwrap();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server_crypto = crypto.clone();
+        let responder =
+            tokio::spawn(async move { respond(&mut server, &[server_crypto], &server_key).await });
+        assert!(initiate(&mut client, &crypto, &other_public).await.is_err());
+        let error = responder.await.unwrap().err().unwrap();
+        assert!(matches!(error.downcast_ref::<FrameError>(), Some(FrameError::Decrypt)));
+    }
+}
@@ -0,0 +1,652 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+use crate::TargetAddr;
+
+/// 连续失败多少次后摘除服务器
+pub const EJECT_AFTER_FAILURES: u32 = 2;
+
+/// 摘除的服务器连续成功多少次后恢复
+pub const READMIT_AFTER_SUCCESSES: u32 = 2;
+
+/// 单次健康检查的超时
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 连接上游服务器并完成握手的超时，服务器不回应时尽快记为失败并换用其他服务器
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 一致性哈希环上每个服务器的虚拟节点数
+const VIRTUAL_NODES: usize = 64;
+
+/// 延迟指数平滑中新样本的权重
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// `latency` 策略只在其他服务器的延迟低于当前服务器的这个比例时切换
+const LATENCY_SWITCH_RATIO: f64 = 0.8;
+
+/// 并且至少快这么多，避免低延迟时因为抖动来回切换
+const LATENCY_SWITCH_MIN_GAIN: Duration = Duration::from_millis(10);
+
+/// 为新连接选择服务器的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Strategy {
+    /// 总是使用排在最前面的可用服务器
+    Failover,
+    /// 依次轮流使用可用服务器
+    RoundRobin,
+    /// 使用活跃流最少的可用服务器
+    LeastConnections,
+    /// 按目标主机的哈希选择，同一目标总是经过同一台服务器
+    ConsistentHash,
+    /// 使用健康检查测得延迟最低的可用服务器
+    Latency,
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strategy::Failover => "failover",
+            Strategy::RoundRobin => "round-robin",
+            Strategy::LeastConnections => "least-connections",
+            Strategy::ConsistentHash => "consistent-hash",
+            Strategy::Latency => "latency",
+        })
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "consistent-hash" => Ok(Strategy::ConsistentHash),
+            "latency" => Ok(Strategy::Latency),
+            _ => Err(anyhow!(
+                "未知的服务器选择策略: {} (可选 failover、round-robin、least-connections、consistent-hash、latency)",
+                s
+            )),
+        }
+    }
+}
+
+impl From<Strategy> for String {
+    fn from(strategy: Strategy) -> Self {
+        strategy.to_string()
+    }
+}
+
+impl TryFrom<String> for Strategy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+/// 配置中的一台服务器，写作 `地址` 或 `名称=地址`，不写名称时用地址作名称
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ServerSpec {
+    pub name: String,
+    pub addr: String,
+}
+
+impl fmt::Display for ServerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name == self.addr {
+            f.write_str(&self.addr)
+        } else {
+            write!(f, "{}={}", self.name, self.addr)
+        }
+    }
+}
+
+impl FromStr for ServerSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        // URL 的查询参数里也可能有 `=`，名称中不能出现 `:` 和 `/`
+        let (name, addr) = match s.split_once('=') {
+            Some((name, addr)) if !name.contains([':', '/']) => (name.trim(), addr.trim()),
+            _ => (s, s),
+        };
+        if name.is_empty() || addr.is_empty() {
+            return Err(anyhow!("无效的服务器: {}，格式应为 地址 或 名称=地址", s));
+        }
+        Ok(ServerSpec {
+            name: name.to_string(),
+            addr: addr.to_string(),
+        })
+    }
+}
+
+impl From<ServerSpec> for String {
+    fn from(spec: ServerSpec) -> Self {
+        spec.to_string()
+    }
+}
+
+impl TryFrom<String> for ServerSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+/// 池中一台上游服务器的连接方式，由 TCP 和 WebSocket 客户端分别实现
+pub trait Upstream: Send + Sync + 'static {
+    type Session: Send;
+
+    /// 返回已认证的会话，必要时建立连接并完成握手
+    fn session(&self) -> impl Future<Output = Result<Self::Session>> + Send;
+
+    /// 新建一个连接完成完整的握手后关闭，用于健康检查
+    fn probe(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// 经会话打开一个到 `target` 的流，服务器连上目标后关闭，用于测量经服务器连接目标的延迟
+    fn probe_target(&self, target: &TargetAddr) -> impl Future<Output = Result<()>> + Send;
+
+    /// 当前经过这台服务器的活跃流数
+    fn active_streams(&self) -> usize;
+}
+
+/// 健康检查测得的平滑延迟，尚未测得时为 `None`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    /// 新建连接并完成握手的耗时
+    pub handshake: Option<Duration>,
+    /// 经服务器连接探测目标的耗时
+    pub connect: Option<Duration>,
+}
+
+/// 池中的一台服务器及其健康状态
+pub struct Server<T> {
+    name: String,
+    upstream: T,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    successes: AtomicU32,
+    latency: Mutex<Latency>,
+}
+
+impl<T> Server<T> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn upstream(&self) -> &T {
+        &self.upstream
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn latency(&self) -> Latency {
+        *self.latency.lock().unwrap()
+    }
+
+    /// 记录一次连接或健康检查的结果，连续失败时摘除，摘除后连续成功时恢复
+    fn record(&self, success: bool) {
+        if success {
+            self.failures.store(0, Ordering::Relaxed);
+            let successes = self.successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if successes >= READMIT_AFTER_SUCCESSES && !self.healthy.swap(true, Ordering::Relaxed) {
+                info!("服务器 {} 已恢复", self.name);
+            }
+        } else {
+            self.successes.store(0, Ordering::Relaxed);
+            let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= EJECT_AFTER_FAILURES && self.healthy.swap(false, Ordering::Relaxed) {
+                warn!("服务器 {} 连续 {} 次失败，暂时摘除", self.name, failures);
+            }
+        }
+    }
+
+    /// 把一次测量并入平滑延迟，`None` 表示测量失败，清除旧值
+    fn observe(&self, field: fn(&mut Latency) -> &mut Option<Duration>, sample: Option<Duration>) {
+        let mut latency = self.latency.lock().unwrap();
+        let value = field(&mut latency);
+        *value = match (*value, sample) {
+            (Some(old), Some(sample)) => Some(old.mul_f64(1.0 - LATENCY_SMOOTHING) + sample.mul_f64(LATENCY_SMOOTHING)),
+            (_, sample) => sample,
+        };
+    }
+}
+
+/// 一组可以互相替代的上游服务器
+pub struct ServerPool<T> {
+    servers: Vec<Server<T>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    /// 一致性哈希环：按哈希值排序的 (哈希, 服务器下标)
+    ring: Vec<(u64, usize)>,
+    /// `latency` 策略当前使用的服务器下标
+    preferred: AtomicUsize,
+    health_check_interval: Option<Duration>,
+    latency_probe: Option<TargetAddr>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<T: Upstream> ServerPool<T> {
+    pub fn new(servers: Vec<(String, T)>, strategy: Strategy) -> Self {
+        let mut ring: Vec<(u64, usize)> = servers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (name, _))| (0..VIRTUAL_NODES).map(move |node| (hash(&format!("{}#{}", name, node)), index)))
+            .collect();
+        ring.sort_unstable();
+
+        let servers = servers
+            .into_iter()
+            .map(|(name, upstream)| Server {
+                name,
+                upstream,
+                healthy: AtomicBool::new(true),
+                failures: AtomicU32::new(0),
+                successes: AtomicU32::new(0),
+                latency: Mutex::new(Latency::default()),
+            })
+            .collect();
+
+        Self {
+            servers,
+            strategy,
+            next: AtomicUsize::new(0),
+            ring,
+            preferred: AtomicUsize::new(0),
+            health_check_interval: None,
+            latency_probe: None,
+            metrics: None,
+        }
+    }
+
+    /// 设置后台健康检查的间隔，`None` 时只根据连接结果摘除和恢复
+    pub fn health_check_interval(mut self, interval: Option<Duration>) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// 健康检查时经每台服务器连接这个目标，`latency` 策略按连接目标的延迟而不是握手延迟选择
+    pub fn latency_probe(mut self, target: Option<TargetAddr>) -> Self {
+        self.latency_probe = target;
+        self
+    }
+
+    /// 每轮健康检查后把各服务器的健康状态和延迟写入指标
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn servers(&self) -> &[Server<T>] {
+        &self.servers
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// 为目标选出会话；`key` 是目标主机，用于一致性哈希
+    /// 首选的服务器连接失败时依次尝试其他服务器，返回服务器名称和会话
+    pub async fn session(&self, key: &str) -> Result<(&str, T::Session)> {
+        let mut last_error = None;
+        for index in self.candidates(key) {
+            let server = &self.servers[index];
+            match server.upstream.session().await {
+                Ok(session) => {
+                    server.record(true);
+                    return Ok((&server.name, session));
+                }
+                Err(e) => {
+                    warn!("连接服务器 {} 失败: {:#}", server.name, e);
+                    server.record(false);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("没有配置代理服务器")))
+    }
+
+    /// 按策略排列的候选服务器下标：可用的服务器在前，摘除的服务器在最后作为兜底
+    fn candidates(&self, key: &str) -> Vec<usize> {
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) =
+            (0..self.servers.len()).partition(|&index| self.servers[index].is_healthy());
+
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin if !healthy.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                healthy.rotate_left(start);
+            }
+            Strategy::RoundRobin => {}
+            Strategy::LeastConnections => {
+                healthy.sort_by_key(|&index| self.servers[index].upstream.active_streams());
+            }
+            Strategy::ConsistentHash => {
+                let start = self.ring.partition_point(|(point, _)| *point < hash(key));
+                let mut ordered = Vec::with_capacity(healthy.len());
+                for (_, index) in self.ring[start..].iter().chain(&self.ring[..start]) {
+                    if healthy.contains(index) && !ordered.contains(index) {
+                        ordered.push(*index);
+                    }
+                }
+                healthy = ordered;
+            }
+            Strategy::Latency => {
+                // 当前使用的服务器在最前，其余按延迟排列，尚未测得延迟的排在后面
+                let preferred = self.preferred.load(Ordering::Relaxed);
+                healthy.sort_by_key(|&index| (index != preferred, self.score(index).unwrap_or(Duration::MAX)));
+            }
+        }
+
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// 定期对每台服务器做一次完整的握手，摘除连续失败的服务器并恢复重新可用的服务器
+    pub async fn run_health_checks(self: Arc<Self>) {
+        let Some(interval) = self.health_check_interval else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            futures_util::future::join_all(self.servers.iter().map(|server| self.check(server))).await;
+            self.update_preferred();
+            self.report();
+        }
+    }
+
+    /// 对一台服务器做一次健康检查，同时测量握手延迟和经服务器连接探测目标的延迟
+    async fn check(&self, server: &Server<T>) {
+        let started = Instant::now();
+        let result = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, server.upstream.probe()).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("握手超时")),
+        };
+        server.record(result.is_ok());
+        if let Err(e) = result {
+            warn!("服务器 {} 健康检查失败: {:#}", server.name, e);
+            return;
+        }
+        server.observe(|latency| &mut latency.handshake, Some(started.elapsed()));
+
+        let Some(target) = &self.latency_probe else {
+            return;
+        };
+        let started = Instant::now();
+        let result = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, server.upstream.probe_target(target)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("连接超时")),
+        };
+        // 探测目标不可达不一定是服务器的问题，只清除连接延迟，不计入健康状态
+        let sample = match result {
+            Ok(()) => Some(started.elapsed()),
+            Err(e) => {
+                warn!("经服务器 {} 连接探测目标 {} 失败: {:#}", server.name, target, e);
+                None
+            }
+        };
+        server.observe(|latency| &mut latency.connect, sample);
+    }
+
+    /// `latency` 策略比较的延迟：配置了探测目标时用连接目标的延迟，否则用握手延迟
+    fn score(&self, index: usize) -> Option<Duration> {
+        let latency = self.servers[index].latency();
+        match self.latency_probe {
+            Some(_) => latency.connect,
+            None => latency.handshake,
+        }
+    }
+
+    /// 健康检查后为 `latency` 策略重新选择服务器
+    /// 只有当前服务器不可用，或另一台服务器明显更快时才切换
+    fn update_preferred(&self) {
+        if self.strategy != Strategy::Latency {
+            return;
+        }
+        let Some((fastest, index)) = (0..self.servers.len())
+            .filter(|&index| self.servers[index].is_healthy())
+            .filter_map(|index| Some((self.score(index)?, index)))
+            .min()
+        else {
+            return;
+        };
+
+        let current = self.preferred.load(Ordering::Relaxed);
+        if index == current {
+            return;
+        }
+        let switch = match self.score(current) {
+            Some(latency) if self.servers[current].is_healthy() => {
+                fastest < latency.mul_f64(LATENCY_SWITCH_RATIO) && latency - fastest >= LATENCY_SWITCH_MIN_GAIN
+            }
+            _ => true,
+        };
+        if switch {
+            self.preferred.store(index, Ordering::Relaxed);
+            info!("切换到延迟最低的服务器 {} ({} ms)", self.servers[index].name, fastest.as_millis());
+        }
+    }
+
+    /// 输出各服务器的延迟；使用 `latency` 策略时记录在 info 级别
+    fn report(&self) {
+        let summary: Vec<String> = self
+            .servers
+            .iter()
+            .map(|server| {
+                let latency = server.latency();
+                if let Some(metrics) = &self.metrics {
+                    metrics.server_status(&server.name, server.is_healthy(), latency.handshake, latency.connect);
+                }
+                let mut line = format!("{} 握手 {}", server.name, millis(latency.handshake));
+                if self.latency_probe.is_some() {
+                    line.push_str(&format!("，连接 {}", millis(latency.connect)));
+                }
+                if !server.is_healthy() {
+                    line.push_str(" (已摘除)");
+                }
+                line
+            })
+            .collect();
+
+        if self.strategy == Strategy::Latency {
+            info!("服务器延迟: {}", summary.join("; "));
+        } else {
+            debug!("服务器延迟: {}", summary.join("; "));
+        }
+    }
+}
+
+fn millis(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{} ms", latency.as_millis()),
+        None => "-".to_string(),
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeUpstream {
+        up: AtomicBool,
+        streams: AtomicUsize,
+    }
+
+    impl FakeUpstream {
+        fn new(streams: usize) -> Self {
+            Self {
+                up: AtomicBool::new(true),
+                streams: AtomicUsize::new(streams),
+            }
+        }
+    }
+
+    impl Upstream for FakeUpstream {
+        type Session = ();
+
+        async fn session(&self) -> Result<()> {
+            self.probe().await
+        }
+
+        async fn probe(&self) -> Result<()> {
+            match self.up.load(Ordering::Relaxed) {
+                true => Ok(()),
+                false => Err(anyhow!("down")),
+            }
+        }
+
+        async fn probe_target(&self, _target: &TargetAddr) -> Result<()> {
+            self.probe().await
+        }
+
+        fn active_streams(&self) -> usize {
+            self.streams.load(Ordering::Relaxed)
+        }
+    }
+
+    fn pool(strategy: Strategy, streams: &[usize]) -> ServerPool<FakeUpstream> {
+        let servers = streams
+            .iter()
+            .enumerate()
+            .map(|(index, streams)| (format!("s{}", index), FakeUpstream::new(*streams)))
+            .collect();
+        ServerPool::new(servers, strategy)
+    }
+
+    #[test]
+    fn test_parse_server_spec() {
+        let spec: ServerSpec = "tokyo=tokyo.example.com:8080".parse().unwrap();
+        assert_eq!((spec.name.as_str(), spec.addr.as_str()), ("tokyo", "tokyo.example.com:8080"));
+        assert_eq!(spec.to_string(), "tokyo=tokyo.example.com:8080");
+
+        let spec: ServerSpec = "wss://proxy.example.com/ws?token=1".parse().unwrap();
+        assert_eq!(spec.name, spec.addr);
+        assert_eq!(spec.to_string(), "wss://proxy.example.com/ws?token=1");
+
+        assert!("=127.0.0.1:8080".parse::<ServerSpec>().is_err());
+        assert!("hk=".parse::<ServerSpec>().is_err());
+        assert_eq!("least-connections".parse::<Strategy>().unwrap(), Strategy::LeastConnections);
+        assert!("random".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn test_strategies() {
+        let failover = pool(Strategy::Failover, &[0, 0, 0]);
+        assert_eq!(failover.candidates("a"), [0, 1, 2]);
+        assert_eq!(failover.candidates("b"), [0, 1, 2]);
+
+        let round_robin = pool(Strategy::RoundRobin, &[0, 0, 0]);
+        let first: Vec<usize> = (0..3).map(|_| round_robin.candidates("a")[0]).collect();
+        assert_eq!(first, [0, 1, 2]);
+
+        let least = pool(Strategy::LeastConnections, &[5, 1, 3]);
+        assert_eq!(least.candidates("a"), [1, 2, 0]);
+
+        // 同一目标总是得到相同的顺序，不同目标分散到不同服务器
+        let hashed = pool(Strategy::ConsistentHash, &[0, 0, 0, 0]);
+        assert_eq!(hashed.candidates("example.com"), hashed.candidates("example.com"));
+        assert_eq!(hashed.candidates("example.com").len(), 4);
+        let chosen: std::collections::HashSet<usize> =
+            (0..64).map(|i| hashed.candidates(&format!("host{}.example", i))[0]).collect();
+        assert!(chosen.len() > 1);
+    }
+
+    #[test]
+    fn test_latency_hysteresis() {
+        let pool = pool(Strategy::Latency, &[0, 0, 0]);
+        let measure = |index: usize, millis: u64| {
+            *pool.servers[index].latency.lock().unwrap() = Latency {
+                handshake: Some(Duration::from_millis(millis)),
+                connect: None,
+            };
+        };
+
+        // 尚未测得延迟的服务器排在最后
+        measure(0, 100);
+        measure(1, 50);
+        pool.update_preferred();
+        assert_eq!(pool.candidates("a"), [1, 0, 2]);
+
+        // 只快一点时不切换，明显更快时才切换
+        measure(0, 45);
+        pool.update_preferred();
+        assert_eq!(pool.candidates("a")[0], 1);
+        measure(0, 30);
+        pool.update_preferred();
+        assert_eq!(pool.candidates("a"), [0, 1, 2]);
+
+        // 当前服务器被摘除时立即切换
+        for _ in 0..EJECT_AFTER_FAILURES {
+            pool.servers[0].record(false);
+        }
+        pool.update_preferred();
+        assert_eq!(pool.candidates("a"), [1, 2, 0]);
+
+        // 新样本按权重并入平滑延迟，测量失败时清除
+        let server = &pool.servers[2];
+        server.observe(|latency| &mut latency.connect, Some(Duration::from_millis(100)));
+        server.observe(|latency| &mut latency.connect, Some(Duration::from_millis(200)));
+        assert_eq!(server.latency().connect.unwrap().as_millis(), 130);
+        server.observe(|latency| &mut latency.connect, None);
+        assert_eq!(server.latency().connect, None);
+    }
+
+    #[tokio::test]
+    async fn test_eject_and_readmit() {
+        let pool = pool(Strategy::ConsistentHash, &[0, 0, 0]);
+        let preferred = pool.candidates("example.com")[0];
+        pool.servers[preferred].upstream.up.store(false, Ordering::Relaxed);
+
+        // 首选服务器失败时转到下一台，连续失败后被摘除
+        for _ in 0..EJECT_AFTER_FAILURES {
+            let (name, _) = pool.session("example.com").await.unwrap();
+            assert_ne!(name, pool.servers[preferred].name);
+        }
+        assert!(!pool.servers[preferred].is_healthy());
+        assert_eq!(*pool.candidates("example.com").last().unwrap(), preferred);
+        // 其余目标在摘除前后的选择不受影响
+        let other = (0..64)
+            .map(|i| format!("host{}.example", i))
+            .find(|key| pool.candidates(key)[0] != preferred)
+            .unwrap();
+        let before = pool.candidates(&other)[0];
+        pool.servers[preferred].upstream.up.store(true, Ordering::Relaxed);
+        for _ in 0..READMIT_AFTER_SUCCESSES {
+            pool.servers[preferred].record(true);
+        }
+        assert!(pool.servers[preferred].is_healthy());
+        assert_eq!(pool.candidates("example.com")[0], preferred);
+        assert_eq!(pool.candidates(&other)[0], before);
+
+        // 全部失败时返回最后一个错误
+        for server in &pool.servers {
+            server.upstream.up.store(false, Ordering::Relaxed);
+        }
+        assert!(pool.session("example.com").await.is_err());
+    }
+}
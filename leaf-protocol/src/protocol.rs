@@ -0,0 +1,403 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// 握手请求结构体
+/// 客户端向服务器发送的初始连接请求；token 不随请求发送，
+/// 服务器随后回复 `AuthChallenge`，客户端用 `AuthProof` 证明持有 token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeRequest {
+    /// 客户端唯一标识符
+    pub client_id: String,
+    /// 服务器用户数据库中的用户名；不发送时使用服务器的 `--token`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// 发送时的 Unix 时间（秒），计入认证证明；服务器拒绝超出时间窗口的握手
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+/// 握手响应结构体
+/// 服务器对客户端握手请求的回复
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeResponse {
+    /// 握手是否成功
+    pub success: bool,
+    /// 响应消息，包含成功或失败的原因
+    pub message: String,
+    /// 会话ID，握手成功时提供，用于后续通信
+    pub session_id: Option<String>,
+}
+
+/// 代理目标地址
+/// 保留 SOCKS5 请求中的地址类型：域名原样交给服务器解析，客户端不做 DNS 查询
+///
+/// 线上编码为 "host:port" 字符串（IPv6 地址带方括号），
+/// 与旧版本直接发送地址字符串的 `target_addr` 字段保持兼容
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TargetAddr {
+    /// IPv4 地址和端口
+    Ipv4(Ipv4Addr, u16),
+    /// IPv6 地址和端口
+    Ipv6(Ipv6Addr, u16),
+    /// 域名和端口，由服务器端解析
+    Domain(String, u16),
+}
+
+/// SOCKS5 域名地址的最大长度
+pub const MAX_DOMAIN_LEN: usize = 255;
+
+/// SOCKS5 地址类型
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+impl TargetAddr {
+    /// 不带端口的主机部分：域名或 IP 地址
+    pub fn host(&self) -> String {
+        match self {
+            TargetAddr::Ipv4(ip, _) => ip.to_string(),
+            TargetAddr::Ipv6(ip, _) => ip.to_string(),
+            TargetAddr::Domain(domain, _) => domain.clone(),
+        }
+    }
+
+    pub fn domain(domain: String, port: u16) -> anyhow::Result<Self> {
+        if domain.is_empty() || domain.len() > MAX_DOMAIN_LEN {
+            return Err(anyhow::anyhow!("无效的域名长度: {}", domain.len()));
+        }
+        if domain.contains(':') {
+            return Err(anyhow::anyhow!("无效的域名: {}", domain));
+        }
+        Ok(TargetAddr::Domain(domain, port))
+    }
+
+    /// 按 SOCKS5 地址格式编码: [ATYP][地址][端口 u16 大端]
+    pub fn encode_socks(&self, buf: &mut Vec<u8>) {
+        match self {
+            TargetAddr::Ipv4(ip, port) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&ip.octets());
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+            TargetAddr::Ipv6(ip, port) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&ip.octets());
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+            TargetAddr::Domain(domain, port) => {
+                buf.push(ATYP_DOMAIN);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+    }
+
+    /// 解析 SOCKS5 地址格式，返回地址和消耗的字节数
+    pub fn decode_socks(buf: &[u8]) -> anyhow::Result<(Self, usize)> {
+        let too_short = || anyhow::anyhow!("SOCKS5 地址长度不足");
+        let port_at = |offset: usize| -> anyhow::Result<u16> {
+            let bytes = buf.get(offset..offset + 2).ok_or_else(too_short)?;
+            Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+        };
+
+        match *buf.first().ok_or_else(too_short)? {
+            ATYP_IPV4 => {
+                let octets: [u8; 4] = buf.get(1..5).ok_or_else(too_short)?.try_into()?;
+                Ok((TargetAddr::Ipv4(octets.into(), port_at(5)?), 7))
+            }
+            ATYP_IPV6 => {
+                let octets: [u8; 16] = buf.get(1..17).ok_or_else(too_short)?.try_into()?;
+                Ok((TargetAddr::Ipv6(octets.into(), port_at(17)?), 19))
+            }
+            ATYP_DOMAIN => {
+                let len = *buf.get(1).ok_or_else(too_short)? as usize;
+                let domain = buf.get(2..2 + len).ok_or_else(too_short)?;
+                let domain = String::from_utf8(domain.to_vec())?;
+                Ok((TargetAddr::domain(domain, port_at(2 + len)?)?, 4 + len))
+            }
+            atyp => Err(anyhow::anyhow!("不支持的地址类型: {}", atyp)),
+        }
+    }
+}
+
+impl From<SocketAddr> for TargetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => TargetAddr::Ipv4(*addr.ip(), addr.port()),
+            SocketAddr::V6(addr) => TargetAddr::Ipv6(*addr.ip(), addr.port()),
+        }
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ipv4(ip, port) => write!(f, "{}:{}", ip, port),
+            TargetAddr::Ipv6(ip, port) => write!(f, "[{}]:{}", ip, port),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+impl FromStr for TargetAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("目标地址缺少端口: {}", s))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| anyhow::anyhow!("无效的端口: {}", s))?;
+
+        TargetAddr::domain(host.to_string(), port)
+    }
+}
+
+impl TryFrom<String> for TargetAddr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TargetAddr> for String {
+    fn from(addr: TargetAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+/// 代理命令，对应 SOCKS5 请求中的 CMD 字段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ProxyCommand {
+    /// 建立到目标地址的 TCP 连接
+    #[default]
+    Connect,
+    /// 建立 UDP 转发关联，数据报通过 `Datagram` 帧传输
+    UdpAssociate,
+    /// 在服务器上监听，接受目标地址发起的一个入站连接
+    /// 服务器先答复监听地址，接受连接后再答复一次对端地址
+    Bind,
+}
+
+/// 代理请求结构体
+/// 客户端请求代理连接到目标地址
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProxyRequest {
+    /// 目标地址，域名由服务器解析
+    pub target_addr: TargetAddr,
+    /// 代理命令，未提供时为 CONNECT
+    #[serde(default)]
+    pub command: ProxyCommand,
+    /// 通过本地 SOCKS5 认证的用户名，供服务器记账
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// 代理响应结构体
+/// 服务器对代理请求的回复
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProxyResponse {
+    /// 代理连接是否成功建立
+    pub success: bool,
+    /// 响应消息，包含成功或失败的原因
+    pub message: String,
+    /// BIND 命令的监听地址或入站连接的对端地址
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bound_addr: Option<TargetAddr>,
+}
+
+/// 多路复用帧类型
+/// 握手完成后，每个加密帧承载一个多路复用帧
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// 打开新流，载荷为 JSON 编码的 `ProxyRequest`
+    Open = 0x01,
+    /// 打开流的结果，载荷为 JSON 编码的 `ProxyResponse`
+    OpenAck = 0x02,
+    /// 流数据
+    Data = 0x03,
+    /// 关闭流，载荷为空或 UTF-8 编码的关闭原因 (如用户配额用尽)
+    Close = 0x04,
+    /// 流量控制窗口增量，载荷为 4 字节大端 u32
+    WindowUpdate = 0x05,
+    /// UDP 数据报，载荷为 SOCKS5 格式的地址加数据，不受流量控制
+    Datagram = 0x06,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(FrameType::Open),
+            0x02 => Ok(FrameType::OpenAck),
+            0x03 => Ok(FrameType::Data),
+            0x04 => Ok(FrameType::Close),
+            0x05 => Ok(FrameType::WindowUpdate),
+            0x06 => Ok(FrameType::Datagram),
+            _ => Err(anyhow::anyhow!("未知的帧类型: {}", value)),
+        }
+    }
+}
+
+/// 多路复用帧头长度：类型 (1) + 流 ID (4)
+pub const MUX_HEADER_LEN: usize = 5;
+
+/// 多路复用帧
+/// 格式: [类型 u8][流 ID u32 大端][载荷]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MuxFrame {
+    /// 帧类型
+    pub frame_type: FrameType,
+    /// 所属流 ID
+    pub stream_id: u32,
+    /// 帧载荷
+    pub payload: Vec<u8>,
+}
+
+impl MuxFrame {
+    pub fn new(frame_type: FrameType, stream_id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            frame_type,
+            stream_id,
+            payload,
+        }
+    }
+
+    pub fn window_update(stream_id: u32, increment: u32) -> Self {
+        Self::new(FrameType::WindowUpdate, stream_id, increment.to_be_bytes().to_vec())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MUX_HEADER_LEN + self.payload.len());
+        buf.push(self.frame_type as u8);
+        buf.extend_from_slice(&self.stream_id.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    pub fn decode(mut buf: Vec<u8>) -> anyhow::Result<Self> {
+        if buf.len() < MUX_HEADER_LEN {
+            return Err(anyhow::anyhow!("多路复用帧长度不足"));
+        }
+
+        let frame_type = FrameType::try_from(buf[0])?;
+        let stream_id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        let payload = buf.split_off(MUX_HEADER_LEN);
+
+        Ok(Self {
+            frame_type,
+            stream_id,
+            payload,
+        })
+    }
+
+    /// 解析窗口增量帧的载荷
+    pub fn window_increment(&self) -> anyhow::Result<u32> {
+        let bytes: [u8; 4] = self
+            .payload
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("无效的窗口增量帧"))?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+}
+
+/// 编码 `Datagram` 帧载荷: [SOCKS5 地址][数据]
+/// 与 SOCKS5 UDP 请求头去掉 RSV 和 FRAG 后的格式相同
+pub fn encode_datagram(addr: &TargetAddr, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 32);
+    addr.encode_socks(&mut buf);
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// 解析 `Datagram` 帧载荷，返回地址和数据
+pub fn decode_datagram(payload: &[u8]) -> anyhow::Result<(TargetAddr, &[u8])> {
+    let (addr, len) = TargetAddr::decode_socks(payload)?;
+    Ok((addr, &payload[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mux_frame_roundtrip() {
+        let frame = MuxFrame::new(FrameType::Data, 42, b"hello".to_vec());
+        let decoded = MuxFrame::decode(frame.encode()).unwrap();
+
+        assert_eq!(frame, decoded);
+    }
+
+    #[test]
+    fn test_window_update() {
+        let frame = MuxFrame::window_update(7, 65536);
+        let decoded = MuxFrame::decode(frame.encode()).unwrap();
+
+        assert_eq!(decoded.frame_type, FrameType::WindowUpdate);
+        assert_eq!(decoded.window_increment().unwrap(), 65536);
+    }
+
+    #[test]
+    fn test_invalid_frame() {
+        assert!(MuxFrame::decode(vec![0x03, 0, 0]).is_err());
+        assert!(MuxFrame::decode(vec![0xFF, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_target_addr_keeps_type() {
+        let cases = [
+            ("1.2.3.4:80", TargetAddr::Ipv4("1.2.3.4".parse().unwrap(), 80)),
+            ("[::1]:443", TargetAddr::Ipv6("::1".parse().unwrap(), 443)),
+            ("example.com:8080", TargetAddr::Domain("example.com".to_string(), 8080)),
+        ];
+
+        for (text, addr) in cases {
+            assert_eq!(text.parse::<TargetAddr>().unwrap(), addr);
+            assert_eq!(addr.to_string(), text);
+
+            let json = serde_json::to_string(&ProxyRequest {
+                target_addr: addr.clone(),
+                command: ProxyCommand::Connect,
+                user: None,
+            })
+            .unwrap();
+            let request: ProxyRequest = serde_json::from_str(&json).unwrap();
+            assert_eq!(request.target_addr, addr);
+        }
+
+        assert!("example.com".parse::<TargetAddr>().is_err());
+        assert!(":80".parse::<TargetAddr>().is_err());
+        assert!("example.com:99999".parse::<TargetAddr>().is_err());
+    }
+
+    #[test]
+    fn test_datagram_roundtrip() {
+        let addrs = [
+            TargetAddr::Ipv4("8.8.8.8".parse().unwrap(), 53),
+            TargetAddr::Ipv6("2001:db8::1".parse().unwrap(), 443),
+            TargetAddr::Domain("example.com".to_string(), 53),
+        ];
+
+        for addr in addrs {
+            let payload = encode_datagram(&addr, b"query");
+            let (decoded, data) = decode_datagram(&payload).unwrap();
+            assert_eq!(decoded, addr);
+            assert_eq!(data, b"query");
+        }
+
+        assert!(decode_datagram(&[0x01, 8, 8, 8]).is_err());
+        assert!(decode_datagram(&[0x03, 10, b'a']).is_err());
+    }
+}
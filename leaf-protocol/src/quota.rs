@@ -0,0 +1,481 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::replay;
+
+/// 用量文件的保存间隔；进程异常退出时最多丢失这段时间内的用量
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 令牌桶最多积攒一秒的流量
+const BURST: Duration = Duration::from_secs(1);
+
+/// 字节数，配置中可以写整数或带单位的字符串（"512KiB"、"10GB"，单位均按 1024 进位）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(ByteSize(bytes)),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u64 = number.parse().map_err(|_| format!("无效的字节数: {}", s))?;
+
+        let shift = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+            "" => 0,
+            "K" => 10,
+            "M" => 20,
+            "G" => 30,
+            "T" => 40,
+            _ => return Err(format!("未知的字节单位: {}", unit)),
+        };
+
+        number
+            .checked_mul(1 << shift)
+            .map(ByteSize)
+            .ok_or_else(|| format!("字节数过大: {}", s))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{:.1} {}", value, UNITS[unit])
+        }
+    }
+}
+
+/// 单个用户的流量限制，配额按上传加下载的总字节数计算
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub daily_quota: Option<u64>,
+    pub monthly_quota: Option<u64>,
+    /// 每秒字节数，同一用户的所有流共享
+    pub upload_rate: Option<u64>,
+    pub download_rate: Option<u64>,
+}
+
+/// 数据方向，以客户端为准：上传是客户端发往目标的数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// 配额已用尽
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaError {
+    Daily(u64),
+    Monthly(u64),
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaError::Daily(quota) => write!(f, "每日流量配额 {} 已用尽", ByteSize(*quota)),
+            QuotaError::Monthly(quota) => write!(f, "每月流量配额 {} 已用尽", ByteSize(*quota)),
+        }
+    }
+}
+
+impl std::error::Error for QuotaError {}
+
+/// 每个用户最多分别记录的客户端本地用户数，超出的本地用户只计入用户自己的用量
+const MAX_LOCAL_USERS: usize = 256;
+
+/// 客户端本地用户 (SOCKS5 或 HTTP 代理认证的用户名) 的累计用量
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct LocalUsage {
+    upload: u64,
+    download: u64,
+}
+
+/// 一个用户的累计用量，按 UTC 自然日和自然月重置
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Usage {
+    upload: u64,
+    download: u64,
+    /// 1970-01-01 起的天数
+    day: u64,
+    day_bytes: u64,
+    /// 年 * 12 + 月 - 1
+    month: u64,
+    month_bytes: u64,
+    /// 按客户端转发的本地用户分别累计，不参与配额
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    local_users: HashMap<String, LocalUsage>,
+}
+
+impl Usage {
+    fn roll(&mut self, now: u64) {
+        let day = now / 86400;
+        if day != self.day {
+            self.day = day;
+            self.day_bytes = 0;
+        }
+        let month = month_index(day);
+        if month != self.month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+
+    fn check(&self, limits: &Limits) -> Result<(), QuotaError> {
+        if let Some(quota) = limits.daily_quota
+            && self.day_bytes >= quota
+        {
+            return Err(QuotaError::Daily(quota));
+        }
+        if let Some(quota) = limits.monthly_quota
+            && self.month_bytes >= quota
+        {
+            return Err(QuotaError::Monthly(quota));
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, direction: Direction, bytes: u64, local_user: Option<&str>) {
+        match direction {
+            Direction::Upload => self.upload += bytes,
+            Direction::Download => self.download += bytes,
+        }
+        self.day_bytes += bytes;
+        self.month_bytes += bytes;
+
+        let Some(local_user) = local_user else {
+            return;
+        };
+        if !self.local_users.contains_key(local_user) {
+            if self.local_users.len() >= MAX_LOCAL_USERS {
+                return;
+            }
+            self.local_users.insert(local_user.to_string(), LocalUsage::default());
+        }
+        if let Some(local) = self.local_users.get_mut(local_user) {
+            match direction {
+                Direction::Upload => local.upload += bytes,
+                Direction::Download => local.download += bytes,
+            }
+        }
+    }
+}
+
+/// 允许透支的令牌桶：先扣除，再按欠下的令牌数等待
+struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate: None,
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if state.rate != rate {
+            state.rate = rate;
+            state.tokens = 0.0;
+            state.last = Instant::now();
+        }
+    }
+
+    /// 取出令牌，返回需要等待的时间
+    fn reserve(&self, bytes: usize) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let rate = state.rate? as f64;
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+        state.last = now;
+        state.tokens = (state.tokens + elapsed * rate).min(rate * BURST.as_secs_f64());
+        state.tokens -= bytes as f64;
+
+        (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / rate))
+    }
+}
+
+/// 一个用户的计量器，同一用户的所有会话和流共享
+pub struct UserMeter {
+    user: String,
+    limits: Mutex<Limits>,
+    usage: Mutex<Usage>,
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl UserMeter {
+    fn new(user: String, usage: Usage) -> Self {
+        Self {
+            user,
+            limits: Mutex::new(Limits::default()),
+            usage: Mutex::new(usage),
+            upload: TokenBucket::new(),
+            download: TokenBucket::new(),
+        }
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    fn set_limits(&self, limits: Limits) {
+        *self.limits.lock().unwrap() = limits;
+        self.upload.set_rate(limits.upload_rate);
+        self.download.set_rate(limits.download_rate);
+    }
+
+    /// 配额是否还有剩余
+    pub fn check(&self) -> Result<(), QuotaError> {
+        let limits = *self.limits.lock().unwrap();
+        let mut usage = self.usage.lock().unwrap();
+        usage.roll(replay::unix_timestamp());
+        usage.check(&limits)
+    }
+
+    /// 记录转发的字节，并按带宽限制等待；配额已用尽时返回错误，调用者应结束转发
+    /// `local_user` 是客户端转发的本地用户，字节同时计入它的用量
+    pub async fn record(&self, direction: Direction, bytes: usize, local_user: Option<&str>) -> Result<(), QuotaError> {
+        {
+            let limits = *self.limits.lock().unwrap();
+            let mut usage = self.usage.lock().unwrap();
+            usage.roll(replay::unix_timestamp());
+            usage.check(&limits)?;
+            usage.add(direction, bytes as u64, local_user);
+        }
+
+        let bucket = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        };
+        if let Some(wait) = bucket.reserve(bytes) {
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// 累计的 (上传, 下载) 字节数
+    pub fn totals(&self) -> (u64, u64) {
+        let usage = self.usage.lock().unwrap();
+        (usage.upload, usage.download)
+    }
+}
+
+/// 所有用户的计量器，用量定期保存到本地文件，重启后继续累计
+pub struct QuotaManager {
+    meters: Mutex<HashMap<String, Arc<UserMeter>>>,
+    state_file: Option<PathBuf>,
+}
+
+impl QuotaManager {
+    /// 读取用量文件；文件不存在时从零开始
+    pub fn load(state_file: Option<PathBuf>) -> Result<Self> {
+        let mut meters = HashMap::new();
+
+        if let Some(path) = &state_file
+            && path.exists()
+        {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("无法读取用量文件 {}", path.display()))?;
+            let saved: HashMap<String, Usage> = serde_json::from_str(&text)
+                .with_context(|| format!("用量文件 {} 无效", path.display()))?;
+            for (user, usage) in saved {
+                meters.insert(user.clone(), Arc::new(UserMeter::new(user, usage)));
+            }
+        }
+
+        Ok(Self {
+            meters: Mutex::new(meters),
+            state_file,
+        })
+    }
+
+    /// 取得用户的计量器，并应用当前的限制
+    pub fn meter(&self, user: &str, limits: Limits) -> Arc<UserMeter> {
+        let meter = self
+            .meters
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_insert_with(|| Arc::new(UserMeter::new(user.to_string(), Usage::default())))
+            .clone();
+        meter.set_limits(limits);
+        meter
+    }
+
+    /// 写入临时文件后改名，保存过程中崩溃不会留下半个文件
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+
+        let snapshot: HashMap<String, Usage> = self
+            .meters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(user, meter)| (user.clone(), meter.usage.lock().unwrap().clone()))
+            .collect();
+        let text = serde_json::to_string_pretty(&snapshot)?;
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, text).with_context(|| format!("无法写入 {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("无法更新用量文件 {}", path.display()))?;
+        Ok(())
+    }
+
+    /// 定期保存用量，没有配置用量文件时立即返回
+    pub async fn persist_periodically(self: Arc<Self>) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        info!("用量每 {:?} 保存到 {}", PERSIST_INTERVAL, path.display());
+
+        let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.save() {
+                error!("保存用量失败: {:#}", e);
+            }
+        }
+    }
+}
+
+/// 1970-01-01 起的天数对应的 年 * 12 + 月 - 1（UTC）
+fn month_index(days: u64) -> u64 {
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year * 12 + month - 1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_size() {
+        assert_eq!("512".parse::<ByteSize>().unwrap().0, 512);
+        assert_eq!("4KiB".parse::<ByteSize>().unwrap().0, 4096);
+        assert_eq!("10 GB".parse::<ByteSize>().unwrap().0, 10 << 30);
+        assert_eq!("1m".parse::<ByteSize>().unwrap().0, 1 << 20);
+        assert!("10 parsecs".parse::<ByteSize>().is_err());
+        assert_eq!(ByteSize(1536).to_string(), "1.5 KiB");
+    }
+
+    #[test]
+    fn test_usage_rolls_over() {
+        let limits = Limits {
+            daily_quota: Some(100),
+            monthly_quota: Some(150),
+            ..Limits::default()
+        };
+
+        // 2024-01-31
+        let mut usage = Usage::default();
+        usage.roll(19753 * 86400);
+        usage.add(Direction::Upload, 60, None);
+        usage.add(Direction::Download, 40, None);
+        assert_eq!(usage.check(&limits), Err(QuotaError::Daily(100)));
+
+        // 次日是新的一个月，两个配额都重置
+        usage.roll(19754 * 86400);
+        assert_eq!(usage.check(&limits), Ok(()));
+        usage.add(Direction::Download, 99, None);
+        usage.roll(19755 * 86400);
+        usage.add(Direction::Download, 60, None);
+        assert_eq!(usage.check(&limits), Err(QuotaError::Monthly(150)));
+        assert_eq!((usage.upload, usage.download), (60, 199));
+    }
+
+    #[test]
+    fn test_month_index() {
+        assert_eq!(month_index(0), 1970 * 12);
+        // 2000-02-29 和 2000-03-01
+        assert_eq!(month_index(11016), 2000 * 12 + 1);
+        assert_eq!(month_index(11017), 2000 * 12 + 2);
+    }
+
+    #[test]
+    fn test_token_bucket_waits_for_debt() {
+        let bucket = TokenBucket::new();
+        assert!(bucket.reserve(1 << 20).is_none());
+
+        bucket.set_rate(Some(1000));
+        let wait = bucket.reserve(2000).unwrap();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_usage_persists() {
+        let path = std::env::temp_dir().join(format!("leaf-usage-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let manager = QuotaManager::load(Some(path.clone())).unwrap();
+        let limits = Limits {
+            daily_quota: Some(1024),
+            ..Limits::default()
+        };
+        let meter = manager.meter("alice", limits);
+        meter.record(Direction::Upload, 1000, Some("bob")).await.unwrap();
+        meter.record(Direction::Download, 100, None).await.unwrap();
+        assert!(matches!(meter.record(Direction::Upload, 1, None).await, Err(QuotaError::Daily(1024))));
+        manager.save().unwrap();
+
+        let manager = QuotaManager::load(Some(path.clone())).unwrap();
+        let meter = manager.meter("alice", Limits::default());
+        assert_eq!(meter.totals(), (1000, 100));
+        let usage = meter.usage.lock().unwrap().clone();
+        assert_eq!(usage.local_users["bob"], LocalUsage { upload: 1000, download: 0 });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
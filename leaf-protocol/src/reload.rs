@@ -0,0 +1,81 @@
+use anyhow::Result;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// 可以整体替换的共享状态；读取方取得某一时刻完整的一份，替换不影响已经取得的旧值
+pub struct Reloadable<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(value)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// 一次重载的结果，管理接口原样返回
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadSummary {
+    /// 用户数据库中的用户数
+    pub users: usize,
+    /// 因凭据失效被终止的会话数
+    pub evicted: usize,
+    /// 有修改但需要重启才能生效的配置项
+    pub restart_required: Vec<&'static str>,
+}
+
+/// 重新读取配置和凭据；失败时不能修改任何状态
+pub type Reloader = Arc<dyn Fn() -> Result<ReloadSummary> + Send + Sync>;
+
+/// 执行一次重载并记录结果，同一时间只有一次重载在进行
+pub fn run(reloader: &Reloader) -> Result<ReloadSummary> {
+    static RUNNING: Mutex<()> = Mutex::new(());
+    let _running = RUNNING.lock().unwrap();
+
+    match reloader() {
+        Ok(summary) => {
+            info!("配置已重新加载: {} 个用户，终止 {} 个凭据失效的会话", summary.users, summary.evicted);
+            if !summary.restart_required.is_empty() {
+                warn!("以下配置项的修改需要重启才能生效: {}", summary.restart_required.join(", "));
+            }
+            Ok(summary)
+        }
+        Err(e) => {
+            error!("重新加载配置失败，继续使用原来的配置: {:#}", e);
+            Err(e)
+        }
+    }
+}
+
+/// 每次收到 SIGHUP 时重载
+#[cfg(unix)]
+pub async fn reload_on_sighup(reloader: Reloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("无法监听 SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("收到 SIGHUP，重新加载配置");
+        let reloader = reloader.clone();
+        let _ = tokio::task::spawn_blocking(move || run(&reloader)).await;
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_reloader: Reloader) {}
@@ -0,0 +1,104 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::crypto::SALT_LEN;
+
+/// 握手时间戳允许的偏差（秒）
+pub const REPLAY_WINDOW: u64 = 120;
+
+/// 服务器端的会话盐缓存，拒绝重放录制下来的整条连接
+///
+/// 时间戳超出 ±`REPLAY_WINDOW` 的握手会被直接拒绝，所以一个盐只需要
+/// 保留两倍窗口的时间：更晚的重放一定带着过期的时间戳
+pub struct ReplayCache {
+    inner: Mutex<Inner>,
+    ttl: Duration,
+}
+
+#[derive(Default)]
+struct Inner {
+    seen: HashSet<[u8; SALT_LEN]>,
+    order: VecDeque<(Instant, [u8; SALT_LEN])>,
+}
+
+impl ReplayCache {
+    pub fn new() -> Self {
+        Self::with_ttl(Duration::from_secs(2 * REPLAY_WINDOW))
+    }
+
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            ttl,
+        }
+    }
+
+    /// 记录一个盐；已经见过时返回 false
+    pub fn insert(&self, salt: [u8; SALT_LEN]) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        // 过期的盐按插入顺序清理
+        while let Some((inserted_at, old)) = inner.order.front().copied() {
+            if now.duration_since(inserted_at) < self.ttl {
+                break;
+            }
+            inner.order.pop_front();
+            inner.seen.remove(&old);
+        }
+
+        if !inner.seen.insert(salt) {
+            return false;
+        }
+        inner.order.push_back((now, salt));
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 当前 Unix 时间（秒）
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// 握手时间戳是否在允许的时间窗口内
+pub fn timestamp_in_window(timestamp: u64) -> bool {
+    unix_timestamp().abs_diff(timestamp) <= REPLAY_WINDOW
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_cache() {
+        let cache = ReplayCache::with_ttl(Duration::from_millis(20));
+        assert!(cache.insert([1; SALT_LEN]));
+        assert!(!cache.insert([1; SALT_LEN]));
+        assert!(cache.insert([2; SALT_LEN]));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(cache.insert([3; SALT_LEN]));
+        assert_eq!(cache.len(), 1);
+
+        assert!(timestamp_in_window(unix_timestamp()));
+        assert!(!timestamp_in_window(unix_timestamp() - REPLAY_WINDOW - 10));
+    }
+}
@@ -0,0 +1,432 @@
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::config;
+use crate::protocol::TargetAddr;
+
+/// 规则命中后对连接的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Action {
+    /// 经代理服务器转发
+    Proxy,
+    /// 由客户端直接连接目标
+    Direct,
+    /// 拒绝连接
+    Reject,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Proxy => "proxy",
+            Action::Direct => "direct",
+            Action::Reject => "reject",
+        })
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "proxy" => Ok(Action::Proxy),
+            "direct" => Ok(Action::Direct),
+            "reject" => Ok(Action::Reject),
+            _ => Err(anyhow!("未知的路由动作: {} (可选 proxy、direct、reject)", s)),
+        }
+    }
+}
+
+impl From<Action> for String {
+    fn from(action: Action) -> Self {
+        action.to_string()
+    }
+}
+
+impl TryFrom<String> for Action {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+/// 规则的匹配条件
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// 域名完全相同
+    Domain(String),
+    /// 域名本身或其子域名
+    DomainSuffix(String),
+    /// 域名包含关键字
+    DomainKeyword(String),
+    /// 域名匹配正则表达式
+    DomainRegex(Regex),
+    /// IP 地址属于网段；域名目标在客户端不解析，不匹配这类规则
+    IpCidr(IpAddr, u8),
+    /// 目标端口在范围内
+    Port(RangeInclusive<u16>),
+    /// 入站 SOCKS5/HTTP 代理认证的用户名
+    User(String),
+}
+
+/// 一条路由规则，文本格式为 `类型,值,动作`，例如 `domain-suffix,corp.example,direct`
+#[derive(Debug, Clone)]
+pub struct Rule {
+    matcher: Matcher,
+    action: Action,
+}
+
+impl Rule {
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    fn matches(&self, target: &TargetAddr, user: Option<&str>) -> bool {
+        let domain = match target {
+            TargetAddr::Domain(domain, _) => Some(normalize_domain(domain)),
+            _ => None,
+        };
+        match &self.matcher {
+            Matcher::Domain(expected) => domain.is_some_and(|domain| domain == *expected),
+            Matcher::DomainSuffix(suffix) => domain.is_some_and(|domain| {
+                domain == *suffix
+                    || domain.strip_suffix(suffix.as_str()).is_some_and(|prefix| prefix.ends_with('.'))
+            }),
+            Matcher::DomainKeyword(keyword) => domain.is_some_and(|domain| domain.contains(keyword.as_str())),
+            Matcher::DomainRegex(regex) => domain.is_some_and(|domain| regex.is_match(&domain)),
+            Matcher::IpCidr(network, prefix) => match target {
+                TargetAddr::Ipv4(ip, _) => in_network(IpAddr::V4(*ip), *network, *prefix),
+                TargetAddr::Ipv6(ip, _) => in_network(ip.to_canonical(), *network, *prefix),
+                TargetAddr::Domain(..) => false,
+            },
+            Matcher::Port(range) => range.contains(&target_port(target)),
+            Matcher::User(expected) => user == Some(expected.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.matcher {
+            Matcher::Domain(domain) => write!(f, "domain,{}", domain)?,
+            Matcher::DomainSuffix(suffix) => write!(f, "domain-suffix,{}", suffix)?,
+            Matcher::DomainKeyword(keyword) => write!(f, "domain-keyword,{}", keyword)?,
+            Matcher::DomainRegex(regex) => write!(f, "domain-regex,{}", regex)?,
+            Matcher::IpCidr(network, prefix) => write!(f, "ip-cidr,{}/{}", network, prefix)?,
+            Matcher::Port(range) if range.start() == range.end() => write!(f, "port,{}", range.start())?,
+            Matcher::Port(range) => write!(f, "port,{}-{}", range.start(), range.end())?,
+            Matcher::User(user) => write!(f, "user,{}", user)?,
+        }
+        write!(f, ",{}", self.action)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // 正则表达式里可能有逗号，动作取最后一段
+        let (kind, rest) = s.split_once(',').ok_or_else(|| anyhow!("规则格式应为 类型,值,动作: {}", s))?;
+        let (value, action) = rest.rsplit_once(',').ok_or_else(|| anyhow!("规则缺少动作: {}", s))?;
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(anyhow!("规则的值不能为空: {}", s));
+        }
+
+        let matcher = match kind.trim().to_ascii_lowercase().as_str() {
+            "domain" => Matcher::Domain(normalize_domain(value)),
+            "domain-suffix" => Matcher::DomainSuffix(normalize_domain(value.trim_start_matches('.'))),
+            "domain-keyword" => Matcher::DomainKeyword(value.to_ascii_lowercase()),
+            "domain-regex" => Matcher::DomainRegex(Regex::new(value).with_context(|| format!("无效的正则表达式: {}", value))?),
+            "ip-cidr" => {
+                let (network, prefix) = parse_cidr(value)?;
+                Matcher::IpCidr(network, prefix)
+            }
+            "port" => Matcher::Port(parse_port_range(value)?),
+            "user" => Matcher::User(value.to_string()),
+            other => return Err(anyhow!("未知的规则类型: {}", other)),
+        };
+
+        Ok(Rule {
+            matcher,
+            action: action.parse()?,
+        })
+    }
+}
+
+/// 按顺序匹配的路由规则，第一条命中的规则决定动作，都不命中时使用默认动作
+#[derive(Debug, Clone)]
+pub struct Router {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new(Action::Proxy)
+    }
+}
+
+impl Router {
+    pub fn new(default: Action) -> Self {
+        Self {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    /// 追加配置文件或命令行中的规则
+    pub fn add_rules(&mut self, rules: &[String]) -> Result<()> {
+        for rule in rules {
+            self.rules.push(rule.parse().with_context(|| format!("无效的路由规则: {}", rule))?);
+        }
+        Ok(())
+    }
+
+    /// 追加规则列表文件中的规则：每行一条，`#` 开头的行和空行忽略
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("无法读取规则文件 {}", path.display()))?;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = line
+                .parse()
+                .with_context(|| format!("规则文件 {} 第 {} 行无效", path.display(), index + 1))?;
+            self.rules.push(rule);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn default_action(&self) -> Action {
+        self.default
+    }
+
+    /// 为目标选择动作，同时返回命中的规则；`user` 为入站代理认证的用户名
+    pub fn route(&self, target: &TargetAddr, user: Option<&str>) -> (Action, Option<&Rule>) {
+        match self.rules.iter().find(|rule| rule.matches(target, user)) {
+            Some(rule) => (rule.action, Some(rule)),
+            None => (self.default, None),
+        }
+    }
+}
+
+/// 路由配置，客户端配置文件中的 `[routing]` 表
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// 没有规则命中时的动作
+    pub default: Action,
+    /// 规则，先于规则文件匹配
+    pub rules: Vec<String>,
+    /// 规则列表文件，按顺序匹配
+    pub rule_files: Vec<std::path::PathBuf>,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            default: Action::Proxy,
+            rules: Vec::new(),
+            rule_files: Vec::new(),
+        }
+    }
+}
+
+impl RoutingConfig {
+    /// 规则文件的相对路径按配置文件所在的目录解析
+    pub fn resolve_paths(&mut self, config_path: &Path) {
+        for file in &mut self.rule_files {
+            *file = config::resolve_path(config_path, file);
+        }
+    }
+
+    /// 解析规则并读取规则文件
+    pub fn build(&self) -> Result<Router> {
+        let mut router = Router::new(self.default);
+        router.add_rules(&self.rules)?;
+        for file in &self.rule_files {
+            router.load_file(file)?;
+        }
+        Ok(router)
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn target_port(target: &TargetAddr) -> u16 {
+    match target {
+        TargetAddr::Ipv4(_, port) | TargetAddr::Ipv6(_, port) | TargetAddr::Domain(_, port) => *port,
+    }
+}
+
+/// 解析 `10.0.0.0/8` 形式的网段；不带前缀长度时表示单个地址
+fn parse_cidr(value: &str) -> Result<(IpAddr, u8)> {
+    let (ip, prefix) = match value.split_once('/') {
+        Some((ip, prefix)) => (ip, Some(prefix)),
+        None => (value, None),
+    };
+    let ip: IpAddr = ip.parse().map_err(|_| anyhow!("无效的 IP 地址: {}", ip))?;
+    let max: u8 = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max).ok_or_else(|| anyhow!("无效的前缀长度: {}", prefix))?,
+        None => max,
+    };
+    let ip = match ip {
+        IpAddr::V6(ip) => ip.to_canonical(),
+        ip => ip,
+    };
+    // 映射到 IPv4 的 IPv6 网段按对应的 IPv4 网段处理
+    let prefix = match ip {
+        IpAddr::V4(_) if max == 128 => prefix.saturating_sub(96),
+        _ => prefix,
+    };
+    Ok((ip, prefix))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// 解析 `443` 或 `8000-9000` 形式的端口范围
+fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>> {
+    let parse = |port: &str| port.trim().parse::<u16>().map_err(|_| anyhow!("无效的端口: {}", port));
+    let range = match value.split_once('-') {
+        Some((start, end)) => parse(start)?..=parse(end)?,
+        None => parse(value)?..=parse(value)?,
+    };
+    if range.is_empty() {
+        return Err(anyhow!("无效的端口范围: {}", value));
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(rules: &[&str]) -> Router {
+        let mut router = Router::new(Action::Proxy);
+        router.add_rules(&rules.iter().map(|rule| rule.to_string()).collect::<Vec<_>>()).unwrap();
+        router
+    }
+
+    fn action(router: &Router, target: &str, user: Option<&str>) -> Action {
+        router.route(&target.parse().unwrap(), user).0
+    }
+
+    #[test]
+    fn test_domain_rules() {
+        let router = router(&[
+            "domain,exact.example,reject",
+            "domain-suffix,corp.example,direct",
+            "domain-keyword,tracker,reject",
+            r"domain-regex,^cdn\d+\.,direct",
+        ]);
+
+        assert_eq!(action(&router, "exact.example:443", None), Action::Reject);
+        assert_eq!(action(&router, "www.exact.example:443", None), Action::Proxy);
+        assert_eq!(action(&router, "CORP.example.:80", None), Action::Direct);
+        assert_eq!(action(&router, "git.corp.example:22", None), Action::Direct);
+        // 后缀按标签边界匹配
+        assert_eq!(action(&router, "notcorp.example:80", None), Action::Proxy);
+        assert_eq!(action(&router, "ads.tracker.net:443", None), Action::Reject);
+        assert_eq!(action(&router, "cdn12.example.org:443", None), Action::Direct);
+        assert_eq!(action(&router, "www.example.org:443", None), Action::Proxy);
+    }
+
+    #[test]
+    fn test_ip_port_and_user_rules() {
+        let router = router(&[
+            "ip-cidr,10.0.0.0/8,direct",
+            "ip-cidr,fd00::/8,direct",
+            "ip-cidr,192.168.1.1,reject",
+            "port,25,reject",
+            "port,8000-8999,direct",
+            "user,guest,reject",
+        ]);
+
+        assert_eq!(action(&router, "10.1.2.3:443", None), Action::Direct);
+        assert_eq!(action(&router, "[::ffff:10.1.2.3]:443", None), Action::Direct);
+        assert_eq!(action(&router, "[fd12::1]:443", None), Action::Direct);
+        assert_eq!(action(&router, "192.168.1.1:80", None), Action::Reject);
+        assert_eq!(action(&router, "192.168.1.2:80", None), Action::Proxy);
+        // 域名在客户端不解析，不匹配 IP 规则
+        assert_eq!(action(&router, "intranet:443", None), Action::Proxy);
+        assert_eq!(action(&router, "mail.example:25", None), Action::Reject);
+        assert_eq!(action(&router, "1.1.1.1:8080", None), Action::Direct);
+        assert_eq!(action(&router, "1.1.1.1:443", Some("guest")), Action::Reject);
+        assert_eq!(action(&router, "1.1.1.1:443", Some("alice")), Action::Proxy);
+
+        let (_, rule) = router.route(&"10.0.0.1:22".parse().unwrap(), None);
+        assert_eq!(rule.unwrap().to_string(), "ip-cidr,10.0.0.0/8,direct");
+        assert_eq!(Router::new(Action::Direct).route(&"1.1.1.1:443".parse().unwrap(), None).0, Action::Direct);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for rule in [
+            "domain,example.com",
+            "domain,example.com,block",
+            "host,example.com,direct",
+            "ip-cidr,10.0.0.0/33,direct",
+            "ip-cidr,example.com,direct",
+            "port,9000-8000,direct",
+            "domain-regex,(,direct",
+            "domain,,direct",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn test_rule_file() {
+        let path = std::env::temp_dir().join(format!("leaf-rules-{}.list", std::process::id()));
+        std::fs::write(&path, "# 内网\n\ndomain-suffix,lan,direct\nip-cidr,172.16.0.0/12,direct\n").unwrap();
+
+        let mut router = Router::new(Action::Reject);
+        router.add_rules(&["domain,nas.lan,proxy".to_string()]).unwrap();
+        router.load_file(&path).unwrap();
+        assert_eq!(router.len(), 3);
+        // 命令行和配置文件中的规则先于规则文件
+        assert_eq!(action(&router, "nas.lan:445", None), Action::Proxy);
+        assert_eq!(action(&router, "printer.lan:631", None), Action::Direct);
+        assert_eq!(action(&router, "172.20.0.1:80", None), Action::Direct);
+        assert_eq!(action(&router, "example.com:80", None), Action::Reject);
+
+        std::fs::write(&path, "domain-suffix,lan,direct\nport,http,direct\n").unwrap();
+        let error = Router::default().load_file(&path).unwrap_err();
+        assert!(format!("{:#}", error).contains("第 2 行"), "{:#}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::metrics::{Metrics, SessionGuard};
+use crate::quota::{Direction, QuotaError, UserMeter};
+use crate::replay;
+use crate::users::{Credentials, User};
+
+/// 一个会话或流两个方向的字节数
+#[derive(Default)]
+struct Traffic {
+    upload: AtomicU64,
+    download: AtomicU64,
+}
+
+impl Traffic {
+    fn add(&self, direction: Direction, bytes: u64) {
+        let counter = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> (u64, u64) {
+        (self.upload.load(Ordering::Relaxed), self.download.load(Ordering::Relaxed))
+    }
+}
+
+/// 服务器上一个已认证的会话
+pub struct Session {
+    id: String,
+    client_id: String,
+    peer: SocketAddr,
+    connected_at: Instant,
+    /// Unix 时间，供管理接口显示
+    started_at: u64,
+    /// 认证时使用的账户，重载凭据后据此判断会话是否应被终止
+    account: User,
+    meter: Arc<UserMeter>,
+    metrics: Arc<Metrics>,
+    traffic: Traffic,
+    streams: Mutex<HashMap<u32, Arc<StreamEntry>>>,
+    cancel: CancellationToken,
+    _active: SessionGuard,
+}
+
+struct StreamEntry {
+    target: String,
+    /// 客户端转发的本地用户
+    local_user: Option<String>,
+    opened_at: Instant,
+    traffic: Traffic,
+}
+
+impl Session {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn user(&self) -> &str {
+        self.meter.user()
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    /// 会话累计的 (上传, 下载) 字节数
+    pub fn traffic(&self) -> (u64, u64) {
+        self.traffic.get()
+    }
+
+    /// 用户的计量器，配额检查用
+    pub fn meter(&self) -> &UserMeter {
+        &self.meter
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// 登记一个已连接目标的流；返回的句柄释放时流从会话中移除
+    pub fn open_stream(self: &Arc<Self>, stream_id: u32, target: impl Into<String>, local_user: Option<String>) -> StreamTraffic {
+        let entry = Arc::new(StreamEntry {
+            target: target.into(),
+            local_user,
+            opened_at: Instant::now(),
+            traffic: Traffic::default(),
+        });
+        self.streams.lock().unwrap().insert(stream_id, entry.clone());
+
+        StreamTraffic {
+            session: self.clone(),
+            stream_id,
+            entry,
+        }
+    }
+
+    /// 要求会话结束；会话的读取循环收到通知后关闭连接和所有流
+    pub fn terminate(&self) {
+        self.cancel.cancel();
+    }
+
+    /// 等待会话被终止
+    pub async fn terminated(&self) {
+        self.cancel.cancelled().await;
+    }
+
+    fn snapshot(&self) -> SessionSnapshot {
+        let (upload, download) = self.traffic.get();
+        let mut streams: Vec<StreamSnapshot> = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(stream_id, entry)| {
+                let (upload, download) = entry.traffic.get();
+                StreamSnapshot {
+                    stream_id: *stream_id,
+                    target: entry.target.clone(),
+                    local_user: entry.local_user.clone(),
+                    duration_secs: entry.opened_at.elapsed().as_secs(),
+                    upload,
+                    download,
+                }
+            })
+            .collect();
+        streams.sort_by_key(|stream| stream.stream_id);
+
+        SessionSnapshot {
+            session_id: self.id.clone(),
+            user: self.user().to_string(),
+            client_id: self.client_id.clone(),
+            peer: self.peer.to_string(),
+            connected_at: self.started_at,
+            duration_secs: self.connected_at.elapsed().as_secs(),
+            upload,
+            download,
+            streams,
+        }
+    }
+}
+
+/// 一个流的流量记录：同时计入流、会话和用户的用量
+pub struct StreamTraffic {
+    session: Arc<Session>,
+    stream_id: u32,
+    entry: Arc<StreamEntry>,
+}
+
+impl StreamTraffic {
+    pub fn user(&self) -> &str {
+        self.session.user()
+    }
+
+    /// 记录转发的字节，并按用户的带宽限制等待；配额已用尽时返回错误
+    pub async fn record(&self, direction: Direction, bytes: usize) -> Result<(), QuotaError> {
+        self.session
+            .meter
+            .record(direction, bytes, self.entry.local_user.as_deref())
+            .await?;
+        self.session.metrics.relayed(Some(self.user()), direction, bytes);
+        self.entry.traffic.add(direction, bytes as u64);
+        self.session.traffic.add(direction, bytes as u64);
+        Ok(())
+    }
+}
+
+impl Drop for StreamTraffic {
+    fn drop(&mut self) {
+        self.session.streams.lock().unwrap().remove(&self.stream_id);
+    }
+}
+
+/// 管理接口返回的会话信息
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSnapshot {
+    pub session_id: String,
+    pub user: String,
+    pub client_id: String,
+    pub peer: String,
+    /// 连接建立时的 Unix 时间
+    pub connected_at: u64,
+    pub duration_secs: u64,
+    pub upload: u64,
+    pub download: u64,
+    pub streams: Vec<StreamSnapshot>,
+}
+
+/// 会话中一个已连接目标的流
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamSnapshot {
+    pub stream_id: u32,
+    pub target: String,
+    /// 客户端转发的本地用户 (SOCKS5 或 HTTP 代理认证的用户名)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_user: Option<String>,
+    pub duration_secs: u64,
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// 服务器的活跃会话表
+pub struct SessionTable {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl SessionTable {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// 登记认证成功的会话
+    pub fn register(
+        &self,
+        session_id: String,
+        client_id: String,
+        peer: SocketAddr,
+        account: User,
+        meter: Arc<UserMeter>,
+    ) -> Arc<Session> {
+        let session = Arc::new(Session {
+            id: session_id.clone(),
+            client_id,
+            peer,
+            connected_at: Instant::now(),
+            started_at: replay::unix_timestamp(),
+            account,
+            meter,
+            metrics: self.metrics.clone(),
+            traffic: Traffic::default(),
+            streams: Mutex::new(HashMap::new()),
+            cancel: CancellationToken::new(),
+            _active: self.metrics.track_session(),
+        });
+        self.sessions.lock().unwrap().insert(session_id, session.clone());
+        session
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn remove(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().remove(session_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 当前的会话，按连接时间排序；`user` 不为空时只返回该用户的会话
+    pub fn snapshot(&self, user: Option<&str>) -> Vec<SessionSnapshot> {
+        let mut sessions: Vec<SessionSnapshot> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| user.is_none_or(|user| session.user() == user))
+            .map(|session| session.snapshot())
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.duration_secs));
+        sessions
+    }
+
+    /// 终止一个会话，会话不存在时返回 false
+    pub fn terminate(&self, session_id: &str) -> bool {
+        match self.sessions.lock().unwrap().get(session_id) {
+            Some(session) => {
+                session.terminate();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 终止用户的所有会话，返回终止的数量
+    pub fn terminate_user(&self, user: &str) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let mut terminated = 0;
+        for session in sessions.values().filter(|session| session.user() == user) {
+            session.terminate();
+            terminated += 1;
+        }
+        terminated
+    }
+
+    /// 终止凭据已失效的会话，返回终止的数量
+    pub fn terminate_revoked(&self, credentials: &Credentials) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let mut terminated = 0;
+        for session in sessions.values().filter(|session| credentials.is_revoked(&session.account)) {
+            info!("用户 {} 的凭据已失效，终止会话 {}", session.user(), session.id());
+            session.terminate();
+            terminated += 1;
+        }
+        terminated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::{Limits, QuotaManager};
+    use crate::users::UserDb;
+
+    const USERS: &str = r#"
+        [[users]]
+        name = "alice"
+        token = "alice-token"
+
+        [[users]]
+        name = "bob"
+        token = "bob-token"
+    "#;
+
+    #[tokio::test]
+    async fn test_session_table() {
+        let quota = QuotaManager::load(None).unwrap();
+        let table = SessionTable::new(Arc::new(Metrics::new("test")));
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let users = UserDb::parse(USERS).unwrap();
+        let register = |id: &str, name: &str| {
+            let account = users.get(name).unwrap().clone();
+            table.register(id.into(), format!("client-{}", id), peer, account, quota.meter(name, Limits::default()))
+        };
+
+        let alice = register("a", "alice");
+        let bob = register("b", "bob");
+
+        let stream = alice.open_stream(1, "example.com:443", Some("carol".into()));
+        stream.record(Direction::Upload, 100).await.unwrap();
+        stream.record(Direction::Download, 2000).await.unwrap();
+
+        let snapshot = table.snapshot(Some("alice"));
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!((snapshot[0].upload, snapshot[0].download), (100, 2000));
+        assert_eq!(snapshot[0].streams[0].target, "example.com:443");
+        assert_eq!(snapshot[0].streams[0].local_user.as_deref(), Some("carol"));
+
+        // 流结束后计数保留在会话上
+        drop(stream);
+        let snapshot = table.snapshot(Some("alice"));
+        assert!(snapshot[0].streams.is_empty());
+        assert_eq!(snapshot[0].download, 2000);
+
+        assert_eq!(table.terminate_user("alice"), 1);
+        alice.terminated().await;
+        assert!(!bob.cancel.is_cancelled());
+        assert!(table.terminate("b"));
+        assert!(!table.terminate("missing"));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_terminate_revoked() {
+        let quota = QuotaManager::load(None).unwrap();
+        let table = SessionTable::new(Arc::new(Metrics::new("test")));
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let users = UserDb::parse(USERS).unwrap();
+        let alice = users.get("alice").unwrap();
+        let bob = users.get("bob").unwrap();
+        let alice = table.register("a".into(), "c".into(), peer, alice.clone(), quota.meter("alice", Limits::default()));
+        let bob = table.register("b".into(), "c".into(), peer, bob.clone(), quota.meter("bob", Limits::default()));
+
+        // 重载后的用户数据库里 bob 被删除
+        let reloaded = UserDb::parse("[[users]]\nname = \"alice\"\ntoken = \"alice-token\"").unwrap();
+        let credentials = Credentials::new(None, Some(reloaded)).unwrap();
+        assert_eq!(table.terminate_revoked(&credentials), 1);
+        bob.terminated().await;
+        assert!(!alice.cancel.is_cancelled());
+    }
+}
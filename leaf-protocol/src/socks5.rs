@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::auth::{self, SocksAuth, USERNAME_PASSWORD};
+use crate::protocol::{decode_datagram, ProxyCommand, TargetAddr};
+
+pub const SOCKS_VERSION: u8 = 0x05;
+pub const NO_AUTHENTICATION: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+const CONNECT_COMMAND: u8 = 0x01;
+const BIND_COMMAND: u8 = 0x02;
+const UDP_ASSOCIATE_COMMAND: u8 = 0x03;
+
+const IPV4_ADDRESS: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6_ADDRESS: u8 = 0x04;
+
+/// SOCKS5 UDP 请求头中 RSV (2) + FRAG (1) 的长度
+pub const UDP_HEADER_PREFIX: usize = 3;
+
+/// SOCKS5 响应状态码 (RFC 1928 第 6 节)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    ConnectionNotAllowed = 0x02,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+/// 一个已解析的 SOCKS5 请求
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksRequest {
+    pub command: ProxyCommand,
+    /// CONNECT/BIND 的目标地址；UDP ASSOCIATE 中是客户端将要使用的发送地址
+    pub target_addr: TargetAddr,
+}
+
+/// 协商认证方法；配置了用户凭据时要求用户名/密码认证并返回用户名
+pub async fn handshake<S>(client: &mut S, auth: &SocksAuth) -> Result<Option<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; 2];
+    client.read_exact(&mut buf).await?;
+
+    let version = buf[0];
+    let nmethods = buf[1];
+
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("不支持的SOCKS版本: {}", version));
+    }
+
+    let mut methods = vec![0u8; nmethods as usize];
+    client.read_exact(&mut methods).await?;
+
+    let method = if auth.is_enabled() { USERNAME_PASSWORD } else { NO_AUTHENTICATION };
+    if !methods.contains(&method) {
+        client.write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(match method {
+            USERNAME_PASSWORD => anyhow!("客户端不支持用户名/密码认证方法"),
+            _ => anyhow!("客户端不支持无认证方法"),
+        });
+    }
+
+    client.write_all(&[SOCKS_VERSION, method]).await?;
+
+    let user = match method {
+        USERNAME_PASSWORD => Some(auth::authenticate(client, auth).await?),
+        _ => None,
+    };
+
+    debug!("SOCKS5 握手成功");
+    Ok(user)
+}
+
+/// 读取握手之后的请求: [VER][CMD][RSV][ATYP][DST.ADDR][DST.PORT]
+/// 命令或地址类型不受支持时先答复对应的错误码
+pub async fn read_request<S>(client: &mut S) -> Result<SocksRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await?;
+
+    let version = buf[0];
+    let command = buf[1];
+    let _reserved = buf[2];
+    let address_type = buf[3];
+
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("不支持的SOCKS版本: {}", version));
+    }
+
+    let command = match command {
+        CONNECT_COMMAND => ProxyCommand::Connect,
+        BIND_COMMAND => ProxyCommand::Bind,
+        UDP_ASSOCIATE_COMMAND => ProxyCommand::UdpAssociate,
+        _ => {
+            send_failure(client, Reply::CommandNotSupported).await?;
+            return Err(anyhow!("不支持的命令: {}", command));
+        }
+    };
+
+    let target_addr = match address_type {
+        IPV4_ADDRESS => {
+            let mut addr_buf = [0u8; 4];
+            client.read_exact(&mut addr_buf).await?;
+            let port = client.read_u16().await?;
+
+            TargetAddr::Ipv4(addr_buf.into(), port)
+        }
+        DOMAIN_NAME => {
+            let domain_len = client.read_u8().await? as usize;
+            let mut domain_buf = vec![0u8; domain_len];
+            client.read_exact(&mut domain_buf).await?;
+            let domain = String::from_utf8(domain_buf)?;
+            let port = client.read_u16().await?;
+
+            // 域名原样保留，由出口一侧解析
+            TargetAddr::domain(domain, port)?
+        }
+        IPV6_ADDRESS => {
+            let mut addr_buf = [0u8; 16];
+            client.read_exact(&mut addr_buf).await?;
+            let port = client.read_u16().await?;
+
+            TargetAddr::Ipv6(addr_buf.into(), port)
+        }
+        _ => {
+            send_failure(client, Reply::AddressTypeNotSupported).await?;
+            return Err(anyhow!("不支持的地址类型: {}", address_type));
+        }
+    };
+
+    Ok(SocksRequest {
+        command,
+        target_addr,
+    })
+}
+
+/// 发送 SOCKS5 响应: [VER][REP][RSV][BND.ADDR][BND.PORT]
+pub async fn send_reply<S>(client: &mut S, reply: Reply, bound_addr: &TargetAddr) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut response = vec![SOCKS_VERSION, reply as u8, 0x00];
+    bound_addr.encode_socks(&mut response);
+
+    client.write_all(&response).await?;
+    Ok(())
+}
+
+/// 发送不带绑定地址 (0.0.0.0:0) 的响应
+pub async fn send_failure<S>(client: &mut S, reply: Reply) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    send_reply(client, reply, &unspecified_addr()).await
+}
+
+/// 没有有意义的绑定地址时使用的 0.0.0.0:0
+pub fn unspecified_addr() -> TargetAddr {
+    TargetAddr::Ipv4([0, 0, 0, 0].into(), 0)
+}
+
+/// 解析 SOCKS5 UDP 请求头，返回 `Datagram` 帧载荷（地址加数据）
+/// 格式: [RSV u16][FRAG u8][ATYP][DST.ADDR][DST.PORT][DATA]
+pub fn parse_udp_request(packet: &[u8]) -> Result<&[u8]> {
+    if packet.len() < UDP_HEADER_PREFIX {
+        return Err(anyhow!("UDP 请求头长度不足"));
+    }
+
+    // 不实现分片重组，按 RFC 1928 丢弃所有分片
+    let frag = packet[2];
+    if frag != 0 {
+        return Err(anyhow!("不支持分片的 UDP 数据报 (FRAG={})", frag));
+    }
+
+    let payload = &packet[UDP_HEADER_PREFIX..];
+    decode_datagram(payload)?;
+    Ok(payload)
+}
+
+/// 为 `Datagram` 帧载荷加上 SOCKS5 UDP 响应头
+pub fn encode_udp_response(payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(UDP_HEADER_PREFIX + payload.len());
+    packet.extend_from_slice(&[0x00, 0x00, 0x00]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_and_request() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        client.write_all(b"\x05\x01\x00").await.unwrap();
+        client.write_all(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb").await.unwrap();
+
+        let user = handshake(&mut server, &SocksAuth::default()).await.unwrap();
+        assert_eq!(user, None);
+        let request = read_request(&mut server).await.unwrap();
+        assert_eq!(request.command, ProxyCommand::Connect);
+        assert_eq!(request.target_addr, TargetAddr::Domain("example.com".to_string(), 443));
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [SOCKS_VERSION, NO_AUTHENTICATION]);
+
+        client.write_all(b"\x05\x09\x00\x01\x7f\x00\x00\x01\x00\x50").await.unwrap();
+        assert!(read_request(&mut server).await.is_err());
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], Reply::CommandNotSupported as u8);
+    }
+
+    #[test]
+    fn test_parse_udp_request() {
+        let packet = [0x00, 0x00, 0x00, 0x01, 8, 8, 8, 8, 0x00, 0x35, b'q'];
+        assert_eq!(parse_udp_request(&packet).unwrap(), &packet[3..]);
+        assert_eq!(encode_udp_response(&packet[3..]), packet);
+
+        let fragment = [0x00, 0x00, 0x01, 0x01, 8, 8, 8, 8, 0x00, 0x35, b'q'];
+        assert!(parse_udp_request(&fragment).is_err());
+        assert!(parse_udp_request(&[0x00, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// 每个来源每秒恢复的试解密额度
+pub const TRIAL_RATE: f64 = 256.0;
+
+/// 每个来源最多积攒的试解密额度
+pub const TRIAL_BURST: f64 = 1024.0;
+
+/// 来源表超过这个大小时清理额度已经恢复满的来源
+const MAX_IDLE_SOURCES: usize = 4096;
+
+/// 来源表的硬上限。清理之后仍然满时淘汰额度恢复得最多的来源，
+/// 不断更换来源地址的攻击者无法让来源表无限增长
+const MAX_SOURCES: usize = 65536;
+
+/// 服务器按来源地址限制认证之前的试解密
+///
+/// 服务器要用默认密钥和每个用户的密钥依次尝试客户端的第一帧 (或 Noise 握手消息)，
+/// 一次失败的握手要做 O(用户数) 次解密。失败的握手按尝试的密钥数扣除来源的额度，
+/// 额度可以透支，透支的来源在额度恢复之前连接直接被关闭，不再读取任何数据。
+/// 成功的握手不扣额度。IPv6 地址按 /64 网段计算，同一网段的地址共享额度
+pub struct TrialLimiter {
+    sources: Mutex<HashMap<IpAddr, Budget>>,
+    rate: f64,
+    burst: f64,
+    max_sources: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Budget {
+    /// 可以为负，表示透支
+    available: f64,
+    updated_at: Instant,
+}
+
+impl TrialLimiter {
+    pub fn new() -> Self {
+        Self::with_rate(TRIAL_RATE, TRIAL_BURST)
+    }
+
+    pub fn with_rate(rate: f64, burst: f64) -> Self {
+        Self {
+            sources: Mutex::new(HashMap::new()),
+            rate,
+            burst,
+            max_sources: MAX_SOURCES,
+        }
+    }
+
+    /// 来源是否还有额度；没有时调用者应在读取任何数据之前关闭连接
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        match sources.get_mut(&source_key(ip)) {
+            Some(budget) => self.refill(budget, now) > 0.0,
+            None => true,
+        }
+    }
+
+    /// 记录一次失败的握手尝试了 `trials` 个密钥
+    pub fn charge(&self, ip: IpAddr, trials: usize) {
+        let now = Instant::now();
+        let key = source_key(ip);
+        let mut sources = self.sources.lock().unwrap();
+        if !sources.contains_key(&key) {
+            if sources.len() >= MAX_IDLE_SOURCES {
+                sources.retain(|_, budget| self.refill(budget, now) < self.burst);
+            }
+            if sources.len() >= self.max_sources {
+                self.evict(&mut sources, now);
+            }
+        }
+
+        let budget = sources.entry(key).or_insert(Budget {
+            available: self.burst,
+            updated_at: now,
+        });
+        self.refill(budget, now);
+        budget.available -= trials as f64;
+    }
+
+    /// 淘汰额度恢复得最多的来源，它最接近于被清理
+    fn evict(&self, sources: &mut HashMap<IpAddr, Budget>, now: Instant) {
+        let recovered = sources
+            .iter_mut()
+            .map(|(key, budget)| (*key, self.refill(budget, now)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((key, _)) = recovered {
+            sources.remove(&key);
+        }
+    }
+
+    fn refill(&self, budget: &mut Budget, now: Instant) -> f64 {
+        let elapsed = now.duration_since(budget.updated_at).as_secs_f64();
+        budget.available = (budget.available + elapsed * self.rate).min(self.burst);
+        budget.updated_at = now;
+        budget.available
+    }
+}
+
+impl Default for TrialLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// IPv6 客户端通常拥有整个 /64 网段，按网段计算才能限制住它
+fn source_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let segments = v6.segments();
+                IpAddr::V6([segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0].into())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_failed_trials_exhaust_source() {
+        let limiter = TrialLimiter::with_rate(100.0, 10.0);
+        let attacker: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        // 额度可以透支一次，之后连接被拒绝，其他来源不受影响
+        assert!(limiter.allow(attacker));
+        limiter.charge(attacker, 4);
+        assert!(limiter.allow(attacker));
+        limiter.charge(attacker, 1000);
+        assert!(!limiter.allow(attacker));
+        assert!(limiter.allow(other));
+
+        // 同一 /64 网段的 IPv6 地址共享额度
+        limiter.charge("2001:db8::1".parse().unwrap(), 1000);
+        assert!(!limiter.allow("2001:db8::ffff".parse().unwrap()));
+        assert!(limiter.allow("2001:db8:0:1::1".parse().unwrap()));
+
+        // 额度按时间恢复，透支越多等待越久
+        let mut sources = limiter.sources.lock().unwrap();
+        let budget = sources.get_mut(&attacker).unwrap();
+        budget.updated_at -= Duration::from_secs(9);
+        drop(sources);
+        assert!(!limiter.allow(attacker));
+        let mut sources = limiter.sources.lock().unwrap();
+        sources.get_mut(&attacker).unwrap().updated_at -= Duration::from_secs(2);
+        drop(sources);
+        assert!(limiter.allow(attacker));
+    }
+
+    #[test]
+    fn test_source_table_is_bounded() {
+        let mut limiter = TrialLimiter::with_rate(0.001, 10.0);
+        limiter.max_sources = 4;
+
+        // 所有来源都还在透支，表满时淘汰透支最少的来源
+        for i in 0..4u8 {
+            limiter.charge(IpAddr::from([192, 0, 2, i]), 100 + i as usize);
+        }
+        limiter.charge("198.51.100.1".parse().unwrap(), 1000);
+        assert_eq!(limiter.sources.lock().unwrap().len(), 4);
+        assert!(limiter.allow("192.0.2.0".parse().unwrap()));
+        assert!(!limiter.allow("192.0.2.3".parse().unwrap()));
+        assert!(!limiter.allow("198.51.100.1".parse().unwrap()));
+
+        // 轮换来源地址不会让来源表增长
+        for i in 0..=255u8 {
+            limiter.charge(IpAddr::from([203, 0, 113, i]), 1000);
+        }
+        assert_eq!(limiter.sources.lock().unwrap().len(), 4);
+
+        // 已经记录的来源再次失败不会淘汰其他来源
+        limiter.charge("203.0.113.255".parse().unwrap(), 1000);
+        assert_eq!(limiter.sources.lock().unwrap().len(), 4);
+        assert!(!limiter.allow("203.0.113.255".parse().unwrap()));
+    }
+}
@@ -0,0 +1,413 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::challenge::{AuthChallenge, AuthProof};
+use crate::crypto::CryptoManager;
+use crate::quota::{ByteSize, Limits};
+use crate::replay;
+
+/// 只配置了 `--token` 时，不带用户名的客户端以这个名字出现在日志中；
+/// 用户数据库不能使用这个名字，否则两者的会话、用量和配额会混在一起
+pub const DEFAULT_USER: &str = "default";
+
+/// 用户数据库文件中的一项
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    name: String,
+    token: String,
+    /// 该用户专用的预共享密钥 (base64)，不设置时使用服务器的 `--key`
+    key: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    /// 过期时间，TOML 日期或日期时间，不带时区时按 UTC 处理
+    expires: Option<toml::value::Datetime>,
+    /// 每个 UTC 自然日的流量配额（上传加下载）
+    daily_quota: Option<ByteSize>,
+    /// 每个 UTC 自然月的流量配额
+    monthly_quota: Option<ByteSize>,
+    /// 每秒上传字节数
+    upload_rate: Option<ByteSize>,
+    /// 每秒下载字节数
+    download_rate: Option<ByteSize>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserFile {
+    #[serde(default)]
+    users: Vec<UserEntry>,
+}
+
+/// 通过认证的用户
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    token: String,
+    key: Option<CryptoManager>,
+    enabled: bool,
+    expires_at: Option<u64>,
+    limits: Limits,
+}
+
+impl User {
+    /// 用户专用的预共享密钥
+    pub fn key(&self) -> Option<&CryptoManager> {
+        self.key.as_ref()
+    }
+
+    /// 用户的流量配额和带宽限制
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// 账户已停用或已过期时返回原因
+    fn check_active(&self) -> Result<(), AuthError> {
+        if !self.enabled {
+            return Err(AuthError::Disabled);
+        }
+        if self.expires_at.is_some_and(|expires_at| replay::unix_timestamp() >= expires_at) {
+            return Err(AuthError::Expired);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("name", &self.name)
+            .field("enabled", &self.enabled)
+            .field("expires_at", &self.expires_at)
+            .field("limits", &self.limits)
+            .finish_non_exhaustive()
+    }
+}
+
+/// 服务器端的用户数据库（TOML）
+///
+/// ```toml
+/// [[users]]
+/// name = "alice"
+/// token = "alice-secret"
+/// key = "base64 编码的 32 字节密钥"   # 可选
+/// enabled = true                      # 可选，默认 true
+/// expires = 2026-12-31                # 可选
+/// daily_quota = "10GiB"               # 可选，另有 monthly_quota
+/// upload_rate = "1MiB"                # 可选，每秒，另有 download_rate
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct UserDb {
+    users: HashMap<String, User>,
+}
+
+impl UserDb {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("无法读取用户数据库 {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("用户数据库 {} 无效", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: UserFile = toml::from_str(text)?;
+
+        let mut users = HashMap::new();
+        for entry in file.users {
+            if entry.name.is_empty() || entry.token.is_empty() {
+                return Err(anyhow!("用户名和 token 不能为空"));
+            }
+            if entry.name == DEFAULT_USER {
+                return Err(anyhow!("用户名 {} 保留给使用 --token 认证的客户端", DEFAULT_USER));
+            }
+            let key = entry
+                .key
+                .as_deref()
+                .map(CryptoManager::new)
+                .transpose()
+                .with_context(|| format!("用户 {} 的密钥无效", entry.name))?;
+            let expires_at = entry
+                .expires
+                .as_ref()
+                .map(datetime_to_unix)
+                .transpose()
+                .with_context(|| format!("用户 {} 的过期时间无效", entry.name))?;
+
+            let user = User {
+                name: entry.name.clone(),
+                token: entry.token,
+                key,
+                enabled: entry.enabled,
+                expires_at,
+                limits: Limits {
+                    daily_quota: entry.daily_quota.map(|size| size.0),
+                    monthly_quota: entry.monthly_quota.map(|size| size.0),
+                    upload_rate: entry.upload_rate.map(|size| size.0),
+                    download_rate: entry.download_rate.map(|size| size.0),
+                },
+            };
+            if users.insert(entry.name.clone(), user).is_some() {
+                return Err(anyhow!("重复的用户名: {}", entry.name));
+            }
+        }
+
+        Ok(Self { users })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+/// 握手被拒绝的原因；`Display` 是发给客户端的说明
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    InvalidCredentials,
+    Disabled,
+    Expired,
+    KeyMismatch,
+    StaleTimestamp,
+    OutdatedClient,
+    /// 质询-响应过程中的协议错误
+    Protocol(&'static str),
+}
+
+impl AuthError {
+    /// 固定的英文标识，用作指标标签
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::Disabled => "disabled",
+            AuthError::Expired => "expired",
+            AuthError::KeyMismatch => "key_mismatch",
+            AuthError::StaleTimestamp => "stale_timestamp",
+            AuthError::OutdatedClient => "outdated_client",
+            AuthError::Protocol(_) => "protocol",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            AuthError::InvalidCredentials => "认证失败：无效的用户或 token",
+            AuthError::Disabled => "认证失败：账户已停用",
+            AuthError::Expired => "认证失败：账户已过期",
+            AuthError::KeyMismatch => "认证失败：加密密钥与用户不匹配",
+            AuthError::StaleTimestamp => "认证失败：握手时间戳超出允许范围",
+            AuthError::OutdatedClient => "认证失败：客户端版本过旧，不支持质询-响应认证",
+            AuthError::Protocol(message) => message,
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// 服务器接受的凭据：单个 `--token`、用户数据库，或两者同时存在
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    default_user: Option<User>,
+    users: UserDb,
+}
+
+impl Credentials {
+    pub fn new(token: Option<String>, users: Option<UserDb>) -> Result<Self> {
+        if token.is_none() && users.is_none() {
+            return Err(anyhow!("缺少 --token 或 --users 参数"));
+        }
+
+        Ok(Self {
+            default_user: token.map(|token| User {
+                name: DEFAULT_USER.to_string(),
+                token,
+                key: None,
+                enabled: true,
+                expires_at: None,
+                limits: Limits::default(),
+            }),
+            users: users.unwrap_or_default(),
+        })
+    }
+
+    /// 各用户专用的预共享密钥，服务器用它们尝试解密客户端的第一帧
+    pub fn user_keys(&self) -> Vec<CryptoManager> {
+        self.users.users.values().filter_map(|user| user.key.clone()).collect()
+    }
+
+    /// 验证握手；失败时返回发给客户端的原因
+    ///
+    /// 证明先于账户状态检查，只有持有 token 的人才能得知账户被停用或已过期
+    pub fn authenticate(
+        &self,
+        user: Option<&str>,
+        client_id: &str,
+        timestamp: u64,
+        challenge: &AuthChallenge,
+        proof: &AuthProof,
+    ) -> Result<&User, AuthError> {
+        let account = match user {
+            Some(name) => self.users.users.get(name),
+            None => self.default_user.as_ref(),
+        };
+
+        let account = match account {
+            Some(account) if challenge.verify(&account.token, client_id, timestamp, proof) => account,
+            _ => return Err(AuthError::InvalidCredentials),
+        };
+
+        account.check_active()?;
+        Ok(account)
+    }
+
+    /// 用旧凭据认证的账户在这份凭据中是否已失效：
+    /// 用户被删除、停用或过期，或者 token、专用密钥已更换
+    pub fn is_revoked(&self, user: &User) -> bool {
+        let current = [self.users.users.get(&user.name), self.default_user.as_ref()]
+            .into_iter()
+            .flatten()
+            .find(|account| account.name == user.name && account.token == user.token);
+
+        match current {
+            Some(account) => {
+                let same_key = match (&account.key, &user.key) {
+                    (Some(current), Some(old)) => current.same_key(old),
+                    (None, None) => true,
+                    _ => false,
+                };
+                account.check_active().is_err() || !same_key
+            }
+            None => true,
+        }
+    }
+}
+
+/// TOML 日期时间转换为 Unix 时间（秒）；只有日期时取当天零点
+fn datetime_to_unix(datetime: &toml::value::Datetime) -> Result<u64> {
+    let date = datetime.date.ok_or_else(|| anyhow!("缺少日期"))?;
+    let mut seconds = days_from_civil(date.year as i64, date.month as i64, date.day as i64) * 86400;
+
+    if let Some(time) = datetime.time {
+        seconds += time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64;
+    }
+    if let Some(toml::value::Offset::Custom { minutes }) = datetime.offset {
+        seconds -= minutes as i64 * 60;
+    }
+
+    u64::try_from(seconds).map_err(|_| anyhow!("时间早于 1970 年"))
+}
+
+/// 公历日期到 1970-01-01 的天数
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERS: &str = r#"
+        [[users]]
+        name = "alice"
+        token = "alice-token"
+        daily_quota = "10GiB"
+        upload_rate = 1048576
+
+        [[users]]
+        name = "bob"
+        token = "bob-token"
+        enabled = false
+
+        [[users]]
+        name = "carol"
+        token = "carol-token"
+        expires = 2001-09-09T01:46:40Z
+    "#;
+
+    fn authenticate(credentials: &Credentials, user: Option<&str>, token: &str) -> Result<String, AuthError> {
+        let challenge = AuthChallenge::new();
+        let proof = challenge.prove(token, "client", 1);
+        credentials
+            .authenticate(user, "client", 1, &challenge, &proof)
+            .map(|user| user.name.clone())
+    }
+
+    #[test]
+    fn test_user_db_authentication() {
+        let credentials = Credentials::new(Some("shared".to_string()), Some(UserDb::parse(USERS).unwrap())).unwrap();
+
+        assert_eq!(authenticate(&credentials, Some("alice"), "alice-token").unwrap(), "alice");
+        let alice = &credentials.users.users["alice"];
+        assert_eq!(alice.limits().daily_quota, Some(10 << 30));
+        assert_eq!(alice.limits().upload_rate, Some(1 << 20));
+        assert_eq!(alice.limits().monthly_quota, None);
+        assert_eq!(authenticate(&credentials, None, "shared").unwrap(), DEFAULT_USER);
+        assert!(authenticate(&credentials, Some("alice"), "shared").is_err());
+        assert!(authenticate(&credentials, Some("nobody"), "alice-token").is_err());
+        assert_eq!(authenticate(&credentials, Some("bob"), "bob-token"), Err(AuthError::Disabled));
+        assert_eq!(authenticate(&credentials, Some("carol"), "carol-token"), Err(AuthError::Expired));
+
+        // 只有用户数据库时，不带用户名的客户端被拒绝
+        let credentials = Credentials::new(None, Some(UserDb::parse(USERS).unwrap())).unwrap();
+        assert!(authenticate(&credentials, None, "shared").is_err());
+    }
+
+    #[test]
+    fn test_revoked_after_reload() {
+        let old = Credentials::new(Some("shared".to_string()), Some(UserDb::parse(USERS).unwrap())).unwrap();
+        let alice = old.users.users["alice"].clone();
+        let default = old.default_user.clone().unwrap();
+        assert!(!old.is_revoked(&alice));
+
+        // alice 换了 token，bob 被删除；默认用户不变
+        let new = Credentials::new(
+            Some("shared".to_string()),
+            Some(UserDb::parse("[[users]]\nname = \"alice\"\ntoken = \"rotated\"").unwrap()),
+        )
+        .unwrap();
+        assert!(new.is_revoked(&alice));
+        assert!(!new.is_revoked(&default));
+
+        let new = Credentials::new(None, Some(UserDb::parse(&USERS.replace("enabled = false", "")).unwrap())).unwrap();
+        assert!(new.is_revoked(&default));
+        assert!(!new.is_revoked(&alice));
+        assert!(!new.is_revoked(&old.users.users["bob"]));
+        assert!(new.is_revoked(&old.users.users["carol"]));
+    }
+
+    #[test]
+    fn test_user_db_validation() {
+        assert!(UserDb::parse("[[users]]\nname = \"a\"\ntoken = \"t\"\nkey = \"short\"").is_err());
+        assert!(UserDb::parse("[[users]]\nname = \"a\"\ntoken = \"t\"\n[[users]]\nname = \"a\"\ntoken = \"u\"").is_err());
+        assert!(UserDb::parse("[[users]]\nname = \"a\"\ntoken = \"t\"\ntypo = 1").is_err());
+        assert!(UserDb::parse("[[users]]\nname = \"a\"\ntoken = \"t\"\ndaily_quota = \"lots\"").is_err());
+        let error = UserDb::parse("[[users]]\nname = \"default\"\ntoken = \"t\"").unwrap_err();
+        assert!(error.to_string().contains("保留"), "{:#}", error);
+
+        let expires = "2001-09-09T01:46:40Z".parse().unwrap();
+        assert_eq!(datetime_to_unix(&expires).unwrap(), 1_000_000_000);
+        let expires = "1970-01-02".parse().unwrap();
+        assert_eq!(datetime_to_unix(&expires).unwrap(), 86400);
+    }
+}
@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+
+use crate::challenge::{AuthChallenge, AuthProof};
+use crate::protocol::{ProxyRequest, ProxyResponse};
+
+/// 每个流每个方向的初始发送窗口（字节）
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// 当前协议版本
+/// - 1: 所有消息均为 JSON 文本
+/// - 2: 数据与窗口更新使用二进制帧，控制消息仍为 JSON
+/// - 3: 质询-响应认证，token 不再随握手发送
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// 支持二进制数据帧的最低协议版本
+pub const BINARY_FRAMES_VERSION: u32 = 2;
+
+/// 使用质询-响应认证的最低协议版本；服务器拒绝更旧的客户端
+pub const CHALLENGE_AUTH_VERSION: u32 = 3;
+
+/// 二进制帧头长度：类型 (1) + 流 ID (4) + 标志 (1)
+pub const BINARY_HEADER_LEN: usize = 6;
+
+/// 二进制帧类型：流数据
+const BINARY_DATA: u8 = 0x01;
+/// 二进制帧类型：窗口更新，载荷为 4 字节大端增量
+const BINARY_WINDOW_UPDATE: u8 = 0x02;
+
+fn legacy_version() -> u32 {
+    1
+}
+
+/// WebSocket 消息类型枚举
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum WsMessage {
+    /// 握手请求
+    Handshake(HandshakeRequest),
+    /// 服务器对握手的质询
+    Challenge(AuthChallenge),
+    /// 客户端对质询的应答
+    Proof(AuthProof),
+    /// 握手响应
+    HandshakeResponse(HandshakeResponse),
+    /// 代理请求，在隧道上打开一个新流
+    ProxyRequest {
+        stream_id: u32,
+        request: ProxyRequest,
+    },
+    /// 代理响应
+    ProxyResponse {
+        stream_id: u32,
+        response: ProxyResponse,
+    },
+    /// 数据转发
+    Data {
+        stream_id: u32,
+        data: Vec<u8>,
+    },
+    /// 关闭流
+    Close {
+        stream_id: u32,
+        /// 关闭原因 (如用户配额用尽)，正常结束时不发送
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// 流量控制：接收方写出数据后归还发送窗口
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
+    /// 错误消息
+    Error(String),
+}
+
+impl WsMessage {
+    /// 把数据面消息编码为二进制帧
+    /// 格式: [类型 u8][流 ID u32 大端][标志 u8，保留为 0][载荷]
+    /// 控制消息不使用二进制帧，返回 `None`
+    pub fn encode_binary(&self) -> Option<Vec<u8>> {
+        let (frame_type, stream_id, payload) = match self {
+            WsMessage::Data { stream_id, data } => (BINARY_DATA, *stream_id, data.as_slice()),
+            WsMessage::WindowUpdate {
+                stream_id,
+                increment,
+            } => (
+                BINARY_WINDOW_UPDATE,
+                *stream_id,
+                &increment.to_be_bytes()[..],
+            ),
+            _ => return None,
+        };
+
+        let mut buf = Vec::with_capacity(BINARY_HEADER_LEN + payload.len());
+        buf.push(frame_type);
+        buf.extend_from_slice(&stream_id.to_be_bytes());
+        buf.push(0);
+        buf.extend_from_slice(payload);
+        Some(buf)
+    }
+
+    /// 解码二进制帧
+    pub fn decode_binary(buf: &[u8]) -> anyhow::Result<Self> {
+        if buf.len() < BINARY_HEADER_LEN {
+            return Err(anyhow::anyhow!("二进制帧长度不足"));
+        }
+
+        let stream_id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        let payload = &buf[BINARY_HEADER_LEN..];
+
+        match buf[0] {
+            BINARY_DATA => Ok(WsMessage::Data {
+                stream_id,
+                data: payload.to_vec(),
+            }),
+            BINARY_WINDOW_UPDATE => {
+                let increment: [u8; 4] = payload
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("无效的窗口更新帧"))?;
+                Ok(WsMessage::WindowUpdate {
+                    stream_id,
+                    increment: u32::from_be_bytes(increment),
+                })
+            }
+            frame_type => Err(anyhow::anyhow!("未知的二进制帧类型: {}", frame_type)),
+        }
+    }
+}
+
+/// 握手请求结构体
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeRequest {
+    /// 客户端唯一标识符
+    pub client_id: String,
+    /// 服务器用户数据库中的用户名；不发送时使用服务器的 `--token`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// 发送时的 Unix 时间（秒），计入认证证明
+    #[serde(default)]
+    pub timestamp: u64,
+    /// 客户端支持的最高协议版本，旧客户端不发送此字段
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+}
+
+/// 握手响应结构体
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeResponse {
+    /// 握手是否成功
+    pub success: bool,
+    /// 响应消息，包含成功或失败的原因
+    pub message: String,
+    /// 会话ID，握手成功时提供，用于后续通信
+    pub session_id: Option<String>,
+    /// 双方协商后使用的协议版本，旧服务器不发送此字段
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_data_roundtrip() {
+        let message = WsMessage::Data {
+            stream_id: 7,
+            data: b"hello".to_vec(),
+        };
+        let encoded = message.encode_binary().unwrap();
+        assert_eq!(encoded.len(), BINARY_HEADER_LEN + 5);
+
+        match WsMessage::decode_binary(&encoded).unwrap() {
+            WsMessage::Data { stream_id, data } => {
+                assert_eq!(stream_id, 7);
+                assert_eq!(data, b"hello");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_binary_window_update_roundtrip() {
+        let message = WsMessage::WindowUpdate {
+            stream_id: 3,
+            increment: 65536,
+        };
+        let encoded = message.encode_binary().unwrap();
+
+        match WsMessage::decode_binary(&encoded).unwrap() {
+            WsMessage::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                assert_eq!(stream_id, 3);
+                assert_eq!(increment, 65536);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_control_messages_stay_json() {
+        assert!(WsMessage::Close { stream_id: 1, reason: None }.encode_binary().is_none());
+        assert!(WsMessage::decode_binary(&[BINARY_DATA, 0, 0]).is_err());
+        assert!(WsMessage::decode_binary(&[0xFF, 0, 0, 0, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn test_legacy_handshake_defaults_to_v1() {
+        let text = r#"{"type":"Handshake","data":{"token":"t","client_id":"c"}}"#;
+        match serde_json::from_str::<WsMessage>(text).unwrap() {
+            WsMessage::Handshake(handshake) => assert_eq!(handshake.version, 1),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+}
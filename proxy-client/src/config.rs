@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use leaf_protocol::auth::SocksAuth;
+use leaf_protocol::codec::{Framing, DEFAULT_MAX_FRAME_LEN, MAX_HANDSHAKE_FRAME_LEN};
+use leaf_protocol::config;
+use leaf_protocol::noise;
+use leaf_protocol::pool::{ServerSpec, Strategy};
+use leaf_protocol::routing::RoutingConfig;
+use leaf_protocol::{CryptoManager, TargetAddr};
+
+use crate::mux::IdlePool;
+
+#[cfg(target_os = "linux")]
+use crate::transparent::TransparentMode;
+
+/// 客户端的配置文件，配置项与同名的命令行参数一致，命令行参数优先
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// SOCKS5 监听地址，可以写一个或多个
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub socks_addr: Vec<SocketAddr>,
+    /// 代理服务器，可以写一个或多个，`名称=地址` 给服务器命名
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub server_addr: Vec<ServerSpec>,
+    pub strategy: Strategy,
+    /// 服务器池的健康检查间隔 (秒)，0 表示不检查
+    pub health_check_interval: u64,
+    /// 健康检查时经每台服务器连接的目标 (`主机:端口`)，用于测量连接延迟
+    pub latency_probe: Option<TargetAddr>,
+    /// 每台服务器保持的备用会话数，当前会话断开时直接换用，0 表示不保持
+    /// 备用会话不承载流量，所有流仍然只走当前会话
+    pub min_idle: usize,
+    /// 每台服务器备用会话数的上限
+    pub max_idle: usize,
+    /// 备用会话的最长寿命 (秒)，超过后重新建立
+    pub idle_max_age: u64,
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub key: Option<String>,
+    pub max_frame_size: usize,
+    pub framing: Framing,
+    pub server_public_key: Option<String>,
+    /// SOCKS5 用户凭据，格式为 `用户名:密码`
+    pub socks_users: Vec<String>,
+    pub require_auth: bool,
+    /// HTTP 代理监听地址，为空时不启用
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub http_addr: Vec<SocketAddr>,
+    #[cfg(target_os = "linux")]
+    pub transparent_addr: Option<SocketAddr>,
+    #[cfg(target_os = "linux")]
+    pub transparent_mode: TransparentMode,
+    pub metrics_addr: Option<SocketAddr>,
+    /// 路由规则，见 `[routing]` 表
+    pub routing: RoutingConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socks_addr: vec!["127.0.0.1:1080".parse().unwrap()],
+            server_addr: vec!["127.0.0.1:8080".parse().unwrap()],
+            strategy: Strategy::Failover,
+            health_check_interval: 30,
+            latency_probe: None,
+            min_idle: 0,
+            max_idle: 4,
+            idle_max_age: 300,
+            token: None,
+            user: None,
+            key: None,
+            max_frame_size: DEFAULT_MAX_FRAME_LEN,
+            framing: Framing::V2,
+            server_public_key: None,
+            socks_users: Vec::new(),
+            require_auth: false,
+            http_addr: Vec::new(),
+            #[cfg(target_os = "linux")]
+            transparent_addr: None,
+            #[cfg(target_os = "linux")]
+            transparent_mode: TransparentMode::Redirect,
+            metrics_addr: None,
+            routing: RoutingConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// 读取配置文件，规则文件的相对路径按配置文件所在的目录解析
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config: Config = config::load(path)?;
+        config.routing.resolve_paths(path);
+        Ok(config)
+    }
+
+    /// `--print-default-config` 输出的内容
+    pub fn default_toml() -> Result<String> {
+        config::to_toml(&Self::default())
+    }
+
+    /// 检查合并命令行参数后的配置
+    pub fn validate(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        let transparent = self.transparent_addr.is_some();
+        #[cfg(not(target_os = "linux"))]
+        let transparent = false;
+        if self.socks_addr.is_empty() && self.http_addr.is_empty() && !transparent {
+            return Err(anyhow!("socks_addr、http_addr 和 transparent_addr 至少设置一个"));
+        }
+        if self.server_addr.is_empty() {
+            return Err(anyhow!("server_addr 至少需要一个服务器"));
+        }
+        for (index, server) in self.server_addr.iter().enumerate() {
+            if self.server_addr[..index].iter().any(|other| other.name == server.name) {
+                return Err(anyhow!("server_addr 中的服务器名称重复: {}", server.name));
+            }
+        }
+        if self.strategy == Strategy::Latency && self.health_check_interval == 0 {
+            return Err(anyhow!("latency 策略依靠健康检查测量延迟，health_check_interval 不能为 0"));
+        }
+        if self.min_idle > self.max_idle {
+            return Err(anyhow!("min_idle ({}) 不能大于 max_idle ({})", self.min_idle, self.max_idle));
+        }
+        if self.min_idle > 0 && self.idle_max_age == 0 {
+            return Err(anyhow!("idle_max_age 不能为 0"));
+        }
+        if self.token.as_deref().is_none_or(str::is_empty) {
+            return Err(anyhow!("缺少认证 token：设置配置项 token 或 --token 参数"));
+        }
+        let key = self.key.as_deref().ok_or_else(|| anyhow!("缺少加密密钥：设置配置项 key 或 --key 参数"))?;
+        CryptoManager::new(key).context("配置项 key 无效")?;
+        if self.max_frame_size < MAX_HANDSHAKE_FRAME_LEN {
+            return Err(anyhow!("max_frame_size 不能小于 {}", MAX_HANDSHAKE_FRAME_LEN));
+        }
+        if let Some(server_public_key) = &self.server_public_key {
+            noise::parse_public_key(server_public_key).context("配置项 server_public_key 无效")?;
+        }
+        SocksAuth::from_entries(&self.socks_users).context("配置项 socks_users 无效")?;
+        Ok(())
+    }
+
+    /// 健康检查间隔，`None` 表示不检查
+    pub fn health_check(&self) -> Option<Duration> {
+        (self.health_check_interval > 0).then(|| Duration::from_secs(self.health_check_interval))
+    }
+
+    /// 备用会话的配置，`min_idle` 为 0 时为 `None`
+    pub fn idle_pool(&self) -> Option<IdlePool> {
+        (self.min_idle > 0).then(|| IdlePool {
+            min_idle: self.min_idle,
+            max_idle: self.max_idle,
+            max_age: Duration::from_secs(self.idle_max_age),
+        })
+    }
+}
@@ -0,0 +1,700 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, info};
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        DuplexStream, ReadHalf, WriteHalf,
+    },
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+use leaf_protocol::auth::SocksAuth;
+use leaf_protocol::mux::MAX_DATA_CHUNK;
+use leaf_protocol::{ProxyCommand, ProxyRequest, TargetAddr};
+
+use crate::outbound::{Dialer, Outbound};
+
+/// 请求头或响应头的最大长度
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// 逐跳头部，转发时总是去掉
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// 解析后的 HTTP 报文头
+#[derive(Debug)]
+struct Head {
+    /// 请求行或状态行
+    start_line: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 检查逗号分隔的头部值中是否包含某个标记
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// 去掉逐跳头部，以及 `Connection` 中列出的头部
+    fn strip_hop_by_hop(&mut self) {
+        let listed: Vec<String> = self
+            .headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|value| value.trim().to_ascii_lowercase())
+            .collect();
+
+        self.headers.retain(|(key, _)| {
+            let key = key.to_ascii_lowercase();
+            !HOP_BY_HOP_HEADERS.contains(&key.as_str()) && !listed.contains(&key)
+        });
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(256);
+        buf.extend_from_slice(self.start_line.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+        for (key, value) in &self.headers {
+            buf.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+        }
+        buf.extend_from_slice(b"\r\n");
+        buf
+    }
+}
+
+/// 报文体的长度界定方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    /// 读到连接关闭为止，只用于响应
+    UntilClose,
+}
+
+fn body_length(head: &Head) -> Result<Option<BodyLength>> {
+    if head.has_token("transfer-encoding", "chunked") {
+        return Ok(Some(BodyLength::Chunked));
+    }
+
+    match head.header("content-length") {
+        Some(length) => {
+            let length = length
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("无效的 Content-Length: {}", length))?;
+            Ok(Some(BodyLength::Fixed(length)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// 到目标的一条上游连接，空闲时可以被同一目标的后续请求复用
+struct Upstream {
+    target_addr: TargetAddr,
+    reader: BufReader<ReadHalf<DuplexStream>>,
+    writer: WriteHalf<DuplexStream>,
+}
+
+/// 处理一个 HTTP 代理连接
+/// 支持 `CONNECT host:port` 隧道和绝对 URI 的普通转发请求
+pub async fn handle_http_connection(
+    client: TcpStream,
+    dialer: Arc<Dialer>,
+    auth: Arc<SocksAuth>,
+) -> Result<()> {
+    let (read_half, mut client_write) = client.into_split();
+    let mut client_read = BufReader::new(read_half);
+    let mut upstream: Option<Upstream> = None;
+
+    while let Some(request) = read_head(&mut client_read).await? {
+        let user = match authorize(&request, &auth) {
+            Ok(user) => user,
+            Err(e) => {
+                debug!("HTTP 代理认证失败: {}", e);
+                client_write
+                    .write_all(
+                        b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                          Proxy-Authenticate: Basic realm=\"leaf\"\r\n\
+                          Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let mut parts = request.start_line.split_whitespace();
+        let (Some(method), Some(uri), Some(version)) = (parts.next(), parts.next(), parts.next())
+        else {
+            send_error(&mut client_write, "400 Bad Request").await?;
+            return Err(anyhow!("无效的请求行: {}", request.start_line));
+        };
+
+        if method.eq_ignore_ascii_case("CONNECT") {
+            let target_addr = match uri.parse::<TargetAddr>() {
+                Ok(target_addr) => target_addr,
+                Err(e) => {
+                    send_error(&mut client_write, "400 Bad Request").await?;
+                    return Err(anyhow!("无效的 CONNECT 目标 {}: {}", uri, e));
+                }
+            };
+            return handle_connect(client_read, client_write, &dialer, target_addr, user).await;
+        }
+
+        let Some((target_addr, path)) = parse_absolute_uri(uri) else {
+            send_error(&mut client_write, "400 Bad Request").await?;
+            return Err(anyhow!("HTTP 代理只接受绝对 URI: {}", uri));
+        };
+
+        let request_line = format!("{} {} {}", method, path, version);
+        let request = Head {
+            start_line: request_line,
+            headers: request.headers,
+        };
+        let keep_alive = forward_request(
+            &mut client_read,
+            &mut client_write,
+            &mut upstream,
+            &dialer,
+            request,
+            target_addr,
+            user,
+        )
+        .await?;
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_connect(
+    client_read: BufReader<OwnedReadHalf>,
+    mut client_write: OwnedWriteHalf,
+    dialer: &Dialer,
+    target_addr: TargetAddr,
+    user: Option<String>,
+) -> Result<()> {
+    let request = ProxyRequest {
+        target_addr,
+        command: ProxyCommand::Connect,
+        user,
+    };
+
+    let outbound = match dialer.connect(&request).await {
+        Ok(Outbound::Rejected) => {
+            info!("拒绝 CONNECT 隧道到 {}", request.target_addr);
+            return send_error(&mut client_write, "403 Forbidden").await;
+        }
+        Ok(outbound) => outbound,
+        Err(e) => {
+            send_error(&mut client_write, "502 Bad Gateway").await?;
+            return Err(e);
+        }
+    };
+
+    client_write
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+
+    // 客户端可能在收到 200 之前就发送了数据，先把缓冲区里已读取的部分交给隧道
+    let buffered = client_read.buffer().to_vec();
+    let socket = tokio::io::join(Cursor::new(buffered).chain(client_read.into_inner()), client_write);
+    outbound.relay(socket, &request.target_addr).await
+}
+
+/// 转发一个普通 HTTP 请求及其响应，返回客户端连接是否可以继续使用
+/// `request` 的请求行已改写为源站形式
+async fn forward_request(
+    client_read: &mut BufReader<OwnedReadHalf>,
+    client_write: &mut OwnedWriteHalf,
+    upstream: &mut Option<Upstream>,
+    dialer: &Dialer,
+    mut request: Head,
+    target_addr: TargetAddr,
+    user: Option<String>,
+) -> Result<bool> {
+    let client_keep_alive = wants_keep_alive(&request);
+    let method = request.start_line.split(' ').next().unwrap_or_default().to_string();
+    let request_body = body_length(&request)?.unwrap_or(BodyLength::Empty);
+
+    // 由代理直接答复 100 Continue，上游不需要再处理 Expect
+    if request.has_token("expect", "100-continue") {
+        client_write.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        request.headers.retain(|(key, _)| !key.eq_ignore_ascii_case("expect"));
+    }
+
+    request.strip_hop_by_hop();
+    if request.header("host").is_none() {
+        request.headers.push(("Host".to_string(), target_addr.to_string()));
+    }
+    request.headers.push(("Connection".to_string(), "keep-alive".to_string()));
+    let request = request.encode();
+
+    // 复用的上游可能已被目标关闭；请求体已经从客户端读出，只有没有请求体的幂等请求可以在新连接上重试
+    let retryable = request_body == BodyLength::Empty && is_idempotent(&method);
+    let mut response = loop {
+        // 目标不同或上游已经关闭时建立新的上游连接
+        let reused = upstream.as_ref().is_some_and(|up| up.target_addr == target_addr);
+        let up = match upstream.take() {
+            Some(up) if up.target_addr == target_addr => upstream.insert(up),
+            _ => match open_upstream(dialer, target_addr.clone(), user.clone()).await {
+                Ok(Some(up)) => upstream.insert(up),
+                Ok(None) => {
+                    // 请求体没有读取，连接不能继续使用
+                    info!("拒绝 HTTP 请求 {} {}", method, target_addr);
+                    send_error(client_write, "403 Forbidden").await?;
+                    return Ok(false);
+                }
+                Err(e) => {
+                    send_error(client_write, "502 Bad Gateway").await?;
+                    return Err(e);
+                }
+            },
+        };
+
+        match exchange(up, client_read, client_write, &request, request_body).await {
+            Ok(response) => break response,
+            Err(e) if reused && retryable => {
+                debug!("复用的上游连接已失效，重试 {} {}: {}", method, target_addr, e);
+                *upstream = None;
+            }
+            Err(e) => {
+                *upstream = None;
+                send_error(client_write, "502 Bad Gateway").await?;
+                return Err(e);
+            }
+        }
+    };
+    let Some(up) = upstream.as_mut() else {
+        return Err(anyhow!("上游连接已关闭"));
+    };
+
+    let status = response_status(&response)?;
+    let response_body = if method.eq_ignore_ascii_case("HEAD") || status == 204 || status == 304 {
+        BodyLength::Empty
+    } else {
+        body_length(&response)?.unwrap_or(BodyLength::UntilClose)
+    };
+
+    let upstream_reusable = response_body != BodyLength::UntilClose && wants_keep_alive(&response);
+    let keep_alive = client_keep_alive && response_body != BodyLength::UntilClose;
+
+    response.strip_hop_by_hop();
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    response.headers.push(("Connection".to_string(), connection.to_string()));
+
+    client_write.write_all(&response.encode()).await?;
+    copy_body(&mut up.reader, client_write, response_body).await?;
+    client_write.flush().await?;
+
+    debug!("{} {} -> {}", method, target_addr, status);
+    if !upstream_reusable {
+        *upstream = None;
+    }
+
+    Ok(keep_alive)
+}
+
+/// 向上游发送请求，返回最终响应的报文头；1xx 中间响应直接转发给客户端
+async fn exchange(
+    up: &mut Upstream,
+    client_read: &mut BufReader<OwnedReadHalf>,
+    client_write: &mut OwnedWriteHalf,
+    request: &[u8],
+    request_body: BodyLength,
+) -> Result<Head> {
+    up.writer.write_all(request).await?;
+    copy_body(client_read, &mut up.writer, request_body).await?;
+    up.writer.flush().await?;
+
+    loop {
+        let response = read_head(&mut up.reader)
+            .await?
+            .ok_or_else(|| anyhow!("上游在响应前关闭了连接"))?;
+        let status = response_status(&response)?;
+        if !(100..200).contains(&status) || status == 101 {
+            return Ok(response);
+        }
+        client_write.write_all(&response.encode()).await?;
+    }
+}
+
+/// 按路由规则打开到目标的连接，并把它包装成本地的字节流；被规则拒绝时返回 `None`
+async fn open_upstream(
+    dialer: &Dialer,
+    target_addr: TargetAddr,
+    user: Option<String>,
+) -> Result<Option<Upstream>> {
+    let request = ProxyRequest {
+        target_addr: target_addr.clone(),
+        command: ProxyCommand::Connect,
+        user,
+    };
+
+    let outbound = match dialer.connect(&request).await? {
+        Outbound::Rejected => return Ok(None),
+        outbound => outbound,
+    };
+
+    let (local, remote) = tokio::io::duplex(MAX_DATA_CHUNK * 4);
+    let relay_target = target_addr.clone();
+    tokio::spawn(async move {
+        let _ = outbound.relay(remote, &relay_target).await;
+    });
+
+    let (reader, writer) = tokio::io::split(local);
+    Ok(Some(Upstream {
+        target_addr,
+        reader: BufReader::new(reader),
+        writer,
+    }))
+}
+
+/// 校验 `Proxy-Authorization: Basic ...`，未启用认证时直接放行
+fn authorize(request: &Head, auth: &SocksAuth) -> Result<Option<String>> {
+    if !auth.is_enabled() {
+        return Ok(None);
+    }
+
+    let credentials = request
+        .header("proxy-authorization")
+        .and_then(|value| value.trim().strip_prefix("Basic "))
+        .ok_or_else(|| anyhow!("缺少 Proxy-Authorization"))?;
+    let decoded = String::from_utf8(STANDARD.decode(credentials.trim())?)?;
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or_else(|| anyhow!("无效的 Proxy-Authorization"))?;
+
+    if !auth.verify(username, password) {
+        return Err(anyhow!("用户 {} 认证失败", username));
+    }
+
+    Ok(Some(username.to_string()))
+}
+
+/// 重复发送不会改变结果的请求方法 (RFC 9110 9.2.2)
+fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"]
+        .iter()
+        .any(|idempotent| method.eq_ignore_ascii_case(idempotent))
+}
+
+/// HTTP/1.1 默认保持连接，HTTP/1.0 需要显式要求
+fn wants_keep_alive(head: &Head) -> bool {
+    if head.has_token("connection", "close") || head.has_token("proxy-connection", "close") {
+        return false;
+    }
+
+    // 请求行以版本结尾，状态行以版本开头
+    head.start_line.ends_with("HTTP/1.1")
+        || head.start_line.starts_with("HTTP/1.1")
+        || head.has_token("connection", "keep-alive")
+        || head.has_token("proxy-connection", "keep-alive")
+}
+
+/// 把 `http://host[:port]/path` 拆成目标地址和源站形式的路径
+fn parse_absolute_uri(uri: &str) -> Option<(TargetAddr, String)> {
+    let (scheme, rest) = uri.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") {
+        return None;
+    }
+
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(index) => (&rest[..index], rest[index..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    let path = if path.starts_with('?') {
+        format!("/{}", path)
+    } else {
+        path
+    };
+
+    // 去掉 URI 中的用户信息
+    let authority = authority.rsplit('@').next()?;
+    let has_port = match authority.rfind(']') {
+        Some(bracket) => authority[bracket..].contains(':'),
+        None => authority.contains(':'),
+    };
+
+    let target_addr = if has_port {
+        authority.parse().ok()?
+    } else {
+        format!("{}:80", authority).parse().ok()?
+    };
+
+    Some((target_addr, path))
+}
+
+fn response_status(response: &Head) -> Result<u16> {
+    response
+        .start_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("无效的状态行: {}", response.start_line))
+}
+
+/// 读取一个报文头，连接在报文开始前关闭时返回 `None`
+async fn read_head<R>(reader: &mut R) -> Result<Option<Head>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut lines = Vec::new();
+    let mut total = 0;
+
+    loop {
+        let mut line = Vec::new();
+        let limit = (MAX_HEAD_LEN - total) as u64;
+        let n = (&mut *reader).take(limit).read_until(b'\n', &mut line).await?;
+
+        if n == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(anyhow!("报文头不完整"));
+        }
+        total += n;
+        if !line.ends_with(b"\n") {
+            return Err(anyhow!("报文头超过 {} 字节", MAX_HEAD_LEN));
+        }
+
+        let line = String::from_utf8(line)?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            // 允许请求之间多余的空行
+            if lines.is_empty() {
+                continue;
+            }
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let start_line = lines.remove(0);
+    let headers = lines
+        .into_iter()
+        .map(|line| {
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow!("无效的头部: {}", line))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Some(Head {
+        start_line,
+        headers,
+    }))
+}
+
+/// 按长度界定方式原样复制报文体
+async fn copy_body<R, W>(reader: &mut R, writer: &mut W, length: BodyLength) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::Empty => {}
+        BodyLength::Fixed(length) => {
+            let copied = tokio::io::copy(&mut (&mut *reader).take(length), writer).await?;
+            if copied != length {
+                return Err(anyhow!("报文体不完整: {}/{} 字节", copied, length));
+            }
+        }
+        BodyLength::UntilClose => {
+            tokio::io::copy(reader, writer).await?;
+        }
+        BodyLength::Chunked => loop {
+            let mut line = String::new();
+            if (&mut *reader).take(MAX_HEAD_LEN as u64).read_line(&mut line).await? == 0 {
+                return Err(anyhow!("分块报文体不完整"));
+            }
+            writer.write_all(line.as_bytes()).await?;
+
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .map_err(|_| anyhow!("无效的分块长度: {}", size))?;
+
+            if size == 0 {
+                // 复制尾部头部直到空行
+                loop {
+                    let mut trailer = String::new();
+                    if (&mut *reader).take(MAX_HEAD_LEN as u64).read_line(&mut trailer).await? == 0 {
+                        return Err(anyhow!("分块报文体不完整"));
+                    }
+                    writer.write_all(trailer.as_bytes()).await?;
+                    if trailer.trim_end().is_empty() {
+                        return Ok(());
+                    }
+                }
+            }
+
+            // 分块数据后跟 CRLF
+            let chunk = size + 2;
+            let copied = tokio::io::copy(&mut (&mut *reader).take(chunk), writer).await?;
+            if copied != chunk {
+                return Err(anyhow!("分块报文体不完整"));
+            }
+        },
+    }
+
+    Ok(())
+}
+
+async fn send_error<W>(writer: &mut W, status: &str) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    writer.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leaf_protocol::metrics::Metrics;
+    use leaf_protocol::pool::{ServerPool, Strategy};
+    use leaf_protocol::routing::{Action, Router};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// 在本地端口上运行 HTTP 代理，目标直连，返回连到代理的客户端连接
+    async fn proxy() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let servers = Arc::new(ServerPool::new(Vec::new(), Strategy::Failover));
+        let dialer = Arc::new(Dialer::new(servers, Router::new(Action::Direct), Arc::new(Metrics::new("test"))));
+        tokio::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            let _ = handle_http_connection(client, dialer, Arc::new(SocksAuth::default())).await;
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    /// 每条连接只答复一个请求就关闭的源站，响应仍声明保持连接
+    async fn one_shot_origin() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let mut socket = BufReader::new(socket);
+                let request = read_head(&mut socket).await.unwrap().unwrap();
+                let body = body_length(&request).unwrap().unwrap_or(BodyLength::Empty);
+                copy_body(&mut socket, &mut tokio::io::sink(), body).await.unwrap();
+                socket
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn read_response(reader: &mut BufReader<TcpStream>) -> (u16, Vec<u8>) {
+        let response = read_head(reader).await.unwrap().unwrap();
+        let mut body = Vec::new();
+        let length = body_length(&response).unwrap().unwrap_or(BodyLength::Empty);
+        copy_body(reader, &mut body, length).await.unwrap();
+        (response_status(&response).unwrap(), body)
+    }
+
+    #[test]
+    fn test_parse_absolute_uri() {
+        let (addr, path) = parse_absolute_uri("http://example.com/a?b=1").unwrap();
+        assert_eq!(addr, TargetAddr::Domain("example.com".to_string(), 80));
+        assert_eq!(path, "/a?b=1");
+
+        let (addr, path) = parse_absolute_uri("http://[::1]:8080").unwrap();
+        assert_eq!(addr, TargetAddr::Ipv6("::1".parse().unwrap(), 8080));
+        assert_eq!(path, "/");
+
+        assert!(parse_absolute_uri("/relative").is_none());
+        assert!(parse_absolute_uri("https://example.com/").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_head_and_chunked_body() {
+        let raw: &[u8] = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+            Connection: close, X-Private\r\nX-Private: 1\r\nProxy-Authorization: x\r\n\
+            Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\nNEXT";
+        let mut reader = BufReader::new(raw);
+
+        let mut head = read_head(&mut reader).await.unwrap().unwrap();
+        assert!(!wants_keep_alive(&head));
+        assert_eq!(body_length(&head).unwrap(), Some(BodyLength::Chunked));
+
+        head.strip_hop_by_hop();
+        let names: Vec<_> = head.headers.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(names, ["Host", "Transfer-Encoding"]);
+
+        let mut body = Vec::new();
+        copy_body(&mut reader, &mut body, BodyLength::Chunked).await.unwrap();
+        assert_eq!(body, b"5\r\nhello\r\n0\r\n\r\n");
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_connect_target() {
+        let mut client = BufReader::new(proxy().await);
+        client.get_mut().write_all(b"CONNECT example.com HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(read_response(&mut client).await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn test_stale_upstream() {
+        let origin = one_shot_origin().await;
+        let mut client = BufReader::new(proxy().await);
+        let get = format!("GET http://{}/ HTTP/1.1\r\nHost: origin\r\n\r\n", origin);
+
+        client.get_mut().write_all(get.as_bytes()).await.unwrap();
+        assert_eq!(read_response(&mut client).await, (200, b"ok".to_vec()));
+
+        // 源站已经关闭了保持的连接，GET 在新的上游连接上重试
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.get_mut().write_all(get.as_bytes()).await.unwrap();
+        assert_eq!(read_response(&mut client).await, (200, b"ok".to_vec()));
+
+        // 带请求体的 POST 不能重试，客户端收到 502
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let post = format!("POST http://{}/ HTTP/1.1\r\nHost: origin\r\nContent-Length: 4\r\n\r\ndata", origin);
+        client.get_mut().write_all(post.as_bytes()).await.unwrap();
+        assert_eq!(read_response(&mut client).await.0, 502);
+    }
+}
@@ -1,44 +1,194 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use log::{error, info, warn};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-};
-
-mod crypto;
-mod protocol;
-
-use crypto::CryptoManager;
-use protocol::{HandshakeRequest, HandshakeResponse, ProxyRequest, ProxyResponse};
-
-const SOCKS_VERSION: u8 = 0x05;
-const NO_AUTHENTICATION: u8 = 0x00;
-const CONNECT_COMMAND: u8 = 0x01;
-const IPV4_ADDRESS: u8 = 0x01;
-const DOMAIN_NAME: u8 = 0x03;
-const IPV6_ADDRESS: u8 = 0x04;
+use futures_util::future::join_all;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_util::codec::Framed;
+
+mod config;
+mod http;
+mod mux;
+mod outbound;
+#[cfg(target_os = "linux")]
+mod transparent;
+mod udp;
+
+use leaf_protocol::auth::SocksAuth;
+use leaf_protocol::challenge::AuthChallenge;
+use leaf_protocol::metrics::{self, Metrics};
+use leaf_protocol::protocol::{HandshakeRequest, HandshakeResponse};
+use leaf_protocol::noise;
+use leaf_protocol::pool::{ServerPool, ServerSpec, Strategy};
+use leaf_protocol::replay;
+use leaf_protocol::routing::Action;
+use leaf_protocol::socks5::{self, Reply};
+use leaf_protocol::codec::{FrameCodec, Framing};
+use leaf_protocol::mux::{MuxSession, MuxStream};
+use leaf_protocol::{CryptoManager, ProxyCommand, ProxyRequest, ProxyResponse, TargetAddr};
+use config::Config;
+use mux::MuxClient;
+use outbound::{Dialer, Outbound};
 
 #[derive(Parser)]
 #[command(name = "proxy-client")]
 #[command(about = "Secure proxy client with SOCKS5 support")]
 struct Args {
-    /// SOCKS5 listen address
-    #[arg(short = 'l', long, default_value = "127.0.0.1:1080")]
-    socks_addr: String,
+    /// Configuration file (TOML); command-line flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Print the default configuration file and exit
+    #[arg(long)]
+    print_default_config: bool,
+
+    /// SOCKS5 listen address, repeatable [default: 127.0.0.1:1080]
+    #[arg(short = 'l', long)]
+    socks_addr: Vec<SocketAddr>,
+
+    /// Proxy server address, optionally named as name=host:port; repeat to build a server pool [default: 127.0.0.1:8080]
+    #[arg(short = 's', long)]
+    server_addr: Vec<ServerSpec>,
+
+    /// How new connections pick a server: failover, round-robin, least-connections, consistent-hash or latency [default: failover]
+    #[arg(long)]
+    strategy: Option<Strategy>,
+
+    /// Seconds between background handshake health checks of pooled servers; 0 disables [default: 30]
+    #[arg(long)]
+    health_check_interval: Option<u64>,
+
+    /// Target (host:port) opened through each pooled server during health checks to measure connect latency
+    #[arg(long)]
+    latency_probe: Option<TargetAddr>,
+
+    /// Standby authenticated sessions kept per server; they carry no traffic and only replace a dropped session without a reconnect handshake [default: 0]
+    #[arg(long)]
+    min_idle: Option<usize>,
 
-    /// Proxy server address
-    #[arg(short = 's', long, default_value = "127.0.0.1:8080")]
-    server_addr: String,
+    /// Upper bound on standby sessions per server [default: 4]
+    #[arg(long)]
+    max_idle: Option<usize>,
+
+    /// Seconds after which a standby session is closed and replaced [default: 300]
+    #[arg(long)]
+    idle_max_age: Option<u64>,
 
     /// Authentication token
     #[arg(short, long)]
-    token: String,
+    token: Option<String>,
+
+    /// User name in the server's user database; the token is then that user's token
+    #[arg(long)]
+    user: Option<String>,
 
     /// Encryption key (base64 encoded)
     #[arg(short, long)]
-    key: String,
+    key: Option<String>,
+
+    /// Maximum encrypted frame size in bytes; larger frames from the server close the session [default: 131072]
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+
+    /// Frame format: v2 (per-session keys, replay protection) or v1 (legacy servers) [default: v2]
+    #[arg(long)]
+    framing: Option<Framing>,
+
+    /// Pinned Noise static public key of the server (base64); enables the forward-secret Noise IK handshake
+    #[arg(long)]
+    server_public_key: Option<String>,
+
+    /// SOCKS5 user credentials as user:password (repeatable); enables RFC 1929 auth
+    #[arg(short = 'u', long = "socks-user")]
+    socks_users: Vec<String>,
+
+    /// Refuse to listen on a non-loopback address without SOCKS5 credentials
+    #[arg(long)]
+    require_auth: bool,
+
+    /// HTTP proxy listen address (CONNECT and plain forward requests), repeatable; disabled when unset
+    #[arg(long)]
+    http_addr: Vec<SocketAddr>,
+
+    /// Transparent proxy listen address for iptables/nftables redirected traffic; disabled when unset
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    transparent_addr: Option<SocketAddr>,
+
+    /// How redirected connections reach the transparent listener [default: redirect]
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_enum)]
+    transparent_mode: Option<transparent::TransparentMode>,
+
+    /// Prometheus metrics address; serves GET /metrics when set
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Routing rule as type,value,action, e.g. domain-suffix,corp.example,direct (repeatable)
+    #[arg(long = "rule")]
+    rules: Vec<String>,
+
+    /// Rule-list file with one rule per line, matched after --rule (repeatable)
+    #[arg(long = "rule-file")]
+    rule_files: Vec<PathBuf>,
+
+    /// Action when no rule matches: proxy, direct or reject [default: proxy]
+    #[arg(long)]
+    default_route: Option<Action>,
+}
+
+impl Args {
+    /// 读取配置文件 (如果指定了) 并用命令行参数覆盖
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.socks_addr.is_empty() {
+            config.socks_addr = self.socks_addr;
+        }
+        if !self.server_addr.is_empty() {
+            config.server_addr = self.server_addr;
+        }
+        config.strategy = self.strategy.unwrap_or(config.strategy);
+        config.health_check_interval = self.health_check_interval.unwrap_or(config.health_check_interval);
+        config.latency_probe = self.latency_probe.or(config.latency_probe);
+        config.min_idle = self.min_idle.unwrap_or(config.min_idle);
+        config.max_idle = self.max_idle.unwrap_or(config.max_idle);
+        config.idle_max_age = self.idle_max_age.unwrap_or(config.idle_max_age);
+        config.token = self.token.or(config.token);
+        config.user = self.user.or(config.user);
+        config.key = self.key.or(config.key);
+        config.max_frame_size = self.max_frame_size.unwrap_or(config.max_frame_size);
+        config.framing = self.framing.unwrap_or(config.framing);
+        config.server_public_key = self.server_public_key.or(config.server_public_key);
+        if !self.socks_users.is_empty() {
+            config.socks_users = self.socks_users;
+        }
+        config.require_auth |= self.require_auth;
+        if !self.http_addr.is_empty() {
+            config.http_addr = self.http_addr;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            config.transparent_addr = self.transparent_addr.or(config.transparent_addr);
+            config.transparent_mode = self.transparent_mode.unwrap_or(config.transparent_mode);
+        }
+        config.metrics_addr = self.metrics_addr.or(config.metrics_addr);
+        if !self.rules.is_empty() {
+            config.routing.rules = self.rules;
+        }
+        if !self.rule_files.is_empty() {
+            config.routing.rule_files = self.rule_files;
+        }
+        config.routing.default = self.default_route.unwrap_or(config.routing.default);
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
@@ -46,23 +196,114 @@ async fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
 
+    if args.print_default_config {
+        print!("{}", Config::default_toml()?);
+        return Ok(());
+    }
+
+    let config = args.into_config()?;
+    let token = config.token.clone().expect("validate 已检查");
+    let key = config.key.as_deref().expect("validate 已检查");
+
     // 初始化加密管理器
-    let crypto = CryptoManager::new(&args.key)?;
+    let crypto = CryptoManager::new(key)?;
+    let codec = FrameCodec::new(crypto)
+        .max_frame_len(config.max_frame_size)
+        .framing(config.framing);
+
+    // 所有入站连接共享到每台代理服务器的多路复用会话
+    let server_public_key = config.server_public_key.as_deref().map(noise::parse_public_key).transpose()?;
+    let metrics = Arc::new(Metrics::new("proxy-client"));
+    let servers = config
+        .server_addr
+        .iter()
+        .map(|server| {
+            let mux = MuxClient::new(server.addr.clone(), token.clone(), codec.clone())
+                .user(config.user.clone())
+                .server_public_key(server_public_key)
+                .idle_pool(config.idle_pool())
+                .metrics(metrics.clone());
+            (server.name.clone(), mux)
+        })
+        .collect();
+    let servers = ServerPool::new(servers, config.strategy)
+        .health_check_interval(config.health_check())
+        .latency_probe(config.latency_probe.clone())
+        .metrics(metrics.clone());
+    let servers = Arc::new(servers);
+    if config.server_addr.len() > 1 {
+        info!("服务器池: {} 台服务器，策略 {}", config.server_addr.len(), config.strategy);
+        if let Some(target) = &config.latency_probe {
+            info!("经各服务器连接 {} 测量延迟", target);
+        }
+        tokio::spawn(servers.clone().run_health_checks());
+    }
+    if let Some(idle_pool) = config.idle_pool() {
+        info!("每台服务器保持 {} 个备用会话", idle_pool.min_idle);
+        for index in 0..servers.servers().len() {
+            let servers = servers.clone();
+            tokio::spawn(async move { servers.servers()[index].upstream().keep_idle_sessions().await });
+        }
+    }
+    
+    let auth = Arc::new(SocksAuth::from_entries(&config.socks_users)?);
+    let router = config.routing.build()?;
+    if !router.is_empty() || router.default_action() != Action::Proxy {
+        info!("已加载 {} 条路由规则，默认动作 {}", router.len(), router.default_action());
+    }
+    let dialer = Arc::new(Dialer::new(servers, router, metrics.clone()));
+    
+    if let Some(metrics_addr) = config.metrics_addr {
+        tokio::spawn(metrics::serve(metrics_addr, metrics));
+    }
     
-    let listener = TcpListener::bind(&args.socks_addr).await?;
-    info!("SOCKS5 代理客户端启动在 {}", args.socks_addr);
-    info!("连接到代理服务器: {}", args.server_addr);
+    let mut listeners = Vec::new();
+    for socks_addr in &config.socks_addr {
+        let listener = bind_listener(*socks_addr, &auth, config.require_auth).await?;
+        info!("SOCKS5 代理客户端启动在 {}", socks_addr);
+        listeners.push(tokio::spawn(run_socks_listener(listener, dialer.clone(), auth.clone())));
+    }
+    for server in &config.server_addr {
+        info!("代理服务器: {}", server);
+    }
+    
+    for http_addr in &config.http_addr {
+        let http_listener = bind_listener(*http_addr, &auth, config.require_auth).await?;
+        info!("HTTP 代理启动在 {}", http_addr);
+        listeners.push(tokio::spawn(run_http_listener(http_listener, dialer.clone(), auth.clone())));
+    }
+    
+    #[cfg(target_os = "linux")]
+    if let Some(transparent_addr) = config.transparent_addr {
+        let transparent_listener = transparent::bind(transparent_addr, config.transparent_mode)?;
+        info!("透明代理 ({:?}) 启动在 {}", config.transparent_mode, transparent_addr);
+        listeners.push(tokio::spawn(transparent::serve(transparent_listener, config.transparent_mode, dialer.clone())));
+    }
+
+    join_all(listeners).await;
+    Ok(())
+}
 
+/// 绑定 SOCKS5 或 HTTP 代理监听地址，并检查非回环地址是否配置了认证
+async fn bind_listener(addr: SocketAddr, auth: &SocksAuth, require_auth: bool) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("无法监听 {}", addr))?;
+    auth.check_listener(listener.local_addr()?, require_auth)?;
+    Ok(listener)
+}
+
+async fn run_socks_listener(listener: TcpListener, dialer: Arc<Dialer>, auth: Arc<SocksAuth>) {
     loop {
         match listener.accept().await {
             Ok((socket, addr)) => {
                 info!("新 SOCKS5 连接来自: {}", addr);
-                let crypto = crypto.clone();
-                let server_addr = args.server_addr.clone();
-                let token = args.token.clone();
+                let dialer = dialer.clone();
+                let auth = auth.clone();
                 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_socks_connection(socket, server_addr, token, crypto).await {
+                    let _session = dialer.metrics().track_session();
+                    if let Err(e) = handle_socks_connection(socket, dialer, auth).await {
                         error!("处理 SOCKS5 连接时出错: {}", e);
                     }
                 });
@@ -74,303 +315,181 @@ async fn main() -> Result<()> {
     }
 }
 
+async fn run_http_listener(listener: TcpListener, dialer: Arc<Dialer>, auth: Arc<SocksAuth>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                info!("新 HTTP 代理连接来自: {}", addr);
+                let dialer = dialer.clone();
+                let auth = auth.clone();
+                
+                tokio::spawn(async move {
+                    let _session = dialer.metrics().track_session();
+                    if let Err(e) = http::handle_http_connection(socket, dialer, auth).await {
+                        error!("处理 HTTP 代理连接时出错: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("接受 HTTP 代理连接时出错: {}", e);
+            }
+        }
+    }
+}
+
 async fn handle_socks_connection(
     mut client: TcpStream,
-    server_addr: String,
-    token: String,
-    crypto: CryptoManager,
+    dialer: Arc<Dialer>,
+    auth: Arc<SocksAuth>,
 ) -> Result<()> {
-    // 处理 SOCKS5 握手
-    handle_socks_handshake(&mut client).await?;
-    
-    // 处理 SOCKS5 请求
-    let target_addr = handle_socks_request(&mut client).await?;
-    
-    // 连接到代理服务器
-    let mut server = TcpStream::connect(&server_addr).await?;
+    // 处理 SOCKS5 握手，启用认证时得到用户名
+    let user = socks5::handshake(&mut client, &auth).await?;
     
-    // 与代理服务器进行握手认证
-    perform_server_handshake(&mut server, &token, &crypto).await?;
-    
-    // 发送代理请求
-    send_proxy_request(&mut server, target_addr, &crypto).await?;
-    
-    // 接收代理响应
-    let response = receive_proxy_response(&mut server, &crypto).await?;
+    // 处理 SOCKS5 请求，域名交给服务器解析，本地不做 DNS 查询
+    let socks5::SocksRequest { command, target_addr } = socks5::read_request(&mut client).await?;
+    info!("目标地址: {}", target_addr);
+    let request = ProxyRequest {
+        target_addr,
+        command,
+        user,
+    };
     
-    if response.success {
-        // 发送 SOCKS5 成功响应
-        send_socks_success_response(&mut client).await?;
-        
-        // 开始转发数据
-        forward_data(client, server, crypto).await?;
-    } else {
-        // 发送 SOCKS5 失败响应
-        send_socks_failure_response(&mut client).await?;
-        return Err(anyhow!("代理服务器连接失败: {}", response.message));
+    // UDP 关联和 BIND 总是经过代理服务器，路由规则只用于 CONNECT
+    match command {
+        ProxyCommand::Connect => {}
+        ProxyCommand::UdpAssociate | ProxyCommand::Bind => {
+            // 获取到代理服务器的多路复用会话（必要时重新连接并认证）
+            let session = dialer.session(&request.target_addr).await?;
+            return match command {
+                ProxyCommand::UdpAssociate => handle_udp_associate(client, &session, &request).await,
+                _ => handle_bind(client, &session, &request).await,
+            };
+        }
     }
     
-    Ok(())
+    // 按路由规则经代理服务器、直连或拒绝
+    match dialer.connect(&request).await {
+        Ok(Outbound::Rejected) => {
+            socks5::send_failure(&mut client, Reply::ConnectionNotAllowed).await?;
+            info!("拒绝连接 {}", request.target_addr);
+            Ok(())
+        }
+        Ok(outbound) => {
+            // 发送 SOCKS5 成功响应，开始转发数据
+            socks5::send_reply(&mut client, Reply::Succeeded, &socks5::unspecified_addr()).await?;
+            outbound.relay(client, &request.target_addr).await
+        }
+        Err(e) => {
+            // 发送 SOCKS5 失败响应
+            socks5::send_failure(&mut client, Reply::GeneralFailure).await?;
+            Err(e)
+        }
+    }
 }
 
-async fn handle_socks_handshake(client: &mut TcpStream) -> Result<()> {
-    let mut buf = [0u8; 2];
-    client.read_exact(&mut buf).await?;
-    
-    let version = buf[0];
-    let nmethods = buf[1];
-    
-    if version != SOCKS_VERSION {
-        return Err(anyhow!("不支持的SOCKS版本: {}", version));
-    }
+async fn handle_udp_associate(
+    mut client: TcpStream,
+    session: &Arc<MuxSession>,
+    request: &ProxyRequest,
+) -> Result<()> {
+    // 在接受 SOCKS5 连接的同一地址上为客户端绑定 UDP 套接字
+    let socket = UdpSocket::bind((client.local_addr()?.ip(), 0)).await?;
     
-    let mut methods = vec![0u8; nmethods as usize];
-    client.read_exact(&mut methods).await?;
+    let mut stream = send_proxy_request(session, request).await?;
+    let response = receive_proxy_response(&mut stream).await?;
     
-    if !methods.contains(&NO_AUTHENTICATION) {
-        let response = [SOCKS_VERSION, 0xFF];
-        client.write_all(&response).await?;
-        return Err(anyhow!("客户端不支持无认证方法"));
+    if !response.success {
+        socks5::send_failure(&mut client, Reply::GeneralFailure).await?;
+        return Err(anyhow!("代理服务器建立 UDP 关联失败: {}", response.message));
     }
     
-    let response = [SOCKS_VERSION, NO_AUTHENTICATION];
-    client.write_all(&response).await?;
+    let bound_addr = socket.local_addr()?;
+    socks5::send_reply(&mut client, Reply::Succeeded, &bound_addr.into()).await?;
     
-    info!("SOCKS5 握手成功");
-    Ok(())
+    info!("流 {} 建立 UDP 关联，中继地址: {}", stream.id(), bound_addr);
+    udp::relay_udp(client, socket, stream, &request.target_addr).await
 }
 
-async fn handle_socks_request(client: &mut TcpStream) -> Result<SocketAddr> {
-    let mut buf = [0u8; 4];
-    client.read_exact(&mut buf).await?;
+async fn handle_bind(
+    mut client: TcpStream,
+    session: &Arc<MuxSession>,
+    request: &ProxyRequest,
+) -> Result<()> {
+    let mut stream = send_proxy_request(session, request).await?;
     
-    let version = buf[0];
-    let command = buf[1];
-    let _reserved = buf[2];
-    let address_type = buf[3];
+    // 第一次响应：服务器上的监听地址
+    let bound_addr = receive_bind_response(&mut client, &mut stream).await?;
+    info!("流 {} 在服务器 {} 上等待入站连接", stream.id(), bound_addr);
     
-    if version != SOCKS_VERSION {
-        return Err(anyhow!("不支持的SOCKS版本: {}", version));
-    }
-    
-    if command != CONNECT_COMMAND {
-        return Err(anyhow!("不支持的命令: {}", command));
-    }
+    // 第二次响应：入站连接的对端地址
+    let peer_addr = receive_bind_response(&mut client, &mut stream).await?;
+    info!("流 {} 接受来自 {} 的入站连接", stream.id(), peer_addr);
     
-    let target_addr = match address_type {
-        IPV4_ADDRESS => {
-            let mut addr_buf = [0u8; 4];
-            client.read_exact(&mut addr_buf).await?;
-            let ip = Ipv4Addr::new(addr_buf[0], addr_buf[1], addr_buf[2], addr_buf[3]);
-            
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-            
-            SocketAddr::from((ip, port))
-        }
-        DOMAIN_NAME => {
-            let mut len_buf = [0u8; 1];
-            client.read_exact(&mut len_buf).await?;
-            let domain_len = len_buf[0] as usize;
-            
-            let mut domain_buf = vec![0u8; domain_len];
-            client.read_exact(&mut domain_buf).await?;
-            let domain = String::from_utf8(domain_buf)?;
-            
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-            
-            info!("连接到域名: {}:{}", domain, port);
-            
-            let addrs = tokio::net::lookup_host(format!("{}:{}", domain, port)).await?;
-            addrs.into_iter().next().ok_or_else(|| anyhow!("无法解析域名: {}", domain))?
+    let metrics = stream.metrics().clone();
+    stream.relay(client, &*metrics).await
+}
+
+/// 接收一次 BIND 答复并转成 SOCKS5 响应
+async fn receive_bind_response(client: &mut TcpStream, stream: &mut MuxStream) -> Result<TargetAddr> {
+    let response = receive_proxy_response(stream).await?;
+    
+    match response.bound_addr {
+        Some(addr) if response.success => {
+            socks5::send_reply(client, Reply::Succeeded, &addr).await?;
+            Ok(addr)
         }
-        IPV6_ADDRESS => {
-            let mut addr_buf = [0u8; 16];
-            client.read_exact(&mut addr_buf).await?;
-            let ip = Ipv6Addr::from(addr_buf);
-            
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-            
-            SocketAddr::from((ip, port))
+        _ => {
+            socks5::send_failure(client, Reply::GeneralFailure).await?;
+            Err(anyhow!("代理服务器 BIND 失败: {}", response.message))
         }
-        _ => return Err(anyhow!("不支持的地址类型: {}", address_type)),
-    };
-    
-    info!("目标地址: {}", target_addr);
-    Ok(target_addr)
+    }
 }
 
-async fn perform_server_handshake(
-    server: &mut TcpStream,
+pub(crate) async fn perform_server_handshake(
+    server: &mut Framed<TcpStream, FrameCodec>,
     token: &str,
-    crypto: &CryptoManager,
+    user: Option<&str>,
 ) -> Result<()> {
     let handshake = HandshakeRequest {
-        token: token.to_string(),
         client_id: uuid::Uuid::new_v4().to_string(),
+        user: user.map(str::to_string),
+        timestamp: replay::unix_timestamp(),
     };
     
+    // 发送握手请求
     let handshake_data = serde_json::to_vec(&handshake)?;
-    let encrypted_data = crypto.encrypt(&handshake_data)?;
+    server.send(handshake_data.as_slice()).await?;
     
-    // 发送握手请求
-    let length = (encrypted_data.len() as u32).to_be_bytes();
-    server.write_all(&length).await?;
-    server.write_all(&encrypted_data).await?;
+    // 用 token 应答服务器的质询，token 本身不发送
+    let challenge_data = server.next().await.ok_or_else(|| anyhow!("服务器关闭了连接"))??;
+    let challenge: AuthChallenge = serde_json::from_slice(&challenge_data)?;
+    let proof = challenge.prove(token, &handshake.client_id, handshake.timestamp);
+    let proof_data = serde_json::to_vec(&proof)?;
+    server.send(proof_data.as_slice()).await?;
     
     // 接收握手响应
-    let mut length_buf = [0u8; 4];
-    server.read_exact(&mut length_buf).await?;
-    let length = u32::from_be_bytes(length_buf) as usize;
-    
-    let mut response_buf = vec![0u8; length];
-    server.read_exact(&mut response_buf).await?;
-    
-    let decrypted_data = crypto.decrypt(&response_buf)?;
+    let decrypted_data = server.next().await.ok_or_else(|| anyhow!("服务器关闭了连接"))??;
     let response: HandshakeResponse = serde_json::from_slice(&decrypted_data)?;
     
     if !response.success {
         return Err(anyhow!("服务器握手失败: {}", response.message));
     }
     
-    info!("服务器握手成功");
+    debug!("服务器握手成功");
     Ok(())
 }
 
-async fn send_proxy_request(
-    server: &mut TcpStream,
-    target_addr: SocketAddr,
-    crypto: &CryptoManager,
-) -> Result<()> {
-    let request = ProxyRequest {
-        target_addr: target_addr.to_string(),
-    };
-    
-    let request_data = serde_json::to_vec(&request)?;
-    let encrypted_data = crypto.encrypt(&request_data)?;
-    
-    let length = (encrypted_data.len() as u32).to_be_bytes();
-    server.write_all(&length).await?;
-    server.write_all(&encrypted_data).await?;
-    
-    Ok(())
+pub(crate) async fn send_proxy_request(
+    session: &Arc<MuxSession>,
+    request: &ProxyRequest,
+) -> Result<MuxStream> {
+    session.open_stream(request).await
 }
 
-async fn receive_proxy_response(
-    server: &mut TcpStream,
-    crypto: &CryptoManager,
-) -> Result<ProxyResponse> {
-    let mut length_buf = [0u8; 4];
-    server.read_exact(&mut length_buf).await?;
-    let length = u32::from_be_bytes(length_buf) as usize;
-    
-    let mut response_buf = vec![0u8; length];
-    server.read_exact(&mut response_buf).await?;
-    
-    let decrypted_data = crypto.decrypt(&response_buf)?;
-    let response: ProxyResponse = serde_json::from_slice(&decrypted_data)?;
+pub(crate) async fn receive_proxy_response(stream: &mut MuxStream) -> Result<ProxyResponse> {
+    let response_data = stream.recv_reply().await?;
+    let response: ProxyResponse = serde_json::from_slice(&response_data)?;
+    stream.record_connect(response.success);
     
     Ok(response)
 }
-
-async fn send_socks_success_response(client: &mut TcpStream) -> Result<()> {
-    let response = [
-        SOCKS_VERSION,  // 版本
-        0x00,           // 状态码 (成功)
-        0x00,           // 保留字段
-        0x01,           // 地址类型 (IPv4)
-        0x00, 0x00, 0x00, 0x00,  // IP地址 (0.0.0.0)
-        0x00, 0x00,     // 端口 (0)
-    ];
-    
-    client.write_all(&response).await?;
-    Ok(())
-}
-
-async fn send_socks_failure_response(client: &mut TcpStream) -> Result<()> {
-    let response = [
-        SOCKS_VERSION,  // 版本
-        0x01,           // 状态码 (失败)
-        0x00,           // 保留字段
-        0x01,           // 地址类型 (IPv4)
-        0x00, 0x00, 0x00, 0x00,  // IP地址 (0.0.0.0)
-        0x00, 0x00,     // 端口 (0)
-    ];
-    
-    client.write_all(&response).await?;
-    Ok(())
-}
-
-async fn forward_data(
-    mut client: TcpStream,
-    mut server: TcpStream,
-    crypto: CryptoManager,
-) -> Result<()> {
-    let (mut client_read, mut client_write) = client.split();
-    let (mut server_read, mut server_write) = server.split();
-    
-    let client_to_server = async {
-        let mut buf = [0u8; 8192];
-        loop {
-            let n = match client_read.read(&mut buf).await {
-                Ok(n) if n == 0 => break,
-                Ok(n) => n,
-                Err(_) => break,
-            };
-            
-            // 加密数据
-            match crypto.encrypt(&buf[..n]) {
-                Ok(encrypted) => {
-                    let length = (encrypted.len() as u32).to_be_bytes();
-                    if server_write.write_all(&length).await.is_err() {
-                        break;
-                    }
-                    if server_write.write_all(&encrypted).await.is_err() {
-                        break;
-                    }
-                }
-                Err(_) => break,
-            }
-        }
-    };
-    
-    let server_to_client = async {
-        let mut buf = [0u8; 8192];
-        loop {
-            // 读取长度
-            let mut length_buf = [0u8; 4];
-            if server_read.read_exact(&mut length_buf).await.is_err() {
-                break;
-            }
-            let length = u32::from_be_bytes(length_buf) as usize;
-            
-            // 读取加密数据
-            let mut encrypted_buf = vec![0u8; length];
-            if server_read.read_exact(&mut encrypted_buf).await.is_err() {
-                break;
-            }
-            
-            // 解密数据
-            match crypto.decrypt(&encrypted_buf) {
-                Ok(decrypted) => {
-                    if client_write.write_all(&decrypted).await.is_err() {
-                        break;
-                    }
-                }
-                Err(_) => break,
-            }
-        }
-    };
-    
-    tokio::select! {
-        _ = client_to_server => info!("客户端到服务器的数据传输完成"),
-        _ = server_to_client => info!("服务器到客户端的数据传输完成"),
-    }
-    
-    Ok(())
-} 
\ No newline at end of file
@@ -0,0 +1,352 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{net::TcpStream, sync::Notify};
+use tokio_util::codec::Framed;
+
+use leaf_protocol::codec::{self, FrameCodec, Role};
+use leaf_protocol::metrics::Metrics;
+use leaf_protocol::mux::{self, MuxSession};
+use leaf_protocol::noise;
+use leaf_protocol::pool::{Upstream, CONNECT_TIMEOUT};
+use leaf_protocol::protocol::{MuxFrame, ProxyCommand, ProxyRequest, TargetAddr};
+
+/// 到服务器的连接空闲多久后开始发送 TCP keepalive，及时发现半开的空闲连接
+const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+
+/// keepalive 探测的间隔
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 检查和补充备用会话的间隔
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 备用会话的数量和寿命
+///
+/// 所有流都走当前会话，备用会话不承载流量，只在当前会话断开后换用，省去重连时的连接和握手
+#[derive(Debug, Clone, Copy)]
+pub struct IdlePool {
+    /// 保持的备用会话数
+    pub min_idle: usize,
+    /// 备用会话数的上限，超过时关闭最旧的会话
+    pub max_idle: usize,
+    /// 备用会话的最长寿命，超过后关闭并重新建立
+    pub max_age: Duration,
+}
+
+/// 一个已认证、等待换用的备用会话
+struct IdleSession {
+    session: Arc<MuxSession>,
+    connected_at: tokio::time::Instant,
+}
+
+/// 维护到代理服务器的单个持久多路复用连接
+/// 可以保持备用会话，当前会话断开时直接换用，不必等待握手
+pub struct MuxClient {
+    server_addr: String,
+    token: String,
+    user: Option<String>,
+    codec: FrameCodec,
+    server_public_key: Option<[u8; noise::KEY_LEN]>,
+    session: tokio::sync::Mutex<Option<Arc<MuxSession>>>,
+    /// 最近建立的会话，统计活跃流数时不需要等待连接锁
+    latest: Mutex<Weak<MuxSession>>,
+    idle_pool: Option<IdlePool>,
+    idle: Mutex<VecDeque<IdleSession>>,
+    /// 取走备用会话后通知维护任务立即补充
+    idle_taken: Notify,
+    metrics: Arc<Metrics>,
+}
+
+impl MuxClient {
+    pub fn new(server_addr: String, token: String, codec: FrameCodec) -> Self {
+        Self {
+            server_addr,
+            token,
+            user: None,
+            codec,
+            server_public_key: None,
+            session: tokio::sync::Mutex::new(None),
+            latest: Mutex::new(Weak::new()),
+            idle_pool: None,
+            idle: Mutex::new(VecDeque::new()),
+            idle_taken: Notify::new(),
+            metrics: Arc::new(Metrics::new("proxy-client")),
+        }
+    }
+
+    /// 使用共享的指标，服务器池中的所有服务器计入同一组指标
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 以服务器用户数据库中的用户身份认证
+    pub fn user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// 设置固定的服务器 Noise 公钥，之后每个连接先完成 Noise IK 握手
+    pub fn server_public_key(mut self, key: Option<[u8; noise::KEY_LEN]>) -> Self {
+        self.server_public_key = key;
+        self
+    }
+
+    /// 保持备用会话，由 [`MuxClient::keep_idle_sessions`] 维护
+    pub fn idle_pool(mut self, idle_pool: Option<IdlePool>) -> Self {
+        self.idle_pool = idle_pool;
+        self
+    }
+
+    /// 返回当前会话；连接已断开时重新连接并认证
+    pub async fn session(&self) -> Result<Arc<MuxSession>> {
+        let mut current = self.session.lock().await;
+        if let Some(session) = current.as_ref()
+            && !session.is_closed()
+        {
+            return Ok(session.clone());
+        }
+
+        let session = match self.take_idle() {
+            Some(session) => {
+                info!("改用到代理服务器 {} 的备用会话", self.server_addr);
+                session
+            }
+            None => {
+                let session = self.connect().await?;
+                info!("已建立到代理服务器 {} 的多路复用会话", self.server_addr);
+                session
+            }
+        };
+        *current = Some(session.clone());
+        *self.latest.lock().unwrap() = Arc::downgrade(&session);
+        Ok(session)
+    }
+
+    /// 取出最新的可用备用会话，丢弃遇到的已断开或过期的会话
+    fn take_idle(&self) -> Option<Arc<MuxSession>> {
+        let idle_pool = self.idle_pool?;
+        let mut idle = self.idle.lock().unwrap();
+        let mut taken = None;
+        while let Some(entry) = idle.pop_back() {
+            if !entry.session.is_closed() && entry.connected_at.elapsed() < idle_pool.max_age {
+                taken = Some(entry.session);
+                break;
+            }
+            entry.session.close();
+        }
+        drop(idle);
+        self.idle_taken.notify_one();
+        taken
+    }
+
+    /// 关闭已断开或超过寿命的备用会话，超过 `max_idle` 时关闭最旧的
+    fn prune_idle(&self, idle_pool: IdlePool) {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|entry| {
+            let stale = entry.session.is_closed() || entry.connected_at.elapsed() >= idle_pool.max_age;
+            if stale {
+                debug!("丢弃到代理服务器 {} 的备用会话", self.server_addr);
+                entry.session.close();
+            }
+            !stale
+        });
+        while idle.len() > idle_pool.max_idle {
+            if let Some(entry) = idle.pop_front() {
+                entry.session.close();
+            }
+        }
+    }
+
+    /// 维护备用会话：丢弃失效的会话并补足到 `min_idle`
+    /// 没有配置备用会话时立即返回
+    pub async fn keep_idle_sessions(&self) {
+        let Some(idle_pool) = self.idle_pool else {
+            return;
+        };
+
+        loop {
+            self.prune_idle(idle_pool);
+
+            while self.idle.lock().unwrap().len() < idle_pool.min_idle {
+                match self.connect().await {
+                    Ok(session) => {
+                        debug!("预先建立了到代理服务器 {} 的备用会话", self.server_addr);
+                        self.idle.lock().unwrap().push_back(IdleSession {
+                            session,
+                            connected_at: tokio::time::Instant::now(),
+                        });
+                    }
+                    // 服务器不可用时由健康检查和实际请求报告，这里只在下一轮重试
+                    Err(e) => {
+                        debug!("预先连接代理服务器 {} 失败: {:#}", self.server_addr, e);
+                        break;
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(IDLE_CHECK_INTERVAL) => {}
+                _ = self.idle_taken.notified() => {}
+            }
+        }
+    }
+
+    /// 连接服务器并完成 Noise 握手 (如果启用) 和认证
+    /// 整个过程不超过 [`CONNECT_TIMEOUT`]，`session()` 持有连接锁时不会无限等待
+    async fn handshake(&self) -> Result<Framed<TcpStream, FrameCodec>> {
+        match tokio::time::timeout(CONNECT_TIMEOUT, self.open_connection()).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.metrics.handshake_failed("timeout");
+                Err(anyhow!("连接代理服务器 {} 超时", self.server_addr))
+            }
+        }
+    }
+
+    async fn open_connection(&self) -> Result<Framed<TcpStream, FrameCodec>> {
+        let mut server = match TcpStream::connect(&self.server_addr).await {
+            Ok(server) => server,
+            Err(e) => {
+                self.metrics.handshake_failed("connect");
+                return Err(e.into());
+            }
+        };
+        let keepalive = TcpKeepalive::new().with_time(KEEPALIVE_TIME).with_interval(KEEPALIVE_INTERVAL);
+        if let Err(e) = SockRef::from(&server).set_tcp_keepalive(&keepalive) {
+            debug!("设置 TCP keepalive 失败: {}", e);
+        }
+        let codec = match &self.server_public_key {
+            Some(key) => match noise::initiate(&mut server, self.codec.crypto(), key).await {
+                Ok(keys) => self.codec.clone().transport_keys(keys),
+                Err(e) => {
+                    self.metrics.handshake_failed("noise");
+                    return Err(e);
+                }
+            },
+            None => self.codec.clone(),
+        };
+        let mut framed = Framed::new(server, codec);
+        if let Err(e) = crate::perform_server_handshake(&mut framed, &self.token, self.user.as_deref()).await {
+            self.metrics.handshake_error(&e);
+            return Err(e);
+        }
+        self.metrics.handshake_succeeded(None);
+        Ok(framed)
+    }
+
+    async fn connect(&self) -> Result<Arc<MuxSession>> {
+        let framed = self.handshake().await?;
+        let (reader, writer) = codec::into_split(framed);
+        let session = MuxSession::new(writer, Role::Client, self.metrics.clone());
+        let accept = |open: MuxFrame| warn!("忽略服务器发起的流 {}", open.stream_id);
+        let reader_session = session.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mux::read_frames(reader, reader_session, accept).await {
+                info!("多路复用会话结束: {}", e);
+            }
+        });
+        Ok(session)
+    }
+}
+
+impl Upstream for MuxClient {
+    type Session = Arc<MuxSession>;
+
+    async fn session(&self) -> Result<Arc<MuxSession>> {
+        MuxClient::session(self).await
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.handshake().await.map(drop)
+    }
+
+    async fn probe_target(&self, target: &TargetAddr) -> Result<()> {
+        let session = MuxClient::session(self).await?;
+        let request = ProxyRequest {
+            target_addr: target.clone(),
+            command: ProxyCommand::Connect,
+            user: None,
+        };
+        let mut stream = crate::send_proxy_request(&session, &request).await?;
+        let response = crate::receive_proxy_response(&mut stream).await?;
+        if !response.success {
+            return Err(anyhow!("{}", response.message));
+        }
+        Ok(())
+    }
+
+    fn active_streams(&self) -> usize {
+        self.latest.lock().unwrap().upgrade().map_or(0, |session| session.stream_count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leaf_protocol::CryptoManager;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::FramedWrite;
+
+    fn codec() -> FrameCodec {
+        FrameCodec::new(CryptoManager::new(&CryptoManager::generate_key()).unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unresponsive_server_times_out() {
+        // 监听但从不应答，连接建立后握手一直等不到回复
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = MuxClient::new(listener.local_addr().unwrap().to_string(), "token".to_string(), codec());
+
+        let started = tokio::time::Instant::now();
+        let error = client.session().await.err().unwrap();
+        assert!(error.to_string().contains("超时"), "{:#}", error);
+        assert!(started.elapsed() >= CONNECT_TIMEOUT);
+        drop(listener);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_sessions() {
+        let idle_pool = IdlePool {
+            min_idle: 1,
+            max_idle: 2,
+            max_age: Duration::from_secs(60),
+        };
+        // 服务器地址不可达，换出的只能是备用会话
+        let client = MuxClient::new("127.0.0.1:1".to_string(), "token".to_string(), codec()).idle_pool(Some(idle_pool));
+        let standby = || {
+            let session = MuxSession::new(FramedWrite::new(tokio::io::sink(), codec()), Role::Client, client.metrics.clone());
+            client.idle.lock().unwrap().push_back(IdleSession {
+                session: session.clone(),
+                connected_at: tokio::time::Instant::now(),
+            });
+            session
+        };
+
+        // 换用最新的可用会话，遇到的已断开会话被丢弃
+        let old = standby();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let warm = standby();
+        standby().close();
+        assert!(Arc::ptr_eq(&client.session().await.unwrap(), &warm));
+        assert_eq!(client.idle.lock().unwrap().len(), 1);
+
+        // 超过寿命的会话不会被换出，并被关闭
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(client.take_idle().is_none());
+        assert!(old.is_closed());
+
+        // 维护时超过 max_idle 的最旧会话被关闭，过期后全部关闭
+        let sessions: Vec<_> = (0..3).map(|_| standby()).collect();
+        client.prune_idle(idle_pool);
+        assert_eq!(client.idle.lock().unwrap().len(), 2);
+        assert!(sessions[0].is_closed() && !sessions[1].is_closed());
+        tokio::time::advance(idle_pool.max_age).await;
+        client.prune_idle(idle_pool);
+        assert!(client.idle.lock().unwrap().is_empty());
+        assert!(sessions.iter().all(|session| session.is_closed()));
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use leaf_protocol::metrics::Metrics;
+use leaf_protocol::pool::ServerPool;
+use leaf_protocol::routing::{Action, Router};
+use leaf_protocol::{ProxyRequest, TargetAddr};
+
+use leaf_protocol::mux::{MuxSession, MuxStream};
+
+use crate::mux::MuxClient;
+
+/// 按路由规则为 CONNECT 请求建立出站连接，所有入站监听器共享
+pub struct Dialer {
+    servers: Arc<ServerPool<MuxClient>>,
+    router: Router,
+    metrics: Arc<Metrics>,
+}
+
+/// 按路由规则为一个 CONNECT 请求建立的出站连接
+pub enum Outbound {
+    /// 经代理服务器的流，目标已经连接成功
+    Proxy(MuxStream),
+    /// 客户端直接连到目标的连接
+    Direct(TcpStream),
+    /// 被规则拒绝
+    Rejected,
+}
+
+impl Dialer {
+    pub fn new(servers: Arc<ServerPool<MuxClient>>, router: Router, metrics: Arc<Metrics>) -> Self {
+        Self {
+            servers,
+            router,
+            metrics,
+        }
+    }
+
+    /// 客户端的指标，所有入站监听共享
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// 按服务器选择策略取得到代理服务器的多路复用会话 (必要时重新连接并认证)
+    /// 首选的服务器不可用时换用池中的其他服务器
+    pub async fn session(&self, target_addr: &TargetAddr) -> Result<Arc<MuxSession>> {
+        let (server, session) = self.servers.session(&target_addr.host()).await?;
+        if self.servers.servers().len() > 1 {
+            info!("{} 经服务器 {}", target_addr, server);
+        }
+        Ok(session)
+    }
+
+    /// 按路由规则连接请求的目标；代理服务器或目标连接失败时返回错误
+    pub async fn connect(&self, request: &ProxyRequest) -> Result<Outbound> {
+        let target_addr = &request.target_addr;
+        let (action, rule) = self.router.route(target_addr, request.user.as_deref());
+        match rule {
+            Some(rule) => info!("{} 命中规则 {}", target_addr, rule),
+            None if action != Action::Proxy => info!("{} 未命中规则，使用默认动作 {}", target_addr, action),
+            None => {}
+        }
+
+        match action {
+            Action::Proxy => {
+                let session = self.session(target_addr).await?;
+                let mut stream = crate::send_proxy_request(&session, request).await?;
+                let response = crate::receive_proxy_response(&mut stream).await?;
+                if !response.success {
+                    return Err(anyhow!("代理服务器连接失败: {}", response.message));
+                }
+                Ok(Outbound::Proxy(stream))
+            }
+            Action::Direct => {
+                let target = connect_direct(target_addr)
+                    .await
+                    .with_context(|| format!("直连 {} 失败", target_addr))?;
+                Ok(Outbound::Direct(target))
+            }
+            Action::Reject => Ok(Outbound::Rejected),
+        }
+    }
+}
+
+impl Outbound {
+    /// 在本地连接和出站连接之间转发数据，直到任一方向关闭
+    pub async fn relay<S>(self, mut socket: S, target_addr: &TargetAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            Outbound::Proxy(stream) => {
+                info!("流 {} 开始转发到 {}", stream.id(), target_addr);
+                let metrics = stream.metrics().clone();
+                stream.relay(socket, &*metrics).await
+            }
+            Outbound::Direct(mut target) => {
+                info!("开始直连转发到 {}", target_addr);
+                tokio::io::copy_bidirectional(&mut socket, &mut target).await?;
+                Ok(())
+            }
+            Outbound::Rejected => Err(anyhow!("连接 {} 已被路由规则拒绝", target_addr)),
+        }
+    }
+}
+
+/// 在本地连接目标；直连的域名由本机解析
+async fn connect_direct(target_addr: &TargetAddr) -> std::io::Result<TcpStream> {
+    match target_addr {
+        TargetAddr::Ipv4(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Ipv6(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Domain(domain, port) => TcpStream::connect((domain.as_str(), *port)).await,
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+use leaf_protocol::{ProxyCommand, ProxyRequest};
+
+use crate::outbound::Dialer;
+
+/// 透明代理模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransparentMode {
+    /// iptables/nftables REDIRECT，原始目标通过 SO_ORIGINAL_DST 取得
+    Redirect,
+    /// iptables/nftables TPROXY，连接的本地地址就是原始目标
+    Tproxy,
+}
+
+/// 绑定透明代理监听器；TPROXY 模式需要 CAP_NET_ADMIN 以设置 IP_TRANSPARENT
+pub fn bind(listen_addr: SocketAddr, mode: TransparentMode) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(listen_addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+
+    if mode == TransparentMode::Tproxy {
+        // Linux 上 IP_TRANSPARENT 对 IPv4 和 IPv6 套接字都有效
+        socket
+            .set_ip_transparent(true)
+            .map_err(|e| anyhow!("设置 IP_TRANSPARENT 失败（需要 CAP_NET_ADMIN）: {}", e))?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&listen_addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// 接受被重定向的连接，并按路由规则转发到原始目标
+pub async fn serve(listener: TcpListener, mode: TransparentMode, dialer: Arc<Dialer>) {
+    let listen_addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("获取透明代理监听地址失败: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let dialer = dialer.clone();
+
+                tokio::spawn(async move {
+                    let _session = dialer.metrics().track_session();
+                    if let Err(e) =
+                        handle_transparent_connection(socket, addr, listen_addr, mode, dialer).await
+                    {
+                        error!("处理透明代理连接时出错: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("接受透明代理连接时出错: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_transparent_connection(
+    client: TcpStream,
+    peer_addr: SocketAddr,
+    listen_addr: SocketAddr,
+    mode: TransparentMode,
+    dialer: Arc<Dialer>,
+) -> Result<()> {
+    let target_addr = original_dst(&client, mode)?;
+
+    // 直接连到监听端口的连接没有经过重定向，转发它只会连回自己
+    if is_listener_addr(target_addr, listen_addr) {
+        return Err(anyhow!("来自 {} 的连接没有经过重定向", peer_addr));
+    }
+    info!("透明代理连接 {} -> {}", peer_addr, target_addr);
+
+    let request = ProxyRequest {
+        target_addr: target_addr.into(),
+        command: ProxyCommand::Connect,
+        user: None,
+    };
+
+    // 失败或被拒绝时直接关闭连接，应用会看到连接被重置
+    dialer
+        .connect(&request)
+        .await?
+        .relay(client, &request.target_addr)
+        .await
+}
+
+/// 监听在通配地址上时，任何本机地址加监听端口都指向监听器自身
+fn is_listener_addr(target_addr: SocketAddr, listen_addr: SocketAddr) -> bool {
+    target_addr.port() == listen_addr.port()
+        && (listen_addr.ip().is_unspecified() || target_addr.ip() == listen_addr.ip())
+}
+
+/// 取得连接被重定向之前的目标地址
+fn original_dst(stream: &TcpStream, mode: TransparentMode) -> Result<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+
+    let addr = match mode {
+        TransparentMode::Tproxy => return Ok(local_addr),
+        TransparentMode::Redirect if local_addr.is_ipv4() => SockRef::from(stream).original_dst(),
+        TransparentMode::Redirect => SockRef::from(stream).original_dst_ipv6(),
+    }
+    .map_err(|e| anyhow!("读取 SO_ORIGINAL_DST 失败: {}", e))?;
+
+    addr.as_socket().ok_or_else(|| anyhow!("无效的原始目标地址"))
+}
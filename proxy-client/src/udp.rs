@@ -0,0 +1,94 @@
+use anyhow::Result;
+use log::{debug, info};
+use std::net::SocketAddr;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream, UdpSocket},
+};
+
+use leaf_protocol::mux::MuxStream;
+use leaf_protocol::quota::Direction;
+use leaf_protocol::socks5::{encode_udp_response, parse_udp_request};
+use leaf_protocol::TargetAddr;
+
+/// UDP 数据报的最大长度
+const MAX_DATAGRAM: usize = 65535;
+
+/// 在 SOCKS5 客户端的 UDP 套接字和多路复用流之间转发数据报
+///
+/// 关联在控制连接关闭或服务器关闭流（例如空闲超时）时结束。
+/// 只接受来自控制连接对端 IP 的数据报，并锁定第一个发送数据报的端口
+pub async fn relay_udp(
+    mut control: TcpStream,
+    socket: UdpSocket,
+    mut stream: MuxStream,
+    client_hint: &TargetAddr,
+) -> Result<()> {
+    let peer_ip = control.peer_addr()?.ip();
+    let mut client_addr = expected_client_addr(client_hint);
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut control_buf = [0u8; 1];
+
+    loop {
+        tokio::select! {
+            // RFC 1928: 控制连接关闭时 UDP 关联随之结束
+            result = control.read(&mut control_buf) => match result {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            },
+            result = socket.recv_from(&mut buf) => {
+                let (n, src) = result?;
+                if src.ip() != peer_ip {
+                    debug!("丢弃来自 {} 的 UDP 数据报：不是控制连接的对端", src);
+                    continue;
+                }
+                match client_addr {
+                    Some(addr) if addr != src => {
+                        debug!("丢弃来自 {} 的 UDP 数据报：关联已绑定到 {}", src, addr);
+                        continue;
+                    }
+                    Some(_) => {}
+                    None => client_addr = Some(src),
+                }
+
+                match parse_udp_request(&buf[..n]) {
+                    Ok(payload) => {
+                        stream.metrics().relayed(None, Direction::Upload, payload.len());
+                        stream.send_datagram(payload.to_vec())?;
+                    }
+                    Err(e) => debug!("丢弃来自 {} 的 UDP 数据报: {}", src, e),
+                }
+            }
+            payload = stream.recv_datagram() => {
+                let Some(payload) = payload else {
+                    break;
+                };
+                stream.metrics().relayed(None, Direction::Download, payload.len());
+                let Some(addr) = client_addr else {
+                    continue;
+                };
+
+                socket.send_to(&encode_udp_response(&payload), addr).await?;
+            }
+        }
+    }
+
+    info!("流 {} 的 UDP 关联结束", stream.id());
+    Ok(())
+}
+
+/// UDP ASSOCIATE 请求中的 DST.ADDR/DST.PORT 是客户端将要使用的发送地址，
+/// 全零表示客户端尚不知道，此时锁定第一个数据报的来源
+fn expected_client_addr(hint: &TargetAddr) -> Option<SocketAddr> {
+    let addr = match hint {
+        TargetAddr::Ipv4(ip, port) => SocketAddr::from((*ip, *port)),
+        TargetAddr::Ipv6(ip, port) => SocketAddr::from((*ip, *port)),
+        TargetAddr::Domain(..) => return None,
+    };
+
+    if addr.ip().is_unspecified() || addr.port() == 0 {
+        None
+    } else {
+        Some(addr)
+    }
+}
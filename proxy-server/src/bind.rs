@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+
+use leaf_protocol::protocol::TargetAddr;
+
+/// 等待入站连接的超时
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// BIND 命令在出口侧的监听套接字
+/// 只接受一个来自目标地址的入站连接
+pub struct BindListener {
+    listener: TcpListener,
+    /// 允许的对端 IP，为空时接受任意对端
+    allowed_peers: Vec<IpAddr>,
+}
+
+impl BindListener {
+    /// 在通往目标地址的出口 IP 上监听随机端口，
+    /// 让目标看到的是它能够连回的地址，而不是 0.0.0.0
+    pub async fn bind(target_addr: &TargetAddr) -> Result<Self> {
+        let allowed_peers = resolve_peers(target_addr).await?;
+
+        let local_ip = match allowed_peers.first() {
+            Some(peer) => egress_ip(*peer).await,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let listener = TcpListener::bind((local_ip, 0)).await?;
+
+        Ok(Self {
+            listener,
+            allowed_peers,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// 等待目标发起的入站连接，忽略来自其他地址的连接
+    pub async fn accept(self) -> Result<(TcpStream, SocketAddr)> {
+        tokio::time::timeout(BIND_ACCEPT_TIMEOUT, async {
+            loop {
+                let (conn, peer) = self.listener.accept().await?;
+                if self.allowed_peers.is_empty() || self.allowed_peers.contains(&peer.ip()) {
+                    return Ok((conn, peer));
+                }
+                debug!("拒绝来自 {} 的入站连接：不是 BIND 请求的目标", peer);
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("等待入站连接超时"))?
+    }
+}
+
+/// 解析 BIND 请求中的目标地址；未指定的地址表示接受任意对端
+async fn resolve_peers(target_addr: &TargetAddr) -> Result<Vec<IpAddr>> {
+    let ips = match target_addr {
+        TargetAddr::Ipv4(ip, _) => vec![IpAddr::V4(*ip)],
+        TargetAddr::Ipv6(ip, _) => vec![IpAddr::V6(*ip)],
+        TargetAddr::Domain(domain, port) => lookup_host((domain.as_str(), *port))
+            .await?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+
+    Ok(ips.into_iter().filter(|ip| !ip.is_unspecified()).collect())
+}
+
+/// 通过未发送数据的 UDP connect 查询到达对端时使用的本地地址
+async fn egress_ip(peer: IpAddr) -> IpAddr {
+    let unspecified = match peer {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let probe = async {
+        let socket = UdpSocket::bind((unspecified, 0)).await?;
+        socket.connect((peer, 9)).await?;
+        socket.local_addr()
+    };
+
+    match probe.await {
+        Ok(addr) => addr.ip(),
+        Err(e) => {
+            debug!("无法确定到 {} 的出口地址: {}", peer, e);
+            unspecified
+        }
+    }
+}
@@ -1,47 +1,175 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use log::{error, info, warn};
-use std::collections::HashMap;
+use futures_util::future::join_all;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::RwLock,
-};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
 
-mod crypto;
-mod protocol;
+mod bind;
+mod config;
+mod udp;
 
-use crypto::CryptoManager;
-use protocol::{HandshakeRequest, HandshakeResponse, ProxyRequest, ProxyResponse};
+use bind::BindListener;
+use config::Config;
+use leaf_protocol::admin::{self, AdminAddr};
+use leaf_protocol::challenge::{AuthChallenge, AuthProof};
+use leaf_protocol::quota::QuotaManager;
+use leaf_protocol::reload::{self, ReloadSummary, Reloadable, Reloader};
+use leaf_protocol::sessions::{Session, SessionTable, StreamTraffic};
+use leaf_protocol::metrics::{self, Metrics};
+use leaf_protocol::users::{AuthError, Credentials, User, UserDb};
+use leaf_protocol::protocol::{HandshakeRequest, HandshakeResponse, MuxFrame};
+use leaf_protocol::codec::{self, FrameCodec, FrameError, Framing, Role, MAX_HANDSHAKE_FRAME_LEN};
+use leaf_protocol::mux::{self, MuxSession, MuxStream};
+use leaf_protocol::noise::{self, ServerKey};
+use leaf_protocol::replay::{self, ReplayCache};
+use leaf_protocol::throttle::TrialLimiter;
+use leaf_protocol::{CryptoManager, ProxyCommand, ProxyRequest, ProxyResponse, TargetAddr};
+use udp::UdpAssociation;
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(name = "proxy-server")]
 #[command(about = "Secure proxy server")]
 struct Args {
-    /// Server listen address
-    #[arg(short, long, default_value = "0.0.0.0:8080")]
-    listen_addr: Option<String>,
+    /// Configuration file (TOML); command-line flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Print the default configuration file and exit
+    #[arg(long)]
+    print_default_config: bool,
 
-    /// Authentication token
+    /// Server listen address, repeatable [default: 0.0.0.0:8080]
+    #[arg(short, long)]
+    listen_addr: Vec<SocketAddr>,
+
+    /// Authentication token for clients that do not name a user
     #[arg(short, long)]
     token: Option<String>,
 
+    /// User database (TOML) with per-user tokens, keys, enabled flags and expiry
+    #[arg(long)]
+    users: Option<PathBuf>,
+
+    /// File that keeps per-user traffic usage across restarts (JSON)
+    #[arg(long)]
+    usage_file: Option<PathBuf>,
+
     /// Encryption key (base64 encoded)
     #[arg(short, long)]
     key: Option<String>,
 
+    /// Maximum encrypted frame size in bytes; larger frames close the connection before allocation [default: 131072]
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+
+    /// Frame format: v2 (per-session keys, replay protection) or v1 (legacy clients) [default: v2]
+    #[arg(long)]
+    framing: Option<Framing>,
+
+    /// Noise static private key (base64); enables the forward-secret Noise IK handshake
+    #[arg(long)]
+    noise_key: Option<String>,
+
+    /// Admin API address: a loopback host:port or unix:/path/to/socket
+    #[arg(long)]
+    admin_addr: Option<AdminAddr>,
+
+    /// Bearer token required by the admin API
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// Prometheus metrics address; serves GET /metrics when set
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Terminate sessions whose user was removed, disabled or re-keyed when reloading
+    #[arg(long)]
+    evict_revoked_sessions: bool,
+
     /// Generate a new encryption key
     #[arg(long)]
     generate_key: bool,
+
+    /// Generate a Noise static keypair; clients pin the public key with --server-public-key
+    #[arg(long)]
+    generate_noise_key: bool,
+}
+
+impl Args {
+    /// 读取配置文件 (如果指定了) 并用命令行参数覆盖
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen_addr.is_empty() {
+            config.listen_addr = self.listen_addr;
+        }
+        config.token = self.token.or(config.token);
+        config.users = self.users.or(config.users);
+        config.usage_file = self.usage_file.or(config.usage_file);
+        config.key = self.key.or(config.key);
+        config.max_frame_size = self.max_frame_size.unwrap_or(config.max_frame_size);
+        config.framing = self.framing.unwrap_or(config.framing);
+        config.noise_key = self.noise_key.or(config.noise_key);
+        config.admin_addr = self.admin_addr.or(config.admin_addr);
+        config.admin_token = self.admin_token.or(config.admin_token);
+        config.metrics_addr = self.metrics_addr.or(config.metrics_addr);
+        config.evict_revoked_sessions |= self.evict_revoked_sessions;
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// 所有监听地址共享的服务器状态
+struct Server {
+    /// 启动时的命令行参数，重载时重新与配置文件合并
+    args: Args,
+    startup: Config,
+    state: Reloadable<ServerState>,
+    replay_cache: Arc<ReplayCache>,
+    /// 认证之前的试解密按来源计费，重载前后是同一个
+    trial_limiter: Arc<TrialLimiter>,
+    quota: Arc<QuotaManager>,
+    sessions: Arc<SessionTable>,
 }
 
-#[derive(Debug)]
-struct ClientSession {
-    client_id: String,
-    session_id: String,
-    connected_at: std::time::Instant,
+/// 重载时整体替换的部分，已经建立的连接继续使用旧的一份
+struct ServerState {
+    credentials: Arc<Credentials>,
+    codec: FrameCodec,
+    noise_key: Option<ServerKey>,
+}
+
+impl ServerState {
+    /// 按配置加载凭据和密钥，返回状态和用户数
+    fn build(config: &Config, replay_cache: &Arc<ReplayCache>) -> Result<(Self, usize)> {
+        let key = config.key.as_deref().expect("validate 已检查");
+        let users = config.users.as_ref().map(UserDb::load).transpose()?;
+        let user_count = users.as_ref().map_or(0, UserDb::len);
+        if users.is_some() {
+            info!("已加载 {} 个用户", user_count);
+        }
+        let credentials = Arc::new(Credentials::new(config.token.clone(), users)?);
+        let noise_key = config.noise_key.as_deref().map(ServerKey::from_base64).transpose()?;
+
+        // 初始化加密管理器
+        let crypto = CryptoManager::new(key)?;
+        // 所有连接共享同一个会话盐缓存，重载前后也是同一个；用户专用的密钥由客户端的第一帧选定
+        let codec = FrameCodec::new(crypto)
+            .alternate_keys(credentials.user_keys())
+            .max_frame_len(config.max_frame_size)
+            .framing(config.framing)
+            .role(Role::Server)
+            .replay_cache(replay_cache.clone());
+        Ok((Self { credentials, codec, noise_key }, user_count))
+    }
 }
 
 #[tokio::main]
@@ -55,36 +183,125 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    // 检查必需参数
-    let listen_addr = args.listen_addr.ok_or_else(|| anyhow!("缺少 --listen-addr 参数"))?;
-    let token = args.token.ok_or_else(|| anyhow!("缺少 --token 参数"))?;
-    let key = args.key.ok_or_else(|| anyhow!("缺少 --key 参数"))?;
+    if args.generate_noise_key {
+        let (private, public) = noise::generate_keypair()?;
+        println!("Noise 私钥 (--noise-key): {}", private);
+        println!("Noise 公钥 (客户端 --server-public-key): {}", public);
+        return Ok(());
+    }
 
-    // 初始化加密管理器
-    let crypto = CryptoManager::new(&key)?;
+    if args.print_default_config {
+        print!("{}", Config::default_toml()?);
+        return Ok(());
+    }
+
+    let config = args.clone().into_config()?;
+    let replay_cache = Arc::new(ReplayCache::new());
+    let (state, _) = ServerState::build(&config, &replay_cache)?;
+    let quota = Arc::new(QuotaManager::load(config.usage_file.clone())?);
+    tokio::spawn(quota.clone().persist_periodically());
     
     // 存储活跃的客户端会话
-    let sessions: Arc<RwLock<HashMap<String, ClientSession>>> = Arc::new(RwLock::new(HashMap::new()));
-    
-    let listener = TcpListener::bind(&listen_addr).await?;
-    info!("代理服务器启动在 {}", listen_addr);
-
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                info!("新连接来自: {}", addr);
-                let crypto = crypto.clone();
-                let sessions = sessions.clone();
-                let token = token.clone();
-                
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client_connection(socket, addr, token, crypto, sessions).await {
-                        error!("处理客户端连接时出错: {}", e);
-                    }
-                });
+    let metrics = Arc::new(Metrics::new("proxy-server"));
+    let sessions = Arc::new(SessionTable::new(metrics.clone()));
+    if let Some(metrics_addr) = config.metrics_addr {
+        tokio::spawn(metrics::serve(metrics_addr, metrics));
+    }
+    
+    let mut listeners = Vec::new();
+    for listen_addr in &config.listen_addr {
+        let listener = TcpListener::bind(listen_addr)
+            .await
+            .with_context(|| format!("无法监听 {}", listen_addr))?;
+        info!("代理服务器启动在 {}，帧格式 {}", listen_addr, config.framing);
+        listeners.push(listener);
+    }
+    if state.noise_key.is_some() {
+        info!("使用 Noise IK 握手，会话密钥前向安全");
+    }
+
+    let admin = config.admin_addr.clone().zip(config.admin_token.clone());
+    let server = Arc::new(Server {
+        args,
+        startup: config,
+        state: Reloadable::new(state),
+        replay_cache,
+        trial_limiter: Arc::new(TrialLimiter::new()),
+        quota,
+        sessions: sessions.clone(),
+    });
+
+    let reloader: Reloader = {
+        let server = server.clone();
+        Arc::new(move || server.reload())
+    };
+    tokio::spawn(reload::reload_on_sighup(reloader.clone()));
+    if let Some((admin_addr, admin_token)) = admin {
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin_addr, admin_token, sessions, reloader).await {
+                error!("管理接口出错: {:#}", e);
             }
-            Err(e) => {
-                error!("接受连接时出错: {}", e);
+        });
+    }
+
+    join_all(listeners.into_iter().map(|listener| server.clone().accept_loop(listener))).await;
+    Ok(())
+}
+
+impl Server {
+    /// 重新读取配置文件和用户数据库；出错时保持原来的状态
+    fn reload(&self) -> Result<ReloadSummary> {
+        let config = self.args.clone().into_config()?;
+        let (state, users) = ServerState::build(&config, &self.replay_cache)?;
+        let credentials = state.credentials.clone();
+        self.state.store(state);
+
+        let evicted = match config.evict_revoked_sessions {
+            true => self.sessions.terminate_revoked(&credentials),
+            false => 0,
+        };
+        Ok(ReloadSummary {
+            users,
+            evicted,
+            restart_required: self.startup.restart_required(&config),
+        })
+    }
+
+    async fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    // 不读取已经透支的来源发来的任何数据，它无法再让服务器逐个尝试用户密钥
+                    if !self.trial_limiter.allow(addr.ip()) {
+                        debug!("来源 {} 的试解密额度已用尽，关闭连接", addr);
+                        self.sessions.metrics().handshake_failed("throttled");
+                        continue;
+                    }
+
+                    info!("新连接来自: {}", addr);
+                    let state = self.state.load();
+                    let codec = state.codec.clone();
+                    let trials = codec.candidate_count();
+                    let sessions = self.sessions.clone();
+                    let credentials = state.credentials.clone();
+                    let noise_key = state.noise_key.clone();
+                    let quota = self.quota.clone();
+                    let trial_limiter = self.trial_limiter.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) =
+                            handle_client_connection(socket, addr, credentials, quota, codec, noise_key, sessions).await
+                        {
+                            if matches!(e.downcast_ref::<FrameError>(), Some(FrameError::Decrypt)) {
+                                trial_limiter.charge(addr.ip(), trials);
+                            }
+                            error!("处理客户端连接时出错: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("接受连接时出错: {}", e);
+                }
             }
         }
     }
@@ -93,93 +310,270 @@ async fn main() -> Result<()> {
 async fn handle_client_connection(
     mut client: TcpStream,
     client_addr: SocketAddr,
-    token: String,
-    crypto: CryptoManager,
-    sessions: Arc<RwLock<HashMap<String, ClientSession>>>,
+    credentials: Arc<Credentials>,
+    quota: Arc<QuotaManager>,
+    codec: FrameCodec,
+    noise_key: Option<ServerKey>,
+    sessions: Arc<SessionTable>,
 ) -> Result<()> {
+    let default_key = codec.crypto().clone();
+    let metrics = sessions.metrics().clone();
+    
+    // Noise 握手得到的会话密钥取代由 --key 派生的密钥，token 认证照常进行
+    let codec = match &noise_key {
+        Some(noise_key) => match noise::respond(&mut client, &codec.candidate_keys(), noise_key).await {
+            Ok(keys) => codec.transport_keys(keys),
+            Err(e) => {
+                metrics.handshake_failed("noise");
+                return Err(e);
+            }
+        },
+        None => codec,
+    };
+    
     // 处理握手认证
-    let session_id = perform_handshake(&mut client, &token, &crypto).await?;
+    // 认证之前只接受小帧
+    let mut framed = Framed::new(client, codec.clone().max_frame_len(MAX_HANDSHAKE_FRAME_LEN));
+    let (session_id, client_id, user) = match perform_handshake(&mut framed, &credentials, &default_key).await {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            metrics.handshake_error(&e);
+            return Err(e);
+        }
+    };
+    metrics.handshake_succeeded(Some(&user.name));
+    framed.codec_mut().set_max_frame_len(codec.frame_limit());
     
     // 存储会话信息
-    {
-        let mut sessions_write = sessions.write().await;
-        sessions_write.insert(
-            session_id.clone(),
-            ClientSession {
-                client_id: client_addr.to_string(),
-                session_id: session_id.clone(),
-                connected_at: std::time::Instant::now(),
-            },
-        );
+    let meter = quota.meter(&user.name, user.limits());
+    let session = sessions.register(session_id.clone(), client_id, client_addr, user.clone(), meter);
+    let user = user.name;
+    
+    info!("客户端 {} 以用户 {} 认证成功，会话 ID: {}", client_addr, user, session_id);
+    
+    // 认证后的连接承载多路复用的代理流
+    let (reader, writer) = codec::into_split(framed);
+    let mux = MuxSession::new(writer, Role::Server, metrics);
+    
+    // 新流在读取下一帧前注册，保证后续帧能找到它
+    let accept = |open: MuxFrame| {
+        let stream = match mux.register_stream(open.stream_id) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("用户 {} 打开流时出错: {}", session.user(), e);
+                mux.reject_stream(open.stream_id, &e.to_string());
+                return;
+            }
+        };
+        let session = session.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_proxy_stream(stream, &open.payload, &session).await {
+                error!("用户 {} 处理代理流 {} 时出错: {}", session.user(), open.stream_id, e);
+            }
+        });
+    };
+    tokio::select! {
+        result = mux::read_frames(reader, mux.clone(), accept) => {
+            if let Err(e) = result {
+                info!("用户 {} 的会话 {} 读取结束: {}", user, session_id, e);
+            }
+        }
+        _ = session.terminated() => {
+            info!("用户 {} 的会话 {} 被终止", user, session_id);
+        }
     }
     
-    info!("客户端 {} 认证成功，会话 ID: {}", client_addr, session_id);
+    mux.shutdown();
     
+    // 清理会话
+    sessions.remove(&session_id);
+    
+    let (upload, download) = session.traffic();
+    info!(
+        "用户 {} 的客户端 {} 连接结束，会话 {} 持续 {:?}，上传 {} 字节，下载 {} 字节",
+        session.user(),
+        session.client_id(),
+        session.id(),
+        session.connected_at().elapsed(),
+        upload,
+        download
+    );
+    Ok(())
+}
+
+async fn handle_proxy_stream(stream: MuxStream, payload: &[u8], session: &Arc<Session>) -> Result<()> {
     // 处理代理请求
-    let target_addr = receive_proxy_request(&mut client, &crypto).await?;
+    let request = receive_proxy_request(payload)?;
+    let target_addr = request.target_addr;
+    let local_user = request.user;
+    let user = session.user();
+    
+    // 配额用尽后不再建立新的流，客户端收到明确的原因
+    if let Err(e) = session.meter().check() {
+        send_proxy_response(&stream, false, &format!("用户 {} 的{}", user, e)).await?;
+        return Err(anyhow!(e));
+    }
+    
+    // 客户端本地用户的流量同时计入它自己的用量，并在管理接口的流信息中显示
+    if let Some(local_user) = &local_user {
+        info!("用户 {} 的流 {} 来自客户端本地用户 {}", user, stream.id(), local_user);
+    }
+    
+    match request.command {
+        ProxyCommand::Connect => {}
+        ProxyCommand::UdpAssociate => return handle_udp_associate(stream, session, local_user).await,
+        ProxyCommand::Bind => return handle_bind(stream, &target_addr, session, local_user).await,
+    }
     
     // 连接到目标服务器
-    let mut target = match TcpStream::connect(&target_addr).await {
+    let started = std::time::Instant::now();
+    let connected = connect_target(&target_addr).await;
+    session.metrics().observe_connect(started.elapsed(), connected.is_ok());
+    let target = match connected {
         Ok(conn) => {
-            info!("成功连接到目标服务器: {}", target_addr);
+            info!("用户 {} 的流 {} 成功连接到目标服务器: {}", user, stream.id(), target_addr);
             conn
         }
         Err(e) => {
-            error!("连接目标服务器失败: {} - {}", target_addr, e);
-            send_proxy_response(&mut client, false, &format!("连接失败: {}", e), &crypto).await?;
+            error!("用户 {} 连接目标服务器失败: {} - {}", user, target_addr, e);
+            send_proxy_response(&stream, false, &format!("连接失败: {}", e)).await?;
             return Err(anyhow!("连接目标服务器失败: {}", e));
         }
     };
     
     // 发送成功响应
-    send_proxy_response(&mut client, true, "连接成功", &crypto).await?;
+    send_proxy_response(&stream, true, "连接成功").await?;
     
     // 开始转发数据
-    forward_data(client, target, crypto).await?;
+    let traffic = session.open_stream(stream.id(), target_addr.to_string(), local_user);
+    forward_data(stream, target, &traffic).await
+}
+
+async fn handle_udp_associate(stream: MuxStream, session: &Arc<Session>, local_user: Option<String>) -> Result<()> {
+    let user = session.user();
+    let association = match UdpAssociation::bind().await {
+        Ok(association) => association,
+        Err(e) => {
+            error!("用户 {} 绑定 UDP 套接字失败: {}", user, e);
+            send_proxy_response(&stream, false, &format!("UDP 关联失败: {}", e)).await?;
+            return Err(anyhow!("绑定 UDP 套接字失败: {}", e));
+        }
+    };
     
-    // 清理会话
-    {
-        let mut sessions_write = sessions.write().await;
-        sessions_write.remove(&session_id);
-    }
+    send_proxy_response(&stream, true, "UDP 关联已建立").await?;
     
-    info!("客户端 {} 连接结束", client_addr);
-    Ok(())
+    info!("用户 {} 的流 {} 建立 UDP 关联", user, stream.id());
+    let traffic = session.open_stream(stream.id(), "UDP", local_user);
+    association.relay(stream, &traffic).await
 }
 
+async fn handle_bind(
+    mut stream: MuxStream,
+    target_addr: &TargetAddr,
+    session: &Arc<Session>,
+    local_user: Option<String>,
+) -> Result<()> {
+    let user = session.user();
+    let listener = match BindListener::bind(target_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("用户 {} BIND 监听失败: {} - {}", user, target_addr, e);
+            send_proxy_response(&stream, false, &format!("监听失败: {}", e)).await?;
+            return Err(anyhow!("BIND 监听失败: {}", e));
+        }
+    };
+    
+    // 第一次答复：监听地址，客户端把它告诉目标
+    let bound_addr = listener.local_addr()?;
+    send_bind_response(&stream, "监听已建立", bound_addr).await?;
+    info!("用户 {} 的流 {} 在 {} 上等待来自 {} 的入站连接", user, stream.id(), bound_addr, target_addr);
+    
+    // 客户端放弃时不再等待入站连接
+    let accepted = tokio::select! {
+        accepted = listener.accept() => accepted,
+        _ = stream.wait_closed() => return Ok(()),
+    };
+    
+    let (inbound, peer_addr) = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            send_proxy_response(&stream, false, &format!("接受入站连接失败: {}", e)).await?;
+            return Err(anyhow!("流 {} 接受入站连接失败: {}", stream.id(), e));
+        }
+    };
+    
+    // 第二次答复：入站连接的对端地址
+    send_bind_response(&stream, "已接受入站连接", peer_addr).await?;
+    info!("用户 {} 的流 {} 接受来自 {} 的入站连接", user, stream.id(), peer_addr);
+    
+    let traffic = session.open_stream(stream.id(), format!("BIND {}", peer_addr), local_user);
+    forward_data(stream, inbound, &traffic).await
+}
+
+/// 连接到代理目标；域名在服务器端解析，依次尝试解析出的每个地址
+async fn connect_target(target_addr: &TargetAddr) -> std::io::Result<TcpStream> {
+    match target_addr {
+        TargetAddr::Ipv4(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Ipv6(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Domain(domain, port) => TcpStream::connect((domain.as_str(), *port)).await,
+    }
+}
+
+/// 认证客户端，返回会话 ID、客户端 ID 和用户
 async fn perform_handshake(
-    client: &mut TcpStream,
-    expected_token: &str,
-    crypto: &CryptoManager,
-) -> Result<String> {
+    client: &mut Framed<TcpStream, FrameCodec>,
+    credentials: &Credentials,
+    default_key: &CryptoManager,
+) -> Result<(String, String, User)> {
     // 接收握手请求
-    let mut length_buf = [0u8; 4];
-    client.read_exact(&mut length_buf).await?;
-    let length = u32::from_be_bytes(length_buf) as usize;
+    let decrypted_data = client.next().await.ok_or_else(|| anyhow!("客户端在握手前关闭了连接"))??;
+    let handshake: HandshakeRequest = serde_json::from_slice(&decrypted_data)?;
     
-    let mut request_buf = vec![0u8; length];
-    client.read_exact(&mut request_buf).await?;
+    // 质询每个连接都不同，录制下来的证明无法在新连接上重用
+    let challenge = AuthChallenge::new();
+    let challenge_data = serde_json::to_vec(&challenge)?;
+    client.send(challenge_data.as_slice()).await?;
     
-    let decrypted_data = crypto.decrypt(&request_buf)?;
-    let handshake: HandshakeRequest = serde_json::from_slice(&decrypted_data)?;
+    let proof_data = client.next().await.ok_or_else(|| anyhow!("客户端在应答质询前关闭了连接"))??;
+    let proof: AuthProof = serde_json::from_slice(&proof_data)?;
     
-    // 验证 token
-    if handshake.token != expected_token {
-        let response = HandshakeResponse {
-            success: false,
-            message: "认证失败：无效的 token".to_string(),
-            session_id: None,
-        };
-        
-        let response_data = serde_json::to_vec(&response)?;
-        let encrypted_response = crypto.encrypt(&response_data)?;
-        
-        let length = (encrypted_response.len() as u32).to_be_bytes();
-        client.write_all(&length).await?;
-        client.write_all(&encrypted_response).await?;
-        
-        return Err(anyhow!("认证失败：无效的 token"));
-    }
+    // v2 的盐缓存只保留有限时间，更早录制的握手靠时间戳拒绝
+    let result = if client.codec().framing_version() == Framing::V2
+        && !replay::timestamp_in_window(handshake.timestamp)
+    {
+        Err(AuthError::StaleTimestamp)
+    } else {
+        match credentials.authenticate(
+            handshake.user.as_deref(),
+            &handshake.client_id,
+            handshake.timestamp,
+            &challenge,
+            &proof,
+        ) {
+            // 有专用密钥的用户必须使用它，其他用户使用 --key
+            Ok(user) if !client.codec().crypto().same_key(user.key().unwrap_or(default_key)) => {
+                Err(AuthError::KeyMismatch)
+            }
+            Ok(user) => Ok(user.clone()),
+            Err(e) => Err(e),
+        }
+    };
+    
+    let user = match result {
+        Ok(user) => user,
+        Err(e) => {
+            let response = HandshakeResponse {
+                success: false,
+                message: e.to_string(),
+                session_id: None,
+            };
+            
+            let response_data = serde_json::to_vec(&response)?;
+            client.send(response_data.as_slice()).await?;
+            
+            return Err(e.into());
+        }
+    };
     
     // 生成会话 ID
     let session_id = uuid::Uuid::new_v4().to_string();
@@ -192,118 +586,39 @@ async fn perform_handshake(
     };
     
     let response_data = serde_json::to_vec(&response)?;
-    let encrypted_response = crypto.encrypt(&response_data)?;
-    
-    let length = (encrypted_response.len() as u32).to_be_bytes();
-    client.write_all(&length).await?;
-    client.write_all(&encrypted_response).await?;
+    client.send(response_data.as_slice()).await?;
     
-    Ok(session_id)
+    Ok((session_id, handshake.client_id, user))
 }
 
-async fn receive_proxy_request(
-    client: &mut TcpStream,
-    crypto: &CryptoManager,
-) -> Result<String> {
-    let mut length_buf = [0u8; 4];
-    client.read_exact(&mut length_buf).await?;
-    let length = u32::from_be_bytes(length_buf) as usize;
+fn receive_proxy_request(payload: &[u8]) -> Result<ProxyRequest> {
+    let request: ProxyRequest = serde_json::from_slice(payload)?;
     
-    let mut request_buf = vec![0u8; length];
-    client.read_exact(&mut request_buf).await?;
-    
-    let decrypted_data = crypto.decrypt(&request_buf)?;
-    let request: ProxyRequest = serde_json::from_slice(&decrypted_data)?;
-    
-    Ok(request.target_addr)
+    Ok(request)
 }
 
-async fn send_proxy_response(
-    client: &mut TcpStream,
-    success: bool,
-    message: &str,
-    crypto: &CryptoManager,
-) -> Result<()> {
+async fn send_proxy_response(stream: &MuxStream, success: bool, message: &str) -> Result<()> {
     let response = ProxyResponse {
         success,
         message: message.to_string(),
+        bound_addr: None,
     };
     
     let response_data = serde_json::to_vec(&response)?;
-    let encrypted_response = crypto.encrypt(&response_data)?;
-    
-    let length = (encrypted_response.len() as u32).to_be_bytes();
-    client.write_all(&length).await?;
-    client.write_all(&encrypted_response).await?;
-    
-    Ok(())
+    stream.send_reply(response_data).await
 }
 
-async fn forward_data(
-    mut client: TcpStream,
-    mut target: TcpStream,
-    crypto: CryptoManager,
-) -> Result<()> {
-    let (mut client_read, mut client_write) = client.split();
-    let (mut target_read, mut target_write) = target.split();
-    
-    let client_to_target = async {
-        let mut buf = [0u8; 8192];
-        loop {
-            // 读取长度
-            let mut length_buf = [0u8; 4];
-            if client_read.read_exact(&mut length_buf).await.is_err() {
-                break;
-            }
-            let length = u32::from_be_bytes(length_buf) as usize;
-            
-            // 读取加密数据
-            let mut encrypted_buf = vec![0u8; length];
-            if client_read.read_exact(&mut encrypted_buf).await.is_err() {
-                break;
-            }
-            
-            // 解密数据
-            match crypto.decrypt(&encrypted_buf) {
-                Ok(decrypted) => {
-                    if target_write.write_all(&decrypted).await.is_err() {
-                        break;
-                    }
-                }
-                Err(_) => break,
-            }
-        }
-    };
-    
-    let target_to_client = async {
-        let mut buf = [0u8; 8192];
-        loop {
-            let n = match target_read.read(&mut buf).await {
-                Ok(n) if n == 0 => break,
-                Ok(n) => n,
-                Err(_) => break,
-            };
-            
-            // 加密数据
-            match crypto.encrypt(&buf[..n]) {
-                Ok(encrypted) => {
-                    let length = (encrypted.len() as u32).to_be_bytes();
-                    if client_write.write_all(&length).await.is_err() {
-                        break;
-                    }
-                    if client_write.write_all(&encrypted).await.is_err() {
-                        break;
-                    }
-                }
-                Err(_) => break,
-            }
-        }
+async fn send_bind_response(stream: &MuxStream, message: &str, addr: SocketAddr) -> Result<()> {
+    let response = ProxyResponse {
+        success: true,
+        message: message.to_string(),
+        bound_addr: Some(addr.into()),
     };
     
-    tokio::select! {
-        _ = client_to_target => info!("客户端到目标的数据传输完成"),
-        _ = target_to_client => info!("目标到客户端的数据传输完成"),
-    }
-    
-    Ok(())
-} 
\ No newline at end of file
+    let response_data = serde_json::to_vec(&response)?;
+    stream.send_reply(response_data).await
+}
+
+async fn forward_data(stream: MuxStream, target: TcpStream, traffic: &StreamTraffic) -> Result<()> {
+    stream.relay(target, traffic).await
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use std::future::pending;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+
+use leaf_protocol::mux::MuxStream;
+use leaf_protocol::protocol::{decode_datagram, encode_datagram, TargetAddr};
+use leaf_protocol::quota::{Direction, QuotaError};
+use leaf_protocol::sessions::StreamTraffic;
+
+/// UDP 关联的空闲超时，两个方向都没有数据报时关闭关联
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// UDP 数据报的最大长度
+const MAX_DATAGRAM: usize = 65535;
+
+/// 一个 UDP 关联在出口侧的套接字
+/// 每个关联独占套接字，目标的回复按来源地址映射回同一个流
+pub struct UdpAssociation {
+    v4: UdpSocket,
+    /// 系统不支持 IPv6 时为 `None`
+    v6: Option<UdpSocket>,
+}
+
+impl UdpAssociation {
+    pub async fn bind() -> Result<Self> {
+        let v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+
+        Ok(Self { v4, v6 })
+    }
+
+    /// 在流和目标之间转发数据报，直到流关闭、空闲超时或用户配额用尽
+    /// 配额用尽时客户端从 Close 帧中得知原因
+    pub async fn relay(self, mut stream: MuxStream, traffic: &StreamTraffic) -> Result<()> {
+        let result = self.relay_datagrams(&mut stream, traffic).await;
+        if let Err(e) = &result
+            && let Some(quota) = e.downcast_ref::<QuotaError>()
+        {
+            stream.set_close_reason(format!("用户 {} 的{}", traffic.user(), quota));
+        }
+        result
+    }
+
+    async fn relay_datagrams(&self, stream: &mut MuxStream, traffic: &StreamTraffic) -> Result<()> {
+        let mut buf_v4 = vec![0u8; MAX_DATAGRAM];
+        let mut buf_v6 = vec![0u8; MAX_DATAGRAM];
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(UDP_IDLE_TIMEOUT) => {
+                    info!("流 {} 的 UDP 关联空闲超时", stream.id());
+                    break;
+                }
+                payload = stream.recv_datagram() => {
+                    let Some(payload) = payload else {
+                        break;
+                    };
+                    traffic.record(Direction::Upload, payload.len()).await?;
+                    if let Err(e) = self.send(&payload).await {
+                        debug!("流 {} 发送 UDP 数据报失败: {}", stream.id(), e);
+                    }
+                }
+                result = self.v4.recv_from(&mut buf_v4) => {
+                    let (n, src) = result?;
+                    traffic.record(Direction::Download, n).await?;
+                    stream.send_datagram(encode_datagram(&src.into(), &buf_v4[..n]))?;
+                }
+                result = recv_optional(self.v6.as_ref(), &mut buf_v6) => {
+                    let (n, src) = result?;
+                    traffic.record(Direction::Download, n).await?;
+                    stream.send_datagram(encode_datagram(&src.into(), &buf_v6[..n]))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, payload: &[u8]) -> Result<()> {
+        let (target_addr, data) = decode_datagram(payload)?;
+        let addr = self.resolve(&target_addr).await?;
+
+        match (addr, &self.v6) {
+            (SocketAddr::V4(_), _) => self.v4.send_to(data, addr).await?,
+            (SocketAddr::V6(_), Some(v6)) => v6.send_to(data, addr).await?,
+            (SocketAddr::V6(_), None) => return Err(anyhow!("不支持 IPv6 目标: {}", addr)),
+        };
+
+        Ok(())
+    }
+
+    /// 解析目标地址；域名优先选择可用地址族的第一个地址
+    async fn resolve(&self, target_addr: &TargetAddr) -> Result<SocketAddr> {
+        match target_addr {
+            TargetAddr::Ipv4(ip, port) => Ok(SocketAddr::from((*ip, *port))),
+            TargetAddr::Ipv6(ip, port) => Ok(SocketAddr::from((*ip, *port))),
+            TargetAddr::Domain(domain, port) => lookup_host((domain.as_str(), *port))
+                .await?
+                .find(|addr| addr.is_ipv4() || self.v6.is_some())
+                .ok_or_else(|| anyhow!("无法解析域名: {}", domain)),
+        }
+    }
+}
+
+async fn recv_optional(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => pending().await,
+    }
+}
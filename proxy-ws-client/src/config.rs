@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use leaf_protocol::auth::SocksAuth;
+use leaf_protocol::config;
+use leaf_protocol::pool::{ServerSpec, Strategy};
+use leaf_protocol::TargetAddr;
+
+/// 客户端的配置文件，配置项与同名的命令行参数一致，命令行参数优先
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// SOCKS5 监听地址，可以写一个或多个
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub socks_addr: Vec<SocketAddr>,
+    /// WebSocket 服务器，可以写一个或多个，`名称=URL` 给服务器命名
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub server_url: Vec<ServerSpec>,
+    pub strategy: Strategy,
+    /// 服务器池的健康检查间隔 (秒)，0 表示不检查
+    pub health_check_interval: u64,
+    /// 健康检查时经每台服务器连接的目标 (`主机:端口`)，用于测量连接延迟
+    pub latency_probe: Option<TargetAddr>,
+    pub token: Option<String>,
+    pub user: Option<String>,
+    /// SOCKS5 用户凭据，格式为 `用户名:密码`
+    pub socks_users: Vec<String>,
+    pub require_auth: bool,
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socks_addr: vec!["127.0.0.1:1080".parse().unwrap()],
+            server_url: vec!["ws://127.0.0.1:8080/ws".parse().unwrap()],
+            strategy: Strategy::Failover,
+            health_check_interval: 30,
+            latency_probe: None,
+            token: None,
+            user: None,
+            socks_users: Vec::new(),
+            require_auth: false,
+            metrics_addr: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        config::load(path)
+    }
+
+    /// `--print-default-config` 输出的内容
+    pub fn default_toml() -> Result<String> {
+        config::to_toml(&Self::default())
+    }
+
+    /// 检查合并命令行参数后的配置
+    pub fn validate(&self) -> Result<()> {
+        if self.socks_addr.is_empty() {
+            return Err(anyhow!("socks_addr 至少需要一个地址"));
+        }
+        if self.server_url.is_empty() {
+            return Err(anyhow!("server_url 至少需要一个服务器"));
+        }
+        for (index, server) in self.server_url.iter().enumerate() {
+            if !server.addr.starts_with("ws://") && !server.addr.starts_with("wss://") {
+                return Err(anyhow!("server_url 必须以 ws:// 或 wss:// 开头: {}", server.addr));
+            }
+            if self.server_url[..index].iter().any(|other| other.name == server.name) {
+                return Err(anyhow!("server_url 中的服务器名称重复: {}", server.name));
+            }
+        }
+        if self.strategy == Strategy::Latency && self.health_check_interval == 0 {
+            return Err(anyhow!("latency 策略依靠健康检查测量延迟，health_check_interval 不能为 0"));
+        }
+        if self.token.as_deref().is_none_or(str::is_empty) {
+            return Err(anyhow!("缺少认证 token：设置配置项 token 或 --token 参数"));
+        }
+        SocksAuth::from_entries(&self.socks_users).context("配置项 socks_users 无效")?;
+        Ok(())
+    }
+
+    /// 健康检查间隔，`None` 表示不检查
+    pub fn health_check(&self) -> Option<Duration> {
+        (self.health_check_interval > 0).then(|| Duration::from_secs(self.health_check_interval))
+    }
+}
@@ -1,48 +1,106 @@
-use anyhow::{anyhow, Result};
-use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::get,
-    Router,
-};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use futures_util::{sink::SinkExt, stream::StreamExt};
-use log::{error, info, warn};
-use std::{net::{Ipv4Addr, Ipv6Addr, SocketAddr}, sync::Arc};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as TungsteniteMessage};
-use url::Url;
-use uuid::Uuid;
-
-mod protocol;
-
-use protocol::{HandshakeRequest, HandshakeResponse, ProxyRequest, ProxyResponse, WsMessage};
-
-const SOCKS_VERSION: u8 = 0x05;
-const NO_AUTHENTICATION: u8 = 0x00;
-const CONNECT_COMMAND: u8 = 0x01;
-const IPV4_ADDRESS: u8 = 0x01;
-const DOMAIN_NAME: u8 = 0x03;
-const IPV6_ADDRESS: u8 = 0x04;
+use futures_util::{future::join_all, sink::SinkExt, stream::StreamExt};
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::protocol::Message as TungsteniteMessage;
+
+mod config;
+mod tunnel;
+
+use leaf_protocol::auth::SocksAuth;
+use leaf_protocol::metrics::{self, Metrics};
+use leaf_protocol::pool::{ServerPool, ServerSpec, Strategy};
+use leaf_protocol::replay;
+use leaf_protocol::socks5::{self, Reply};
+use leaf_protocol::ws::{HandshakeRequest, HandshakeResponse, WsMessage, PROTOCOL_VERSION};
+use leaf_protocol::{ProxyCommand, ProxyRequest, ProxyResponse, TargetAddr};
+use config::Config;
+use tunnel::{WsConnection, WsStream, WsTunnel};
 
 #[derive(Parser)]
 #[command(name = "proxy-ws-client")]
 #[command(about = "WebSocket proxy client with SOCKS5 support")]
 struct Args {
-    /// SOCKS5 listen address
-    #[arg(short = 'l', long, default_value = "127.0.0.1:1080")]
-    socks_addr: String,
+    /// Configuration file (TOML); command-line flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Print the default configuration file and exit
+    #[arg(long)]
+    print_default_config: bool,
+
+    /// SOCKS5 listen address, repeatable [default: 127.0.0.1:1080]
+    #[arg(short = 'l', long)]
+    socks_addr: Vec<SocketAddr>,
+
+    /// WebSocket server URL, optionally named as name=url; repeat to build a server pool [default: ws://127.0.0.1:8080/ws]
+    #[arg(short = 's', long)]
+    server_url: Vec<ServerSpec>,
 
-    /// WebSocket server URL
-    #[arg(short = 's', long, default_value = "ws://127.0.0.1:8080/ws")]
-    server_url: String,
+    /// How new connections pick a server: failover, round-robin, least-connections, consistent-hash or latency [default: failover]
+    #[arg(long)]
+    strategy: Option<Strategy>,
+
+    /// Seconds between background handshake health checks of pooled servers; 0 disables [default: 30]
+    #[arg(long)]
+    health_check_interval: Option<u64>,
+
+    /// Target (host:port) opened through each pooled server during health checks to measure connect latency
+    #[arg(long)]
+    latency_probe: Option<TargetAddr>,
 
     /// Authentication token
     #[arg(short, long)]
-    token: String,
+    token: Option<String>,
+
+    /// User name in the server's user database; the token is then that user's token
+    #[arg(long)]
+    user: Option<String>,
+
+    /// SOCKS5 user credentials as user:password (repeatable); enables RFC 1929 auth
+    #[arg(short = 'u', long = "socks-user")]
+    socks_users: Vec<String>,
+
+    /// Refuse to listen on a non-loopback address without SOCKS5 credentials
+    #[arg(long)]
+    require_auth: bool,
+
+    /// Prometheus metrics address; serves GET /metrics when set
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+}
+
+impl Args {
+    /// 读取配置文件 (如果指定了) 并用命令行参数覆盖
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.socks_addr.is_empty() {
+            config.socks_addr = self.socks_addr;
+        }
+        if !self.server_url.is_empty() {
+            config.server_url = self.server_url;
+        }
+        config.strategy = self.strategy.unwrap_or(config.strategy);
+        config.health_check_interval = self.health_check_interval.unwrap_or(config.health_check_interval);
+        config.latency_probe = self.latency_probe.or(config.latency_probe);
+        config.token = self.token.or(config.token);
+        config.user = self.user.or(config.user);
+        if !self.socks_users.is_empty() {
+            config.socks_users = self.socks_users;
+        }
+        config.require_auth |= self.require_auth;
+        config.metrics_addr = self.metrics_addr.or(config.metrics_addr);
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
@@ -50,19 +108,77 @@ async fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
 
-    let listener = TcpListener::bind(&args.socks_addr).await?;
-    info!("SOCKS5 代理客户端启动在 {}", args.socks_addr);
-    info!("连接到 WebSocket 服务器: {}", args.server_url);
+    if args.print_default_config {
+        print!("{}", Config::default_toml()?);
+        return Ok(());
+    }
+
+    let config = args.into_config()?;
+    let token = config.token.clone().expect("validate 已检查");
+    let auth = Arc::new(SocksAuth::from_entries(&config.socks_users)?);
+
+    // 所有 SOCKS5 连接共享到每台服务器的一条已认证的 WebSocket 隧道
+    let metrics = Arc::new(Metrics::new("proxy-ws-client"));
+    let servers = config
+        .server_url
+        .iter()
+        .map(|server| {
+            let tunnel = WsTunnel::new(server.addr.clone(), token.clone())
+                .user(config.user.clone())
+                .metrics(metrics.clone());
+            (server.name.clone(), tunnel)
+        })
+        .collect();
+    let servers = ServerPool::new(servers, config.strategy)
+        .health_check_interval(config.health_check())
+        .latency_probe(config.latency_probe.clone())
+        .metrics(metrics.clone());
+    let servers = Arc::new(servers);
+    if config.server_url.len() > 1 {
+        info!("服务器池: {} 台服务器，策略 {}", config.server_url.len(), config.strategy);
+        if let Some(target) = &config.latency_probe {
+            info!("经各服务器连接 {} 测量延迟", target);
+        }
+        tokio::spawn(servers.clone().run_health_checks());
+    }
+    if let Some(metrics_addr) = config.metrics_addr {
+        tokio::spawn(metrics::serve(metrics_addr, metrics.clone()));
+    }
+
+    let mut listeners = Vec::new();
+    for socks_addr in &config.socks_addr {
+        let listener = TcpListener::bind(socks_addr)
+            .await
+            .with_context(|| format!("无法监听 {}", socks_addr))?;
+        auth.check_listener(listener.local_addr()?, config.require_auth)?;
+        info!("SOCKS5 代理客户端启动在 {}", socks_addr);
+        listeners.push(run_socks_listener(listener, servers.clone(), metrics.clone(), auth.clone()));
+    }
+    for server in &config.server_url {
+        info!("WebSocket 服务器: {}", server);
+    }
+
+    join_all(listeners).await;
+    Ok(())
+}
 
+async fn run_socks_listener(
+    listener: TcpListener,
+    servers: Arc<ServerPool<WsTunnel>>,
+    metrics: Arc<Metrics>,
+    auth: Arc<SocksAuth>,
+) {
     loop {
         match listener.accept().await {
             Ok((socket, addr)) => {
                 info!("新 SOCKS5 连接来自: {}", addr);
-                let server_url = args.server_url.clone();
-                let token = args.token.clone();
+                let servers = servers.clone();
+                let metrics = metrics.clone();
+                let auth = auth.clone();
 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_socks_connection(socket, server_url, token).await {
+                    let _session = metrics.track_session();
+                    if let Err(e) = handle_socks_connection(socket, servers, auth).await {
                         error!("处理 SOCKS5 连接时出错: {}", e);
                     }
                 });
@@ -76,372 +192,175 @@ async fn main() -> Result<()> {
 
 async fn handle_socks_connection(
     mut client: TcpStream,
-    server_url: String,
-    token: String,
+    servers: Arc<ServerPool<WsTunnel>>,
+    auth: Arc<SocksAuth>,
 ) -> Result<()> {
-    // 处理 SOCKS5 握手
-    handle_socks_handshake(&mut client).await?;
-
-    // 处理 SOCKS5 请求
-    let target_addr = handle_socks_request(&mut client).await?;
-
-    // 连接到 WebSocket 服务器
-    let (mut ws_stream, _) = connect_async(&server_url).await?;
-    info!("WebSocket 连接建立");
+    // 处理 SOCKS5 握手，启用认证时得到用户名
+    let user = socks5::handshake(&mut client, &auth).await?;
+
+    // 处理 SOCKS5 请求，域名交给服务器解析，本地不做 DNS 查询
+    let request = socks5::read_request(&mut client).await?;
+    if request.command != ProxyCommand::Connect {
+        socks5::send_failure(&mut client, Reply::CommandNotSupported).await?;
+        return Err(anyhow!("WebSocket 隧道不支持命令: {:?}", request.command));
+    }
+    let target_addr = request.target_addr;
+    info!("目标地址: {}", target_addr);
 
-    // 进行握手认证
-    let session_id = perform_ws_handshake(&mut ws_stream, &token).await?;
+    // 按服务器选择策略获取已认证的隧道连接（断开时自动重连，服务器不可用时换用其他服务器）
+    let (server, connection) = servers.session(&target_addr.host()).await?;
+    if servers.servers().len() > 1 {
+        info!("{} 经服务器 {}", target_addr, server);
+    }
 
     // 发送代理请求
-    let success = send_proxy_request(&server_url, &target_addr, &token).await?;
+    let mut stream = send_proxy_request(&connection, &target_addr, user).await?;
+    let response = stream.recv_response().await?;
 
-    if success {
+    if response.success {
         // 发送 SOCKS5 成功响应
-        send_socks_success_response(&mut client).await?;
+        socks5::send_reply(&mut client, Reply::Succeeded, &socks5::unspecified_addr()).await?;
 
         // 开始转发数据
-        forward_data_via_ws(client, server_url, token, target_addr).await?;
+        forward_data_via_ws(client, stream, &response).await?;
     } else {
         // 发送 SOCKS5 失败响应
-        send_socks_failure_response(&mut client).await?;
-        return Err(anyhow!("代理服务器连接失败"));
-    }
-
-    Ok(())
-}
-
-async fn handle_socks_handshake(client: &mut TcpStream) -> Result<()> {
-    let mut buf = [0u8; 2];
-    client.read_exact(&mut buf).await?;
-
-    let version = buf[0];
-    let nmethods = buf[1];
-
-    if version != SOCKS_VERSION {
-        return Err(anyhow!("不支持的SOCKS版本: {}", version));
-    }
-
-    let mut methods = vec![0u8; nmethods as usize];
-    client.read_exact(&mut methods).await?;
-
-    if !methods.contains(&NO_AUTHENTICATION) {
-        let response = [SOCKS_VERSION, 0xFF];
-        client.write_all(&response).await?;
-        return Err(anyhow!("客户端不支持无认证方法"));
+        socks5::send_failure(&mut client, Reply::GeneralFailure).await?;
+        return Err(anyhow!("代理服务器连接失败: {}", response.message));
     }
 
-    let response = [SOCKS_VERSION, NO_AUTHENTICATION];
-    client.write_all(&response).await?;
-
-    info!("SOCKS5 握手成功");
     Ok(())
 }
 
-async fn handle_socks_request(client: &mut TcpStream) -> Result<SocketAddr> {
-    let mut buf = [0u8; 4];
-    client.read_exact(&mut buf).await?;
-
-    let version = buf[0];
-    let command = buf[1];
-    let _reserved = buf[2];
-    let address_type = buf[3];
-
-    if version != SOCKS_VERSION {
-        return Err(anyhow!("不支持的SOCKS版本: {}", version));
-    }
-
-    if command != CONNECT_COMMAND {
-        return Err(anyhow!("不支持的命令: {}", command));
-    }
-
-    let target_addr = match address_type {
-        IPV4_ADDRESS => {
-            let mut addr_buf = [0u8; 4];
-            client.read_exact(&mut addr_buf).await?;
-            let ip = Ipv4Addr::new(addr_buf[0], addr_buf[1], addr_buf[2], addr_buf[3]);
-
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-
-            SocketAddr::from((ip, port))
-        }
-        DOMAIN_NAME => {
-            let mut len_buf = [0u8; 1];
-            client.read_exact(&mut len_buf).await?;
-            let domain_len = len_buf[0] as usize;
-
-            let mut domain_buf = vec![0u8; domain_len];
-            client.read_exact(&mut domain_buf).await?;
-            let domain = String::from_utf8(domain_buf)?;
-
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-
-            info!("连接到域名: {}:{}", domain, port);
-
-            let addrs = tokio::net::lookup_host(format!("{}:{}", domain, port)).await?;
-            addrs.into_iter()
-                .next()
-                .ok_or_else(|| anyhow!("无法解析域名: {}", domain))?
-        }
-        IPV6_ADDRESS => {
-            let mut addr_buf = [0u8; 16];
-            client.read_exact(&mut addr_buf).await?;
-            let ip = Ipv6Addr::from(addr_buf);
-
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-
-            SocketAddr::from((ip, port))
-        }
-        _ => return Err(anyhow!("不支持的地址类型: {}", address_type)),
-    };
-
-    info!("目标地址: {}", target_addr);
-    Ok(target_addr)
-}
-
-async fn perform_ws_handshake(
+pub(crate) async fn perform_ws_handshake(
     ws_stream: &mut tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
     token: &str,
-) -> Result<String> {
-    // 发送握手请求
+    user: Option<&str>,
+    client_id: &str,
+) -> Result<HandshakeResponse> {
+    // 发送握手请求，token 不随请求发送
+    let timestamp = replay::unix_timestamp();
     let handshake = WsMessage::Handshake(HandshakeRequest {
-        token: token.to_string(),
-        client_id: Uuid::new_v4().to_string(),
+        client_id: client_id.to_string(),
+        user: user.map(str::to_string),
+        timestamp,
+        version: PROTOCOL_VERSION,
     });
-
-    let handshake_text = serde_json::to_string(&handshake)?;
-    ws_stream
-        .send(TungsteniteMessage::Text(handshake_text))
-        .await?;
-
-    // 等待握手响应
-    if let Some(Ok(msg)) = ws_stream.next().await {
-        if let TungsteniteMessage::Text(text) = msg {
-            match serde_json::from_str::<WsMessage>(&text) {
-                Ok(WsMessage::HandshakeResponse(response)) => {
-                    if response.success {
-                        info!("WebSocket 握手成功");
-                        Ok(response.session_id.unwrap_or_default())
-                    } else {
-                        Err(anyhow!("WebSocket 握手失败: {}", response.message))
-                    }
-                }
-                _ => Err(anyhow!("收到无效的握手响应")),
+    send_handshake_message(ws_stream, &handshake).await?;
+
+    // 用 token 应答服务器的质询；服务器拒绝握手时 (例如客户端版本过旧) 不发质询，直接返回失败的响应
+    let response = match recv_handshake_message(ws_stream).await? {
+        WsMessage::Challenge(challenge) => {
+            let proof = WsMessage::Proof(challenge.prove(token, client_id, timestamp));
+            send_handshake_message(ws_stream, &proof).await?;
+            match recv_handshake_message(ws_stream).await? {
+                WsMessage::HandshakeResponse(response) => response,
+                _ => return Err(anyhow!("收到无效的握手响应")),
             }
-        } else {
-            Err(anyhow!("收到非文本握手响应"))
         }
+        WsMessage::HandshakeResponse(response) if !response.success => response,
+        // 按协议服务器只在验证质询应答之后返回成功，跳过质询的成功响应按无效响应处理
+        // 这只是协议一致性检查，不能证明服务器的身份；使用 wss:// 时服务器身份由 TLS 证书保证
+        WsMessage::HandshakeResponse(_) => return Err(anyhow!("服务器未经质询就返回了成功的握手响应，不符合协议")),
+        _ => return Err(anyhow!("收到无效的握手响应")),
+    };
+
+    if response.success {
+        debug!("WebSocket 握手成功，协议版本: {}", response.version);
+        Ok(response)
     } else {
-        Err(anyhow!("未收到握手响应"))
+        Err(anyhow!("WebSocket 握手失败: {}", response.message))
     }
 }
 
-async fn send_proxy_request(
-    server_url: &str,
-    target_addr: &SocketAddr,
-    token: &str,
-) -> Result<bool> {
-    let (mut ws_stream, _) = connect_async(server_url).await?;
-
-    // 先进行握手
-    let _session_id = perform_ws_handshake(&mut ws_stream, token).await?;
-
-    // 发送代理请求
-    let proxy_req = WsMessage::ProxyRequest(ProxyRequest {
-        target_addr: target_addr.to_string(),
-    });
+async fn send_handshake_message(
+    ws_stream: &mut tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+    message: &WsMessage,
+) -> Result<()> {
+    let text = serde_json::to_string(message)?;
+    ws_stream.send(TungsteniteMessage::Text(text)).await?;
+    Ok(())
+}
 
-    let proxy_req_text = serde_json::to_string(&proxy_req)?;
-    ws_stream
-        .send(TungsteniteMessage::Text(proxy_req_text))
-        .await?;
-
-    // 等待代理响应
-    if let Some(Ok(msg)) = ws_stream.next().await {
-        if let TungsteniteMessage::Text(text) = msg {
-            match serde_json::from_str::<WsMessage>(&text) {
-                Ok(WsMessage::ProxyResponse(response)) => {
-                    if response.success {
-                        info!("代理连接成功");
-                        Ok(true)
-                    } else {
-                        error!("代理连接失败: {}", response.message);
-                        Ok(false)
-                    }
-                }
-                _ => {
-                    error!("收到无效的代理响应");
-                    Ok(false)
-                }
-            }
-        } else {
-            error!("收到非文本代理响应");
-            Ok(false)
-        }
-    } else {
-        error!("未收到代理响应");
-        Ok(false)
+async fn recv_handshake_message(
+    ws_stream: &mut tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+) -> Result<WsMessage> {
+    match ws_stream.next().await {
+        Some(Ok(TungsteniteMessage::Text(text))) => Ok(serde_json::from_str::<WsMessage>(&text)?),
+        Some(Ok(_)) => Err(anyhow!("收到非文本握手响应")),
+        _ => Err(anyhow!("未收到握手响应")),
     }
 }
 
-async fn send_socks_success_response(client: &mut TcpStream) -> Result<()> {
-    // SOCKS5 成功响应格式: [version, status, reserved, address_type, ...]
-    let response = [
-        SOCKS_VERSION, // version
-        0x00,          // status (success)
-        0x00,          // reserved
-        0x01,          // address_type (IPv4)
-        0x00, 0x00, 0x00, 0x00, // IP address (0.0.0.0)
-        0x00, 0x00,    // port (0)
-    ];
-    client.write_all(&response).await?;
-    Ok(())
-}
+async fn send_proxy_request(
+    connection: &Arc<WsConnection>,
+    target_addr: &TargetAddr,
+    user: Option<String>,
+) -> Result<WsStream> {
+    let request = ProxyRequest {
+        target_addr: target_addr.clone(),
+        command: ProxyCommand::Connect,
+        user,
+    };
 
-async fn send_socks_failure_response(client: &mut TcpStream) -> Result<()> {
-    // SOCKS5 失败响应格式: [version, status, reserved, address_type, ...]
-    let response = [
-        SOCKS_VERSION, // version
-        0x01,          // status (general failure)
-        0x00,          // reserved
-        0x01,          // address_type (IPv4)
-        0x00, 0x00, 0x00, 0x00, // IP address (0.0.0.0)
-        0x00, 0x00,    // port (0)
-    ];
-    client.write_all(&response).await?;
-    Ok(())
+    connection.open_stream(request).await
 }
 
 async fn forward_data_via_ws(
     client: TcpStream,
-    server_url: String,
-    token: String,
-    target_addr: SocketAddr,
+    stream: WsStream,
+    response: &ProxyResponse,
 ) -> Result<()> {
-    let (mut ws_stream, _) = connect_async(&server_url).await?;
+    info!("流 {} 代理连接成功 ({})，开始数据转发", stream.id(), response.message);
+    stream.relay(client).await
+}
 
-    // 先进行握手
-    let _session_id = perform_ws_handshake(&mut ws_stream, &token).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leaf_protocol::challenge::AuthChallenge;
+
+    /// 启动一个只处理一次握手的 WebSocket 服务器，收到握手请求后依次发送 `replies`
+    async fn handshake_with(replies: Vec<WsMessage>) -> Result<HandshakeResponse> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            // 每个回复之前先收到客户端的一条消息：握手请求或质询应答
+            for reply in replies {
+                ws.next().await.unwrap().unwrap();
+                let text = serde_json::to_string(&reply).unwrap();
+                ws.send(TungsteniteMessage::Text(text)).await.unwrap();
+            }
+        });
 
-    // 发送代理请求
-    let proxy_req = WsMessage::ProxyRequest(ProxyRequest {
-        target_addr: target_addr.to_string(),
-    });
+        let (mut ws_stream, _) = tokio_tungstenite::connect_async(url).await?;
+        perform_ws_handshake(&mut ws_stream, "token", Some("alice"), "client").await
+    }
 
-    let proxy_req_text = serde_json::to_string(&proxy_req)?;
-    ws_stream
-        .send(TungsteniteMessage::Text(proxy_req_text))
-        .await?;
-
-    // 等待代理响应
-    if let Some(Ok(msg)) = ws_stream.next().await {
-        if let TungsteniteMessage::Text(text) = msg {
-            match serde_json::from_str::<WsMessage>(&text) {
-                Ok(WsMessage::ProxyResponse(response)) => {
-                    if !response.success {
-                        error!("代理连接失败: {}", response.message);
-                        return Err(anyhow!("代理连接失败: {}", response.message));
-                    }
-                    info!("代理连接成功，开始数据转发");
-                }
-                _ => {
-                    error!("收到无效的代理响应");
-                    return Err(anyhow!("收到无效的代理响应"));
-                }
-            }
-        } else {
-            error!("收到非文本代理响应");
-            return Err(anyhow!("收到非文本代理响应"));
-        }
-    } else {
-        error!("未收到代理响应");
-        return Err(anyhow!("未收到代理响应"));
+    fn response(success: bool, message: &str) -> WsMessage {
+        WsMessage::HandshakeResponse(HandshakeResponse {
+            success,
+            message: message.to_string(),
+            session_id: success.then(|| "session".to_string()),
+            version: PROTOCOL_VERSION,
+        })
     }
 
-    // 开始数据转发
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-
-    // 使用 Arc<Mutex<>> 来共享客户端连接
-    let client = Arc::new(tokio::sync::Mutex::new(client));
-
-    // 启动双向数据转发
-    let client_to_server = {
-        let client = client.clone();
-        async move {
-            let mut buf = [0u8; 4096];
-            loop {
-                let mut client_guard = client.lock().await;
-                match client_guard.read(&mut buf).await {
-                    Ok(n) if n > 0 => {
-                        let data_msg = WsMessage::Data(buf[..n].to_vec());
-                        if let Ok(data_text) = serde_json::to_string(&data_msg) {
-                            if let Err(e) = ws_sender.send(TungsteniteMessage::Text(data_text)).await {
-                                error!("发送数据到服务器时出错: {}", e);
-                                break;
-                            }
-                        }
-                    }
-                    Ok(0) => break, // EOF
-                    Ok(_) => continue, // 其他情况继续
-                    Err(e) => {
-                        error!("从客户端读取数据时出错: {}", e);
-                        break;
-                    }
-                }
-            }
-        }
-    };
+    #[tokio::test]
+    async fn test_handshake_requires_challenge() {
+        // 应答质询后的成功响应被接受
+        let challenge = WsMessage::Challenge(AuthChallenge::new());
+        let accepted = handshake_with(vec![challenge, response(true, "认证成功")]).await.unwrap();
+        assert_eq!(accepted.session_id.as_deref(), Some("session"));
 
-    let server_to_client = {
-        let client = client.clone();
-        async move {
-            while let Some(Ok(msg)) = ws_receiver.next().await {
-                match msg {
-                    TungsteniteMessage::Text(text) => {
-                        match serde_json::from_str::<WsMessage>(&text) {
-                            Ok(WsMessage::Data(data)) => {
-                                let mut client_guard = client.lock().await;
-                                if let Err(e) = client_guard.write_all(&data).await {
-                                    error!("写入数据到客户端时出错: {}", e);
-                                    break;
-                                }
-                            }
-                            Ok(WsMessage::Error(error_msg)) => {
-                                error!("收到错误消息: {}", error_msg);
-                                break;
-                            }
-                            _ => {
-                                warn!("收到未知消息类型");
-                            }
-                        }
-                    }
-                    TungsteniteMessage::Binary(data) => {
-                        let mut client_guard = client.lock().await;
-                        if let Err(e) = client_guard.write_all(&data).await {
-                            error!("写入二进制数据到客户端时出错: {}", e);
-                            break;
-                        }
-                    }
-                    TungsteniteMessage::Close(_) => {
-                        info!("WebSocket 连接关闭");
-                        break;
-                    }
-                    _ => {}
-                }
-            }
-        }
-    };
+        // 没有质询的成功响应不符合协议
+        let error = handshake_with(vec![response(true, "认证成功")]).await.unwrap_err();
+        assert!(error.to_string().contains("未经质询"), "{:#}", error);
 
-    // 并发执行双向转发
-    tokio::select! {
-        _ = client_to_server => info!("客户端到服务器转发结束"),
-        _ = server_to_client => info!("服务器到客户端转发结束"),
+        // 服务器不发质询直接拒绝时报告它给出的原因
+        let error = handshake_with(vec![response(false, "客户端版本过旧")]).await.unwrap_err();
+        assert!(error.to_string().contains("客户端版本过旧"), "{:#}", error);
     }
-
-    Ok(())
 }
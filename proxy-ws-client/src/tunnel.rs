@@ -0,0 +1,600 @@
+use anyhow::{anyhow, Result};
+use futures_util::{
+    sink::SinkExt,
+    stream::{SplitSink, SplitStream, StreamExt},
+};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Semaphore,
+    },
+};
+use tokio_tungstenite::{
+    connect_async, tungstenite::protocol::Message as TungsteniteMessage, MaybeTlsStream,
+    WebSocketStream,
+};
+use uuid::Uuid;
+
+use leaf_protocol::metrics::Metrics;
+use leaf_protocol::pool::{Upstream, CONNECT_TIMEOUT};
+use leaf_protocol::quota::Direction;
+use leaf_protocol::ws::{HandshakeResponse, WsMessage, BINARY_FRAMES_VERSION, INITIAL_WINDOW};
+use leaf_protocol::{ProxyCommand, ProxyRequest, ProxyResponse, TargetAddr};
+
+type WsStreamInner = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// 写出队列容量
+const OUTBOUND_QUEUE: usize = 64;
+
+/// 心跳间隔，保持空闲隧道不被中间设备断开
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 单个数据消息的最大载荷
+const MAX_DATA_CHUNK: usize = 4096;
+
+/// 流收到的事件
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// 服务器对代理请求的响应
+    Response(ProxyResponse),
+    /// 流数据
+    Data(Vec<u8>),
+    /// 服务器关闭了流，附带服务器给出的原因
+    Close(Option<String>),
+}
+
+struct StreamHandle {
+    events: mpsc::UnboundedSender<StreamEvent>,
+    send_window: Arc<Semaphore>,
+    /// 已收到、还没有用窗口更新归还给服务器的字节数，超过接收窗口说明服务器没有遵守流量控制
+    unacked: Arc<AtomicUsize>,
+}
+
+/// 一条已认证的 WebSocket 隧道连接
+pub struct WsConnection {
+    session_id: String,
+    outbound: mpsc::Sender<WsMessage>,
+    streams: Mutex<HashMap<u32, StreamHandle>>,
+    next_stream_id: AtomicU32,
+    closed: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl WsConnection {
+    fn start(ws_stream: WsStreamInner, session_id: String, version: u32, metrics: Arc<Metrics>) -> Arc<Self> {
+        let (ws_sender, ws_receiver) = ws_stream.split();
+        let (outbound, messages) = mpsc::channel(OUTBOUND_QUEUE);
+
+        let connection = Arc::new(Self {
+            session_id,
+            outbound,
+            streams: Mutex::new(HashMap::new()),
+            next_stream_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+            metrics,
+        });
+
+        let binary = version >= BINARY_FRAMES_VERSION;
+        tokio::spawn(write_messages(
+            ws_sender,
+            messages,
+            binary,
+            Arc::downgrade(&connection),
+        ));
+        tokio::spawn(read_messages(ws_receiver, connection.clone()));
+        connection
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// 隧道上当前打开的流数
+    pub fn stream_count(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// 在隧道上打开一个新流并发送代理请求
+    pub async fn open_stream(self: &Arc<Self>, request: ProxyRequest) -> Result<WsStream> {
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let send_window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        let unacked = Arc::new(AtomicUsize::new(0));
+        self.streams.lock().unwrap().insert(
+            stream_id,
+            StreamHandle {
+                events: events_tx,
+                send_window: send_window.clone(),
+                unacked: unacked.clone(),
+            },
+        );
+
+        let stream = WsStream {
+            stream_id,
+            connection: self.clone(),
+            events,
+            send_window,
+            unacked,
+            opened_at: Instant::now(),
+        };
+
+        self.send(WsMessage::ProxyRequest { stream_id, request })
+            .await?;
+
+        Ok(stream)
+    }
+
+    pub async fn send(&self, message: WsMessage) -> Result<()> {
+        self.outbound
+            .send(message)
+            .await
+            .map_err(|_| anyhow!("WebSocket 隧道已关闭"))
+    }
+
+    fn dispatch(&self, message: WsMessage) {
+        let (stream_id, event) = match message {
+            WsMessage::ProxyResponse { stream_id, response } => {
+                (stream_id, StreamEvent::Response(response))
+            }
+            WsMessage::Data { stream_id, data } => {
+                let mut streams = self.streams.lock().unwrap();
+                let Some(handle) = streams.get(&stream_id) else {
+                    debug!("忽略未知流 {} 的数据", stream_id);
+                    return;
+                };
+
+                let unacked = handle.unacked.fetch_add(data.len(), Ordering::AcqRel) + data.len();
+                if unacked > INITIAL_WINDOW as usize {
+                    // 移除句柄后流的事件通道关闭、转发结束，流释放时向服务器发送 Close
+                    warn!("流 {} 收到 {} 字节未确认的数据，超出接收窗口，重置流", stream_id, unacked);
+                    if let Some(handle) = streams.remove(&stream_id) {
+                        handle.send_window.close();
+                    }
+                    return;
+                }
+                let _ = handle.events.send(StreamEvent::Data(data));
+                return;
+            }
+            WsMessage::Close { stream_id, reason } => (stream_id, StreamEvent::Close(reason)),
+            WsMessage::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                if let Some(handle) = self.streams.lock().unwrap().get(&stream_id) {
+                    // 合法的增量不会超过初始窗口
+                    handle
+                        .send_window
+                        .add_permits(increment.min(INITIAL_WINDOW) as usize);
+                }
+                return;
+            }
+            WsMessage::Error(error_msg) => {
+                error!("收到错误消息: {}", error_msg);
+                return;
+            }
+            _ => {
+                warn!("收到未知消息类型");
+                return;
+            }
+        };
+
+        match self.streams.lock().unwrap().get(&stream_id) {
+            Some(handle) => {
+                let _ = handle.events.send(event);
+            }
+            None => debug!("忽略未知流 {} 的消息", stream_id),
+        }
+    }
+
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        for handle in streams.into_values() {
+            let _ = handle.events.send(StreamEvent::Close(None));
+            handle.send_window.close();
+        }
+    }
+}
+
+/// 隧道上的一个代理流
+pub struct WsStream {
+    stream_id: u32,
+    connection: Arc<WsConnection>,
+    events: mpsc::UnboundedReceiver<StreamEvent>,
+    send_window: Arc<Semaphore>,
+    unacked: Arc<AtomicUsize>,
+    opened_at: Instant,
+}
+
+impl WsStream {
+    pub fn id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// 等待服务器对代理请求的响应
+    pub async fn recv_response(&mut self) -> Result<ProxyResponse> {
+        let result = match self.events.recv().await {
+            Some(StreamEvent::Response(response)) => Ok(response),
+            Some(StreamEvent::Data(_)) => Err(anyhow!("流 {} 在响应前收到数据", self.stream_id)),
+            Some(StreamEvent::Close(Some(reason))) => Err(anyhow!("未收到代理响应: {}", reason)),
+            Some(StreamEvent::Close(None)) | None => Err(anyhow!("未收到代理响应")),
+        };
+        let success = result.as_ref().is_ok_and(|response| response.success);
+        self.connection.metrics.observe_connect(self.opened_at.elapsed(), success);
+        result
+    }
+
+    /// 在本地连接和流之间双向转发数据；服务器带着原因关闭流时 (如配额用尽) 返回错误
+    pub async fn relay<S>(mut self, socket: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut local_read, mut local_write) = tokio::io::split(socket);
+        let stream_id = self.stream_id;
+        let connection = self.connection.clone();
+        let send_window = self.send_window.clone();
+        let unacked = self.unacked.clone();
+        let events = &mut self.events;
+
+        let client_to_server = async {
+            let mut buf = vec![0u8; MAX_DATA_CHUNK];
+            loop {
+                let n = match local_read.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("从客户端读取数据时出错: {}", e);
+                        break;
+                    }
+                };
+
+                // 等待服务器授予足够的窗口
+                match send_window.acquire_many(n as u32).await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                connection.metrics.relayed(None, Direction::Upload, n);
+
+                let message = WsMessage::Data {
+                    stream_id,
+                    data: buf[..n].to_vec(),
+                };
+                if let Err(e) = connection.send(message).await {
+                    error!("发送数据到服务器时出错: {}", e);
+                    break;
+                }
+            }
+        };
+
+        let server_to_client = async {
+            let mut consumed: u32 = 0;
+            while let Some(event) = events.recv().await {
+                match event {
+                    StreamEvent::Data(data) => {
+                        if let Err(e) = local_write.write_all(&data).await {
+                            error!("写入数据到客户端时出错: {}", e);
+                            break;
+                        }
+                        connection.metrics.relayed(None, Direction::Download, data.len());
+
+                        // 数据写出后归还窗口
+                        consumed += data.len() as u32;
+                        if consumed >= INITIAL_WINDOW / 2 {
+                            // 先扣除再发送更新，服务器收到更新后发来的数据不会被误判为超出窗口
+                            unacked.fetch_sub(consumed as usize, Ordering::AcqRel);
+                            let message = WsMessage::WindowUpdate {
+                                stream_id,
+                                increment: consumed,
+                            };
+                            if connection.send(message).await.is_err() {
+                                break;
+                            }
+                            consumed = 0;
+                        }
+                    }
+                    StreamEvent::Close(reason) => return reason,
+                    StreamEvent::Response(_) => warn!("流 {} 收到重复的代理响应", stream_id),
+                }
+            }
+            None
+        };
+
+        tokio::select! {
+            _ = client_to_server => debug!("流 {} 客户端到服务器转发结束", stream_id),
+            reason = server_to_client => {
+                debug!("流 {} 服务器到客户端转发结束", stream_id);
+                if let Some(reason) = reason {
+                    return Err(anyhow!("服务器关闭了流 {}: {}", stream_id, reason));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for WsStream {
+    fn drop(&mut self) {
+        if let Some(handle) = self
+            .connection
+            .streams
+            .lock()
+            .unwrap()
+            .remove(&self.stream_id)
+        {
+            handle.send_window.close();
+        }
+
+        let message = WsMessage::Close {
+            stream_id: self.stream_id,
+            reason: None,
+        };
+        if let Err(TrySendError::Full(message)) = self.connection.outbound.try_send(message) {
+            let outbound = self.connection.outbound.clone();
+            tokio::spawn(async move {
+                let _ = outbound.send(message).await;
+            });
+        }
+    }
+}
+
+/// 按协商的协议版本编码并发送消息
+/// `binary` 为真时数据面消息使用二进制帧，否则全部使用 JSON
+async fn write_messages(
+    mut ws_sender: SplitSink<WsStreamInner, TungsteniteMessage>,
+    mut messages: mpsc::Receiver<WsMessage>,
+    binary: bool,
+    connection: Weak<WsConnection>,
+) {
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.tick().await;
+
+    loop {
+        let result = tokio::select! {
+            message = messages.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+
+                let encoded = if binary { message.encode_binary() } else { None };
+                match encoded {
+                    Some(data) => ws_sender.send(TungsteniteMessage::Binary(data)).await,
+                    None => match serde_json::to_string(&message) {
+                        Ok(text) => ws_sender.send(TungsteniteMessage::Text(text)).await,
+                        Err(e) => {
+                            error!("序列化消息时出错: {}", e);
+                            continue;
+                        }
+                    },
+                }
+            }
+            _ = ping.tick() => ws_sender.send(TungsteniteMessage::Ping(Vec::new())).await,
+        };
+
+        if let Err(e) = result {
+            error!("发送 WebSocket 消息时出错: {}", e);
+            if let Some(connection) = connection.upgrade() {
+                connection.shutdown();
+            }
+            break;
+        }
+    }
+
+    let _ = ws_sender.close().await;
+}
+
+async fn read_messages(mut ws_receiver: SplitStream<WsStreamInner>, connection: Arc<WsConnection>) {
+    while let Some(msg) = ws_receiver.next().await {
+        match msg {
+            Ok(TungsteniteMessage::Text(text)) => match serde_json::from_str::<WsMessage>(&text) {
+                Ok(message) => connection.dispatch(message),
+                Err(e) => warn!("收到无法解析的消息: {}", e),
+            },
+            Ok(TungsteniteMessage::Binary(data)) => match WsMessage::decode_binary(&data) {
+                Ok(message) => connection.dispatch(message),
+                Err(e) => warn!("收到无法解析的二进制帧: {}", e),
+            },
+            Ok(TungsteniteMessage::Close(_)) => {
+                info!("WebSocket 连接关闭");
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("读取 WebSocket 消息时出错: {}", e);
+                break;
+            }
+        }
+    }
+
+    info!("WebSocket 隧道 (会话 {}) 断开", connection.session_id());
+    connection.shutdown();
+}
+
+/// 维护到 WebSocket 服务器的单条持久隧道，断开后自动重连
+pub struct WsTunnel {
+    server_url: String,
+    token: String,
+    user: Option<String>,
+    /// 客户端实例标识，重连时保持不变
+    client_id: String,
+    connection: tokio::sync::Mutex<Option<Arc<WsConnection>>>,
+    /// 最近建立的隧道连接，统计活跃流数时不需要等待连接锁
+    latest: Mutex<Weak<WsConnection>>,
+    metrics: Arc<Metrics>,
+}
+
+impl WsTunnel {
+    pub fn new(server_url: String, token: String) -> Self {
+        Self {
+            server_url,
+            token,
+            user: None,
+            client_id: Uuid::new_v4().to_string(),
+            connection: tokio::sync::Mutex::new(None),
+            latest: Mutex::new(Weak::new()),
+            metrics: Arc::new(Metrics::new("proxy-ws-client")),
+        }
+    }
+
+    /// 使用共享的指标，服务器池中的所有服务器计入同一组指标
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 以服务器用户数据库中的用户身份认证
+    pub fn user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// 返回当前隧道连接；连接已断开时重新连接并认证
+    pub async fn connection(&self) -> Result<Arc<WsConnection>> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref()
+            && !connection.is_closed()
+        {
+            return Ok(connection.clone());
+        }
+
+        let (ws_stream, response) = self.handshake().await?;
+        let session_id = response.session_id.unwrap_or_default();
+        info!("WebSocket 隧道已认证，会话 ID: {}", session_id);
+
+        let connection = WsConnection::start(ws_stream, session_id, response.version, self.metrics.clone());
+        *current = Some(connection.clone());
+        *self.latest.lock().unwrap() = Arc::downgrade(&connection);
+        Ok(connection)
+    }
+
+    /// 建立 WebSocket 连接并完成认证
+    /// 整个过程不超过 [`CONNECT_TIMEOUT`]，`connection()` 持有连接锁时不会无限等待
+    async fn handshake(&self) -> Result<(WsStreamInner, HandshakeResponse)> {
+        match tokio::time::timeout(CONNECT_TIMEOUT, self.open_connection()).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.metrics.handshake_failed("timeout");
+                Err(anyhow!("连接 WebSocket 服务器 {} 超时", self.server_url))
+            }
+        }
+    }
+
+    async fn open_connection(&self) -> Result<(WsStreamInner, HandshakeResponse)> {
+        let mut ws_stream = match connect_async(&self.server_url).await {
+            Ok((ws_stream, _)) => ws_stream,
+            Err(e) => {
+                self.metrics.handshake_failed("connect");
+                return Err(e.into());
+            }
+        };
+        debug!("WebSocket 连接建立");
+
+        let response =
+            match crate::perform_ws_handshake(&mut ws_stream, &self.token, self.user.as_deref(), &self.client_id).await {
+                Ok(response) => response,
+                Err(e) => {
+                    self.metrics.handshake_error(&e);
+                    return Err(e);
+                }
+            };
+        self.metrics.handshake_succeeded(None);
+        Ok((ws_stream, response))
+    }
+}
+
+impl Upstream for WsTunnel {
+    type Session = Arc<WsConnection>;
+
+    async fn session(&self) -> Result<Arc<WsConnection>> {
+        self.connection().await
+    }
+
+    async fn probe(&self) -> Result<()> {
+        let (mut ws_stream, _) = self.handshake().await?;
+        let _ = ws_stream.close(None).await;
+        Ok(())
+    }
+
+    async fn probe_target(&self, target: &TargetAddr) -> Result<()> {
+        let connection = self.connection().await?;
+        let request = ProxyRequest {
+            target_addr: target.clone(),
+            command: ProxyCommand::Connect,
+            user: None,
+        };
+        let response = connection.open_stream(request).await?.recv_response().await?;
+        if !response.success {
+            return Err(anyhow!("{}", response.message));
+        }
+        Ok(())
+    }
+
+    fn active_streams(&self) -> usize {
+        self.latest.lock().unwrap().upgrade().map_or(0, |connection| connection.stream_count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 不经过 WebSocket 的隧道连接，写出的消息从返回的通道取出
+    fn connection() -> (Arc<WsConnection>, mpsc::Receiver<WsMessage>) {
+        let (outbound, messages) = mpsc::channel(OUTBOUND_QUEUE);
+        let connection = Arc::new(WsConnection {
+            session_id: "session".to_string(),
+            outbound,
+            streams: Mutex::new(HashMap::new()),
+            next_stream_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+            metrics: Arc::new(Metrics::new("test")),
+        });
+        (connection, messages)
+    }
+
+    #[tokio::test]
+    async fn test_receive_window_violation_resets_stream() {
+        let (connection, mut messages) = connection();
+        let request = ProxyRequest {
+            target_addr: "example.com:80".parse().unwrap(),
+            command: ProxyCommand::Connect,
+            user: None,
+        };
+        let mut stream = connection.open_stream(request).await.unwrap();
+        assert!(matches!(messages.recv().await, Some(WsMessage::ProxyRequest { stream_id: 1, .. })));
+
+        // 服务器不等窗口更新，多发一个数据消息
+        for _ in 0..INITIAL_WINDOW as usize / MAX_DATA_CHUNK + 1 {
+            connection.dispatch(WsMessage::Data {
+                stream_id: 1,
+                data: vec![0; MAX_DATA_CHUNK],
+            });
+        }
+
+        // 窗口内的数据照常送达，超出窗口时流被移除，事件通道随之关闭
+        let mut received = 0;
+        while let Some(event) = stream.events.recv().await {
+            let StreamEvent::Data(data) = event else {
+                panic!("意外的事件 {:?}", event);
+            };
+            received += data.len();
+        }
+        assert_eq!(received, INITIAL_WINDOW as usize);
+        assert_eq!(connection.stream_count(), 0);
+
+        drop(stream);
+        assert!(matches!(messages.recv().await, Some(WsMessage::Close { stream_id: 1, reason: None })));
+    }
+}
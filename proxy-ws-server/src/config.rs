@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use leaf_protocol::admin::AdminAddr;
+use leaf_protocol::config;
+
+/// 服务器的配置文件，配置项与同名的命令行参数一致，命令行参数优先
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// 监听地址，可以写一个或多个
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub listen_addr: Vec<SocketAddr>,
+    pub token: Option<String>,
+    pub users: Option<PathBuf>,
+    pub usage_file: Option<PathBuf>,
+    pub admin_addr: Option<AdminAddr>,
+    pub admin_token: Option<String>,
+    pub metrics_addr: Option<SocketAddr>,
+    /// 重载后终止凭据已失效的会话
+    pub evict_revoked_sessions: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: vec!["0.0.0.0:8080".parse().unwrap()],
+            token: None,
+            users: None,
+            usage_file: None,
+            admin_addr: None,
+            admin_token: None,
+            metrics_addr: None,
+            evict_revoked_sessions: false,
+        }
+    }
+}
+
+impl Config {
+    /// 读取配置文件，文件中的相对路径按文件所在的目录解析
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config: Config = config::load(path)?;
+        config.users = config.users.map(|users| config::resolve_path(path, &users));
+        config.usage_file = config.usage_file.map(|usage| config::resolve_path(path, &usage));
+        Ok(config)
+    }
+
+    /// `--print-default-config` 输出的内容
+    pub fn default_toml() -> Result<String> {
+        config::to_toml(&Self::default())
+    }
+
+    /// 检查合并命令行参数后的配置
+    pub fn validate(&self) -> Result<()> {
+        if self.listen_addr.is_empty() {
+            return Err(anyhow!("listen_addr 至少需要一个地址"));
+        }
+        if self.token.is_none() && self.users.is_none() {
+            return Err(anyhow!("token 和 users 至少设置一个"));
+        }
+        if self.admin_addr.is_some() && self.admin_token.as_deref().is_none_or(str::is_empty) {
+            return Err(anyhow!("设置了 admin_addr 时必须提供 admin_token"));
+        }
+        Ok(())
+    }
+
+    /// 与运行中的配置相比，修改后需要重启才能生效的配置项
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.listen_addr != new.listen_addr {
+            changed.push("listen_addr");
+        }
+        if self.usage_file != new.usage_file {
+            changed.push("usage_file");
+        }
+        if self.admin_addr != new.admin_addr {
+            changed.push("admin_addr");
+        }
+        if self.admin_token != new.admin_token {
+            changed.push("admin_token");
+        }
+        if self.metrics_addr != new.metrics_addr {
+            changed.push("metrics_addr");
+        }
+        changed
+    }
+}
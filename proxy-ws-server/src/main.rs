@@ -1,48 +1,280 @@
-use anyhow::{anyhow, Result};
+use anyhow::{Context, Result};
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
     response::IntoResponse,
     routing::get,
     Router,
 };
 use clap::Parser;
-use futures_util::stream::StreamExt;
-use log::{error, info, warn};
+use futures_util::{
+    future::try_join_all,
+    sink::SinkExt,
+    stream::{SplitSink, StreamExt},
+};
+use log::{debug, error, info, warn};
 use std::{
     collections::HashMap,
     net::SocketAddr,
-    sync::Arc,
-    time::Instant,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::RwLock,
+    sync::{mpsc, Semaphore},
 };
 use uuid::Uuid;
 
-mod protocol;
+mod config;
 
-use protocol::{HandshakeResponse, ProxyResponse, WsMessage};
+use leaf_protocol::admin::{self, AdminAddr};
+use leaf_protocol::challenge::AuthChallenge;
+use leaf_protocol::replay;
+use leaf_protocol::reload::{self, ReloadSummary, Reloadable, Reloader};
+use leaf_protocol::quota::{Direction, QuotaError, QuotaManager};
+use leaf_protocol::sessions::{Session, SessionTable, StreamTraffic};
+use leaf_protocol::metrics::{self, Metrics};
+use leaf_protocol::users::{AuthError, Credentials, User, UserDb};
+use leaf_protocol::ws::{
+    HandshakeRequest, HandshakeResponse, WsMessage, BINARY_FRAMES_VERSION, CHALLENGE_AUTH_VERSION,
+    INITIAL_WINDOW, PROTOCOL_VERSION,
+};
+use leaf_protocol::{ProxyCommand, ProxyRequest, ProxyResponse, TargetAddr};
+use config::Config;
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(name = "proxy-ws-server")]
 #[command(about = "WebSocket proxy server (ws only)")]
 struct Args {
-    /// Server listen address
-    #[arg(short, long, default_value = "0.0.0.0:8080")]
-    listen_addr: String,
+    /// Configuration file (TOML); command-line flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Print the default configuration file and exit
+    #[arg(long)]
+    print_default_config: bool,
 
-    /// Authentication token
+    /// Server listen address, repeatable [default: 0.0.0.0:8080]
     #[arg(short, long)]
-    token: String,
+    listen_addr: Vec<SocketAddr>,
+
+    /// Authentication token for clients that do not name a user
+    #[arg(short, long)]
+    token: Option<String>,
+
+    /// User database (TOML) with per-user tokens, enabled flags and expiry
+    #[arg(long)]
+    users: Option<PathBuf>,
+
+    /// File that keeps per-user traffic usage across restarts (JSON)
+    #[arg(long)]
+    usage_file: Option<PathBuf>,
+
+    /// Admin API address: a loopback host:port or unix:/path/to/socket
+    #[arg(long)]
+    admin_addr: Option<AdminAddr>,
+
+    /// Bearer token required by the admin API
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// Prometheus metrics address; serves GET /metrics when set
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Terminate sessions whose user was removed, disabled or re-keyed when reloading
+    #[arg(long)]
+    evict_revoked_sessions: bool,
+}
+
+impl Args {
+    /// 读取配置文件 (如果指定了) 并用命令行参数覆盖
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen_addr.is_empty() {
+            config.listen_addr = self.listen_addr;
+        }
+        config.token = self.token.or(config.token);
+        config.users = self.users.or(config.users);
+        config.usage_file = self.usage_file.or(config.usage_file);
+        config.admin_addr = self.admin_addr.or(config.admin_addr);
+        config.admin_token = self.admin_token.or(config.admin_token);
+        config.metrics_addr = self.metrics_addr.or(config.metrics_addr);
+        config.evict_revoked_sessions |= self.evict_revoked_sessions;
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+type AppState = (Arc<Reloadable<Credentials>>, Arc<QuotaManager>, Arc<SessionTable>);
+
+/// 按配置加载凭据，返回凭据和用户数
+fn load_credentials(config: &Config) -> Result<(Credentials, usize)> {
+    let users = config.users.as_ref().map(UserDb::load).transpose()?;
+    let user_count = users.as_ref().map_or(0, UserDb::len);
+    if users.is_some() {
+        info!("已加载 {} 个用户", user_count);
+    }
+    Ok((Credentials::new(config.token.clone(), users)?, user_count))
+}
+
+/// 重新读取配置文件和用户数据库；出错时保持原来的凭据
+fn reload(
+    args: &Args,
+    startup: &Config,
+    credentials: &Reloadable<Credentials>,
+    sessions: &SessionTable,
+) -> Result<ReloadSummary> {
+    let config = args.clone().into_config()?;
+    let (new_credentials, users) = load_credentials(&config)?;
+    credentials.store(new_credentials);
+
+    let evicted = match config.evict_revoked_sessions {
+        true => sessions.terminate_revoked(&credentials.load()),
+        false => 0,
+    };
+    Ok(ReloadSummary {
+        users,
+        evicted,
+        restart_required: startup.restart_required(&config),
+    })
+}
+
+/// 写出队列容量。队列满时各流按先来先到排队，形成对目标读取的背压
+const OUTBOUND_QUEUE: usize = 64;
+
+/// 单个数据消息的最大载荷
+const MAX_DATA_CHUNK: usize = 4096;
+
+/// 隧道上一个流的句柄
+struct StreamHandle {
+    /// 待写入目标服务器的数据，受客户端发送窗口约束
+    data: mpsc::UnboundedSender<Vec<u8>>,
+    /// 向客户端发送数据的窗口
+    send_window: Arc<Semaphore>,
+    /// 已收到、还没有用窗口更新归还给客户端的字节数，超过接收窗口说明客户端没有遵守流量控制
+    unacked: Arc<AtomicUsize>,
 }
 
-#[derive(Debug)]
-struct ClientSession {
-    client_id: String,
-    session_id: String,
-    connected_at: Instant,
+/// 一条已认证隧道上的流表
+/// 客户端关闭流时由读取循环移除，流的任务结束时 (目标连接失败或转发完成) 移除自己
+struct Tunnel {
+    session: Arc<Session>,
+    outbound: mpsc::Sender<WsMessage>,
+    streams: Mutex<HashMap<u32, StreamHandle>>,
+}
+
+impl Tunnel {
+    fn new(session: Arc<Session>, outbound: mpsc::Sender<WsMessage>) -> Arc<Self> {
+        Arc::new(Self {
+            session,
+            outbound,
+            streams: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// 处理客户端发来的一条消息；收到错误消息时返回 `false`，隧道应关闭
+    fn dispatch(self: &Arc<Self>, message: WsMessage) -> bool {
+        match message {
+            WsMessage::ProxyRequest { stream_id, request } => self.open_stream(stream_id, request),
+            WsMessage::Data { stream_id, data } => {
+                // 转发数据到目标服务器；写入在流自己的任务中进行，不阻塞其他流
+                let mut streams = self.streams.lock().unwrap();
+                let Some(handle) = streams.get(&stream_id) else {
+                    debug!("忽略未知流 {} 的数据", stream_id);
+                    return true;
+                };
+
+                let unacked = handle.unacked.fetch_add(data.len(), Ordering::AcqRel) + data.len();
+                if unacked > INITIAL_WINDOW as usize {
+                    // 移除句柄后流的数据通道关闭、转发结束，流的任务向客户端发送 Close
+                    warn!("流 {} 收到 {} 字节未确认的数据，超出接收窗口，重置流", stream_id, unacked);
+                    if let Some(handle) = streams.remove(&stream_id) {
+                        handle.send_window.close();
+                    }
+                    return true;
+                }
+                let _ = handle.data.send(data);
+            }
+            WsMessage::WindowUpdate { stream_id, increment } => {
+                if let Some(handle) = self.streams.lock().unwrap().get(&stream_id) {
+                    // 合法的增量不会超过初始窗口
+                    handle
+                        .send_window
+                        .add_permits(increment.min(INITIAL_WINDOW) as usize);
+                }
+            }
+            WsMessage::Close { stream_id, .. } => self.remove_stream(stream_id),
+            WsMessage::Error(error_msg) => {
+                error!("收到错误消息: {}", error_msg);
+                return false;
+            }
+            _ => warn!("收到未知消息类型"),
+        }
+
+        true
+    }
+
+    fn open_stream(self: &Arc<Self>, stream_id: u32, request: ProxyRequest) {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let send_window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        let unacked = Arc::new(AtomicUsize::new(0));
+        self.streams.lock().unwrap().insert(
+            stream_id,
+            StreamHandle {
+                data: data_tx,
+                send_window: send_window.clone(),
+                unacked: unacked.clone(),
+            },
+        );
+
+        let tunnel = self.clone();
+        tokio::spawn(async move {
+            handle_proxy_stream(
+                stream_id,
+                request,
+                tunnel.session.clone(),
+                data_rx,
+                send_window.clone(),
+                unacked,
+                tunnel.outbound.clone(),
+            )
+            .await;
+
+            // 同一 ID 已被客户端的新流占用时保留新流的句柄
+            let mut streams = tunnel.streams.lock().unwrap();
+            if streams
+                .get(&stream_id)
+                .is_some_and(|handle| Arc::ptr_eq(&handle.send_window, &send_window))
+            {
+                streams.remove(&stream_id);
+            }
+        });
+    }
+
+    fn remove_stream(&self, stream_id: u32) {
+        if let Some(handle) = self.streams.lock().unwrap().remove(&stream_id) {
+            handle.send_window.close();
+        }
+    }
+
+    /// 关闭所有流
+    fn shutdown(&self) {
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        for handle in streams.into_values() {
+            handle.send_window.close();
+        }
+    }
 }
 
 #[tokio::main]
@@ -50,209 +282,751 @@ async fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
 
+    if args.print_default_config {
+        print!("{}", Config::default_toml()?);
+        return Ok(());
+    }
+
+    let config = args.clone().into_config()?;
+    let (credentials, _) = load_credentials(&config)?;
+    let credentials = Arc::new(Reloadable::new(credentials));
+    let quota = Arc::new(QuotaManager::load(config.usage_file.clone())?);
+    tokio::spawn(quota.clone().persist_periodically());
+
     // 存储活跃的客户端会话
-    let sessions: Arc<RwLock<HashMap<String, ClientSession>>> = Arc::new(RwLock::new(HashMap::new()));
+    let metrics = Arc::new(Metrics::new("proxy-ws-server"));
+    let sessions = Arc::new(SessionTable::new(metrics.clone()));
+    if let Some(metrics_addr) = config.metrics_addr {
+        tokio::spawn(metrics::serve(metrics_addr, metrics));
+    }
+
+    let reloader: Reloader = {
+        let startup = config.clone();
+        let credentials = credentials.clone();
+        let sessions = sessions.clone();
+        Arc::new(move || reload(&args, &startup, &credentials, &sessions))
+    };
+    tokio::spawn(reload::reload_on_sighup(reloader.clone()));
+    if let Some(admin_addr) = config.admin_addr.clone() {
+        let admin_token = config.admin_token.clone().expect("validate 已检查");
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin_addr, admin_token, sessions, reloader).await {
+                error!("管理接口出错: {:#}", e);
+            }
+        });
+    }
 
     // 创建路由
     let app = Router::new()
         .route("/ws", get(ws_handler))
-        .with_state((args.token.clone(), sessions.clone()));
+        .with_state((credentials, quota, sessions));
 
-    let addr: SocketAddr = args.listen_addr.parse()?;
-    info!("启动 WebSocket 服务器 (ws) 在 {}", addr);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve::serve(listener, app).await?;
+    let mut servers = Vec::new();
+    for addr in config.listen_addr {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("无法监听 {}", addr))?;
+        info!("启动 WebSocket 服务器 (ws) 在 {}", addr);
+        // 会话表记录客户端的地址
+        let service = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+        servers.push(async move { axum::serve::serve(listener, service).await });
+    }
+    try_join_all(servers).await?;
     Ok(())
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    axum::extract::State((token, sessions)): axum::extract::State<(String, Arc<RwLock<HashMap<String, ClientSession>>>)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    State((credentials, quota, sessions)): State<AppState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_websocket(socket, token, sessions))
+    ws.on_upgrade(move |socket| handle_websocket(socket, peer, credentials, quota, sessions))
 }
 
 async fn handle_websocket(
     mut socket: WebSocket,
-    token: String,
-    sessions: Arc<RwLock<HashMap<String, ClientSession>>>,
+    peer: SocketAddr,
+    credentials: Arc<Reloadable<Credentials>>,
+    quota: Arc<QuotaManager>,
+    sessions: Arc<SessionTable>,
 ) {
     info!("WebSocket 连接建立");
 
     // 等待握手消息
-    if let Some(Ok(msg)) = socket.recv().await {
-        if let Message::Text(text) = msg {
-            match serde_json::from_str::<WsMessage>(&text) {
-                Ok(WsMessage::Handshake(handshake)) => {
-                    // 验证 token
-                    if handshake.token != token {
+    if let Some(Ok(msg)) = socket.recv().await
+        && let Message::Text(text) = msg
+    {
+        match serde_json::from_str::<WsMessage>(&text) {
+            Ok(WsMessage::Handshake(handshake)) => {
+                // 验证客户端持有 token
+                let user = match authenticate(&mut socket, &credentials.load(), &handshake).await {
+                    Ok(user) => user,
+                    Err(e) => {
+                        warn!("客户端 {} {}", handshake.client_id, e);
+                        sessions.metrics().handshake_failed(e.reason());
                         let response = WsMessage::HandshakeResponse(HandshakeResponse {
                             success: false,
-                            message: "认证失败：无效的 token".to_string(),
+                            message: e.to_string(),
                             session_id: None,
+                            version: PROTOCOL_VERSION,
                         });
-                        
-                        if let Ok(response_text) = serde_json::to_string(&response) {
-                            if let Err(e) = socket.send(Message::Text(response_text.into())).await {
-                                error!("发送认证失败响应时出错: {}", e);
-                            }
+                    
+                        if let Ok(response_text) = serde_json::to_string(&response)
+                            && let Err(e) = socket.send(Message::Text(response_text.into())).await
+                        {
+                            error!("发送认证失败响应时出错: {}", e);
                         }
                         return;
                     }
+                };
 
-                    // 生成会话 ID
-                    let session_id = Uuid::new_v4().to_string();
-                    let client_id = handshake.client_id.clone();
-                    // 存储会话信息
-                    {
-                        let mut sessions_write = sessions.write().await;
-                        sessions_write.insert(
-                            session_id.clone(),
-                            ClientSession {
-                                client_id: client_id.clone(),
-                                session_id: session_id.clone(),
-                                connected_at: Instant::now(),
-                            },
-                        );
-                    }
+                // 协商协议版本：取双方都支持的最高版本
+                let version = handshake.version.min(PROTOCOL_VERSION);
 
-                    // 发送握手成功响应
-                    let response = WsMessage::HandshakeResponse(HandshakeResponse {
-                        success: true,
-                        message: "认证成功".to_string(),
-                        session_id: Some(session_id.clone()),
-                    });
-
-                    if let Ok(response_text) = serde_json::to_string(&response) {
-                        if let Err(e) = socket.send(Message::Text(response_text.into())).await {
-                            error!("发送认证成功响应时出错: {}", e);
-                            return;
-                        }
-                    }
+                // 生成会话 ID
+                let session_id = Uuid::new_v4().to_string();
+                let client_id = handshake.client_id.clone();
+                sessions.metrics().handshake_succeeded(Some(&user.name));
 
-                    info!("客户端 {} 认证成功，会话 ID: {}", client_id, session_id);
+                // 存储会话信息
+                let meter = quota.meter(&user.name, user.limits());
+                let session = sessions.register(session_id.clone(), client_id.clone(), peer, user.clone(), meter);
+                let user = user.name;
 
-                    // 处理后续消息
-                    handle_proxy_messages(socket, session_id, sessions).await;
+                // 发送握手成功响应
+                let response = WsMessage::HandshakeResponse(HandshakeResponse {
+                    success: true,
+                    message: "认证成功".to_string(),
+                    session_id: Some(session_id.clone()),
+                    version,
+                });
+
+                if let Ok(response_text) = serde_json::to_string(&response)
+                    && let Err(e) = socket.send(Message::Text(response_text.into())).await
+                {
+                    error!("发送认证成功响应时出错: {}", e);
+                    sessions.remove(&session_id);
+                    return;
                 }
-                _ => {
-                    error!("收到无效的握手消息");
-                    let error_msg = WsMessage::Error("无效的握手消息".to_string());
-                    if let Ok(error_text) = serde_json::to_string(&error_msg) {
-                        let _ = socket.send(Message::Text(error_text.into())).await;
-                    }
+
+                info!(
+                    "客户端 {} 以用户 {} 认证成功，会话 ID: {}，协议版本: {}",
+                    client_id, user, session_id, version
+                );
+
+                // 处理后续消息
+                handle_proxy_messages(socket, session, sessions, version).await;
+            }
+            _ => {
+                error!("收到无效的握手消息");
+                sessions.metrics().handshake_failed("protocol");
+                let error_msg = WsMessage::Error("无效的握手消息".to_string());
+                if let Ok(error_text) = serde_json::to_string(&error_msg) {
+                    let _ = socket.send(Message::Text(error_text.into())).await;
                 }
             }
         }
     }
 }
 
+/// 质询-响应认证，token 不在连接上传输；失败时返回发给客户端的原因
+async fn authenticate(
+    socket: &mut WebSocket,
+    credentials: &Credentials,
+    handshake: &HandshakeRequest,
+) -> Result<User, AuthError> {
+    if handshake.version < CHALLENGE_AUTH_VERSION {
+        return Err(AuthError::OutdatedClient);
+    }
+
+    // 质询每个连接都不同，录制下来的证明无法在新连接上重用
+    let challenge = AuthChallenge::new();
+    let challenge_text = serde_json::to_string(&WsMessage::Challenge(challenge.clone()))
+        .map_err(|_| AuthError::Protocol("认证失败：无法发送质询"))?;
+    if socket.send(Message::Text(challenge_text.into())).await.is_err() {
+        return Err(AuthError::Protocol("认证失败：无法发送质询"));
+    }
+
+    let proof = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => match serde_json::from_str::<WsMessage>(&text) {
+            Ok(WsMessage::Proof(proof)) => proof,
+            _ => return Err(AuthError::Protocol("认证失败：无效的质询应答")),
+        },
+        _ => return Err(AuthError::Protocol("认证失败：未收到质询应答")),
+    };
+
+    if !replay::timestamp_in_window(handshake.timestamp) {
+        return Err(AuthError::StaleTimestamp);
+    }
+    credentials
+        .authenticate(
+            handshake.user.as_deref(),
+            &handshake.client_id,
+            handshake.timestamp,
+            &challenge,
+            &proof,
+        )
+        .cloned()
+}
+
 async fn handle_proxy_messages(
-    mut socket: WebSocket,
-    session_id: String,
-    sessions: Arc<RwLock<HashMap<String, ClientSession>>>,
+    socket: WebSocket,
+    session: Arc<Session>,
+    sessions: Arc<SessionTable>,
+    version: u32,
 ) {
-    let mut target_stream: Option<TcpStream> = None;
-
-    while let Some(Ok(msg)) = socket.recv().await {
-        match msg {
-            Message::Text(text) => {
-                match serde_json::from_str::<WsMessage>(&text) {
-                    Ok(WsMessage::ProxyRequest(proxy_req)) => {
-                        // 连接到目标服务器
-                        match TcpStream::connect(&proxy_req.target_addr).await {
-                            Ok(stream) => {
-                                target_stream = Some(stream);
-                                let response = WsMessage::ProxyResponse(ProxyResponse {
-                                    success: true,
-                                    message: "连接成功".to_string(),
-                                });
-                                
-                                if let Ok(response_text) = serde_json::to_string(&response) {
-                                    if let Err(e) = socket.send(Message::Text(response_text.into())).await {
-                                        error!("发送代理成功响应时出错: {}", e);
-                                        break;
-                                    }
-                                }
-                                
-                                info!("成功连接到目标服务器: {}", proxy_req.target_addr);
-                            }
-                            Err(e) => {
-                                error!("连接目标服务器失败: {} - {}", proxy_req.target_addr, e);
-                                let response = WsMessage::ProxyResponse(ProxyResponse {
-                                    success: false,
-                                    message: format!("连接失败: {}", e),
-                                });
-                                
-                                if let Ok(response_text) = serde_json::to_string(&response) {
-                                    let _ = socket.send(Message::Text(response_text.into())).await;
-                                }
-                            }
-                        }
-                    }
-                    Ok(WsMessage::Data(data)) => {
-                        // 转发数据到目标服务器
-                        if let Some(ref mut target) = target_stream {
-                            if let Err(e) = target.write_all(&data).await {
-                                error!("写入目标服务器时出错: {}", e);
-                                break;
-                            }
-                            
-                            // 读取目标服务器的响应并转发回客户端
-                            let mut buf = [0u8; 4096];
-                            match target.read(&mut buf).await {
-                                Ok(n) if n > 0 => {
-                                    let data_msg = WsMessage::Data(buf[..n].to_vec());
-                                    if let Ok(data_text) = serde_json::to_string(&data_msg) {
-                                        if let Err(e) = socket.send(Message::Text(data_text.into())).await {
-                                            error!("发送数据到客户端时出错: {}", e);
-                                            break;
-                                        }
-                                    }
-                                }
-                                Ok(0) => {
-                                    info!("目标服务器关闭连接");
-                                    break;
-                                }
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("从目标服务器读取数据时出错: {}", e);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    Ok(WsMessage::Error(error_msg)) => {
-                        error!("收到错误消息: {}", error_msg);
-                        break;
-                    }
-                    _ => {
-                        warn!("收到未知消息类型");
-                    }
-                }
-            }
-            Message::Binary(data) => {
-                // 处理二进制数据（直接转发）
-                if let Some(ref mut target) = target_stream {
-                    if let Err(e) = target.write_all(&data).await {
-                        error!("写入目标服务器时出错: {}", e);
-                        break;
-                    }
-                }
+    // 一条隧道承载多个流，所有流共享一个写出任务
+    let (ws_sender, mut ws_receiver) = socket.split();
+    let (outbound, messages) = mpsc::channel(OUTBOUND_QUEUE);
+    let binary = version >= BINARY_FRAMES_VERSION;
+    let writer = tokio::spawn(write_messages(ws_sender, messages, binary));
+
+    let tunnel = Tunnel::new(session.clone(), outbound);
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = session.terminated() => {
+                info!("用户 {} 的会话 {} 被终止", session.user(), session.id());
+                break;
             }
+        };
+
+        // 控制消息为 JSON 文本，数据面消息为二进制帧
+        let message = match msg {
+            Message::Text(text) => serde_json::from_str::<WsMessage>(&text).map_err(anyhow::Error::from),
+            Message::Binary(data) => WsMessage::decode_binary(&data),
             Message::Close(_) => {
                 info!("WebSocket 连接关闭");
                 break;
             }
-            _ => {}
+            _ => continue,
+        };
+
+        match message {
+            Ok(message) => {
+                if !tunnel.dispatch(message) {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("收到无法解析的消息: {}", e);
+            }
         }
     }
 
+    // 关闭所有流
+    tunnel.shutdown();
+    writer.abort();
+
     // 清理会话
-    {
-        let mut sessions_write = sessions.write().await;
-        sessions_write.remove(&session_id);
-    }
+    sessions.remove(session.id());
     
-    info!("会话 {} 结束", session_id);
+    let (upload, download) = session.traffic();
+    info!(
+        "用户 {} 的客户端 {} 的会话 {} 结束，持续 {:?}，上传 {} 字节，下载 {} 字节",
+        session.user(),
+        session.client_id(),
+        session.id(),
+        session.connected_at().elapsed(),
+        upload,
+        download
+    );
+}
+
+async fn handle_proxy_stream(
+    stream_id: u32,
+    request: ProxyRequest,
+    session: Arc<Session>,
+    mut data_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    send_window: Arc<Semaphore>,
+    unacked: Arc<AtomicUsize>,
+    outbound: mpsc::Sender<WsMessage>,
+) {
+    let user = session.user();
+    if let Some(local_user) = &request.user {
+        info!("用户 {} 的流 {} 来自客户端本地用户 {}", user, stream_id, local_user);
+    }
+
+    // WebSocket 隧道只承载 TCP 流
+    if request.command != ProxyCommand::Connect {
+        let response = WsMessage::ProxyResponse {
+            stream_id,
+            response: ProxyResponse {
+                success: false,
+                message: format!("不支持的命令: {:?}", request.command),
+                bound_addr: None,
+            },
+        };
+
+        let _ = outbound.send(response).await;
+        return;
+    }
+
+    // 配额用尽后不再建立新的流，客户端收到明确的原因
+    if let Err(e) = session.meter().check() {
+        warn!("用户 {} 的流 {} 被拒绝: {}", user, stream_id, e);
+        let response = WsMessage::ProxyResponse {
+            stream_id,
+            response: ProxyResponse {
+                success: false,
+                message: format!("用户 {} 的{}", user, e),
+                bound_addr: None,
+            },
+        };
+
+        let _ = outbound.send(response).await;
+        return;
+    }
+
+    // 连接到目标服务器
+    let started = std::time::Instant::now();
+    let connected = connect_target(&request.target_addr).await;
+    session.metrics().observe_connect(started.elapsed(), connected.is_ok());
+    let target = match connected {
+        Ok(stream) => {
+            let response = WsMessage::ProxyResponse {
+                stream_id,
+                response: ProxyResponse {
+                    success: true,
+                    message: "连接成功".to_string(),
+                    bound_addr: None,
+                },
+            };
+
+            if let Err(e) = outbound.send(response).await {
+                error!("发送代理成功响应时出错: {}", e);
+                return;
+            }
+
+            info!("用户 {} 的流 {} 成功连接到目标服务器: {}", user, stream_id, request.target_addr);
+            stream
+        }
+        Err(e) => {
+            error!("用户 {} 连接目标服务器失败: {} - {}", user, request.target_addr, e);
+            let response = WsMessage::ProxyResponse {
+                stream_id,
+                response: ProxyResponse {
+                    success: false,
+                    message: format!("连接失败: {}", e),
+                    bound_addr: None,
+                },
+            };
+
+            let _ = outbound.send(response).await;
+            return;
+        }
+    };
+
+    // 配额用尽时客户端从 Close 消息中得知原因
+    let traffic = session.open_stream(stream_id, request.target_addr.to_string(), request.user.clone());
+    let reason = match forward_data(stream_id, target, &traffic, &mut data_rx, &send_window, &unacked, &outbound).await {
+        Ok(()) => None,
+        Err(e) => {
+            info!("用户 {} 的流 {} 停止转发: {}", user, stream_id, e);
+            Some(format!("用户 {} 的{}", user, e))
+        }
+    };
+
+    let _ = outbound.send(WsMessage::Close { stream_id, reason }).await;
+}
+
+/// 连接到代理目标；域名在服务器端解析，依次尝试解析出的每个地址
+async fn connect_target(target_addr: &TargetAddr) -> std::io::Result<TcpStream> {
+    match target_addr {
+        TargetAddr::Ipv4(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Ipv6(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Domain(domain, port) => TcpStream::connect((domain.as_str(), *port)).await,
+    }
+}
+
+/// 在目标连接和隧道流之间双向转发数据，两个方向并发进行；用户配额用尽时返回错误
+async fn forward_data(
+    stream_id: u32,
+    mut target: TcpStream,
+    traffic: &StreamTraffic,
+    data_rx: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+    send_window: &Semaphore,
+    unacked: &AtomicUsize,
+    outbound: &mpsc::Sender<WsMessage>,
+) -> Result<(), QuotaError> {
+    let (mut target_read, mut target_write) = target.split();
+
+    let client_to_target = async {
+        let mut consumed: u32 = 0;
+        while let Some(data) = data_rx.recv().await {
+            traffic.record(Direction::Upload, data.len()).await?;
+            if let Err(e) = target_write.write_all(&data).await {
+                error!("写入目标服务器时出错: {}", e);
+                break;
+            }
+
+            // 数据写出后归还客户端的发送窗口
+            consumed += data.len() as u32;
+            if consumed >= INITIAL_WINDOW / 2 {
+                // 先扣除再发送更新，客户端收到更新后发来的数据不会被误判为超出窗口
+                unacked.fetch_sub(consumed as usize, Ordering::AcqRel);
+                let message = WsMessage::WindowUpdate {
+                    stream_id,
+                    increment: consumed,
+                };
+                if outbound.send(message).await.is_err() {
+                    break;
+                }
+                consumed = 0;
+            }
+        }
+        Ok(())
+    };
+
+    let target_to_client = async {
+        let mut buf = vec![0u8; MAX_DATA_CHUNK];
+        loop {
+            let n = match target_read.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    error!("从目标服务器读取数据时出错: {}", e);
+                    break;
+                }
+            };
+
+            traffic.record(Direction::Download, n).await?;
+
+            // 等待客户端授予足够的窗口
+            match send_window.acquire_many(n as u32).await {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+
+            let message = WsMessage::Data {
+                stream_id,
+                data: buf[..n].to_vec(),
+            };
+            if let Err(e) = outbound.send(message).await {
+                error!("发送数据到客户端时出错: {}", e);
+                break;
+            }
+        }
+        Ok(())
+    };
+
+    tokio::select! {
+        result = client_to_target => {
+            debug!("流 {} 客户端到目标的数据传输完成", stream_id);
+            result
+        }
+        result = target_to_client => {
+            debug!("流 {} 目标到客户端的数据传输完成", stream_id);
+            result
+        }
+    }
+}
+
+/// 按协商的协议版本编码并发送消息
+/// `binary` 为真时数据面消息使用二进制帧，否则全部使用 JSON
+async fn write_messages(
+    mut ws_sender: SplitSink<WebSocket, Message>,
+    mut messages: mpsc::Receiver<WsMessage>,
+    binary: bool,
+) {
+    while let Some(message) = messages.recv().await {
+        let encoded = if binary { message.encode_binary() } else { None };
+        let frame = match encoded {
+            Some(data) => Message::Binary(data.into()),
+            None => match serde_json::to_string(&message) {
+                Ok(text) => Message::Text(text.into()),
+                Err(e) => {
+                    error!("序列化消息时出错: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        if let Err(e) = ws_sender.send(frame).await {
+            error!("发送 WebSocket 消息时出错: {}", e);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leaf_protocol::quota::Limits;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    fn session(limits: Limits) -> Arc<Session> {
+        let users = UserDb::parse("[[users]]\nname = \"alice\"\ntoken = \"alice-token\"\n").unwrap();
+        let quota = QuotaManager::load(None).unwrap();
+        let table = SessionTable::new(Arc::new(Metrics::new("test")));
+        table.register(
+            "s".into(),
+            "c".into(),
+            "127.0.0.1:5000".parse().unwrap(),
+            users.get("alice").unwrap().clone(),
+            quota.meter("alice", limits),
+        )
+    }
+
+    /// 不经过 WebSocket 的隧道，发给客户端的消息从返回的通道取出
+    fn tunnel() -> (Arc<Tunnel>, mpsc::Receiver<WsMessage>) {
+        let (outbound, messages) = mpsc::channel(OUTBOUND_QUEUE);
+        (Tunnel::new(session(Limits::default()), outbound), messages)
+    }
+
+    fn proxy_request(stream_id: u32, target_addr: SocketAddr) -> WsMessage {
+        WsMessage::ProxyRequest {
+            stream_id,
+            request: ProxyRequest {
+                target_addr: target_addr.into(),
+                command: ProxyCommand::Connect,
+                user: None,
+            },
+        }
+    }
+
+    /// 打开一个流并等待成功响应，返回目标一侧的连接
+    async fn open_stream(
+        tunnel: &Arc<Tunnel>,
+        messages: &mut mpsc::Receiver<WsMessage>,
+        listener: &TcpListener,
+        stream_id: u32,
+    ) -> TcpStream {
+        assert!(tunnel.dispatch(proxy_request(stream_id, listener.local_addr().unwrap())));
+        let (target, _) = listener.accept().await.unwrap();
+        match messages.recv().await.unwrap() {
+            WsMessage::ProxyResponse { stream_id: id, response } => {
+                assert_eq!(id, stream_id);
+                assert!(response.success, "{}", response.message);
+            }
+            other => panic!("意外的消息 {:?}", other),
+        }
+        target
+    }
+
+    async fn recv_close(messages: &mut mpsc::Receiver<WsMessage>, stream_id: u32) {
+        match messages.recv().await.unwrap() {
+            WsMessage::Close { stream_id: id, .. } => assert_eq!(id, stream_id),
+            other => panic!("意外的消息 {:?}", other),
+        }
+    }
+
+    /// 接收一个流的数据直到累计 `len` 字节
+    async fn recv_data(messages: &mut mpsc::Receiver<WsMessage>, stream_id: u32, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            match messages.recv().await.unwrap() {
+                WsMessage::Data { stream_id: id, data } if id == stream_id => received.extend(data),
+                other => panic!("意外的消息 {:?}", other),
+            }
+        }
+        received
+    }
+
+    /// 流的任务结束后句柄随即移除
+    async fn wait_removed(tunnel: &Tunnel, stream_id: u32) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tunnel.streams.lock().unwrap().contains_key(&stream_id) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("流没有被移除");
+    }
+
+    #[tokio::test]
+    async fn test_stream_removed_when_target_fails() {
+        let (tunnel, mut messages) = tunnel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        assert!(tunnel.dispatch(proxy_request(1, target_addr)));
+        match messages.recv().await.unwrap() {
+            WsMessage::ProxyResponse { response, .. } => assert!(!response.success),
+            other => panic!("意外的消息 {:?}", other),
+        }
+        wait_removed(&tunnel, 1).await;
+    }
+
+    #[tokio::test]
+    async fn test_stream_removed_when_target_finishes() {
+        let (tunnel, mut messages) = tunnel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut target = open_stream(&tunnel, &mut messages, &listener, 1).await;
+
+        assert!(tunnel.dispatch(WsMessage::Data { stream_id: 1, data: b"ping".to_vec() }));
+        let mut buf = [0u8; 4];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        target.write_all(b"pong").await.unwrap();
+        assert_eq!(recv_data(&mut messages, 1, 4).await, b"pong");
+
+        // 目标关闭连接，客户端没有发送 Close
+        drop(target);
+        recv_close(&mut messages, 1).await;
+        wait_removed(&tunnel, 1).await;
+    }
+
+    #[tokio::test]
+    async fn test_window_stall_and_update() {
+        let (tunnel, mut messages) = tunnel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = open_stream(&tunnel, &mut messages, &listener, 1).await;
+
+        let total = INITIAL_WINDOW as usize + MAX_DATA_CHUNK;
+        let sent: Vec<u8> = (0..total).map(|i| i as u8).collect();
+        let (mut target_read, mut target_write) = target.into_split();
+        let writer = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                target_write.write_all(&sent).await.unwrap();
+                target_write
+            }
+        });
+
+        // 没有窗口更新时最多发出一个初始窗口的数据
+        let mut received = Vec::new();
+        while let Ok(message) = tokio::time::timeout(Duration::from_millis(200), messages.recv()).await {
+            match message.unwrap() {
+                WsMessage::Data { data, .. } => received.extend(data),
+                other => panic!("意外的消息 {:?}", other),
+            }
+        }
+        assert!(received.len() <= INITIAL_WINDOW as usize);
+        assert!(received.len() > INITIAL_WINDOW as usize - MAX_DATA_CHUNK);
+
+        // 窗口更新后剩余的数据继续发出
+        assert!(tunnel.dispatch(WsMessage::WindowUpdate { stream_id: 1, increment: MAX_DATA_CHUNK as u32 }));
+        received.extend(recv_data(&mut messages, 1, total - received.len()).await);
+        assert_eq!(received, sent);
+        // 写半边释放时目标关闭连接，保留到测试结束
+        let _target_write = writer.await.unwrap();
+
+        // 反方向：目标读走半个窗口的数据后服务器归还窗口
+        for _ in 0..INITIAL_WINDOW as usize / 2 / MAX_DATA_CHUNK {
+            assert!(tunnel.dispatch(WsMessage::Data { stream_id: 1, data: vec![7; MAX_DATA_CHUNK] }));
+        }
+        let mut buf = vec![0u8; INITIAL_WINDOW as usize / 2];
+        target_read.read_exact(&mut buf).await.unwrap();
+        match messages.recv().await.unwrap() {
+            WsMessage::WindowUpdate { stream_id, increment } => assert_eq!((stream_id, increment), (1, INITIAL_WINDOW / 2)),
+            other => panic!("意外的消息 {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_window_violation_resets_stream() {
+        let (tunnel, mut messages) = tunnel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut target = open_stream(&tunnel, &mut messages, &listener, 1).await;
+
+        // 客户端不等窗口更新，多发一块数据；分发是同步的，流的任务来不及归还窗口
+        let chunks = INITIAL_WINDOW as usize / MAX_DATA_CHUNK + 1;
+        for _ in 0..chunks {
+            assert!(tunnel.dispatch(WsMessage::Data { stream_id: 1, data: vec![0; MAX_DATA_CHUNK] }));
+        }
+        assert!(!tunnel.streams.lock().unwrap().contains_key(&1));
+
+        // 窗口内的数据照常写给目标，之后流结束
+        let mut written = Vec::new();
+        target.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written.len(), INITIAL_WINDOW as usize);
+        loop {
+            match messages.recv().await.unwrap() {
+                WsMessage::WindowUpdate { .. } => {}
+                WsMessage::Close { stream_id, .. } => break assert_eq!(stream_id, 1),
+                other => panic!("意外的消息 {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_many_streams() {
+        let (tunnel, mut messages) = tunnel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // 每个流的目标原样回显收到的数据
+        for stream_id in 1..=16 {
+            let target = open_stream(&tunnel, &mut messages, &listener, stream_id).await;
+            tokio::spawn(async move {
+                let (mut read, mut write) = target.into_split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+
+        // 交错发送各流的数据，回显的数据不会串到别的流
+        for round in 0..3u8 {
+            for stream_id in 1..=16u32 {
+                assert!(tunnel.dispatch(WsMessage::Data { stream_id, data: vec![stream_id as u8 ^ round; 1000] }));
+            }
+        }
+        let mut echoed: HashMap<u32, Vec<u8>> = HashMap::new();
+        while echoed.values().map(Vec::len).sum::<usize>() < 16 * 3000 {
+            match messages.recv().await.unwrap() {
+                WsMessage::Data { stream_id, data } => echoed.entry(stream_id).or_default().extend(data),
+                other => panic!("意外的消息 {:?}", other),
+            }
+        }
+        for (stream_id, data) in &echoed {
+            let expected: Vec<u8> = (0..3u8).flat_map(|round| [*stream_id as u8 ^ round; 1000]).collect();
+            assert_eq!(data, &expected, "流 {}", stream_id);
+        }
+
+        // 客户端关闭流后句柄立即移除，流的任务随后结束
+        for stream_id in 1..=16 {
+            assert!(tunnel.dispatch(WsMessage::Close { stream_id, reason: None }));
+        }
+        assert!(tunnel.streams.lock().unwrap().is_empty());
+        for _ in 1..=16 {
+            assert!(matches!(messages.recv().await.unwrap(), WsMessage::Close { .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quota_exhausted_during_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let request = ProxyRequest {
+            target_addr: listener.local_addr().unwrap().into(),
+            command: ProxyCommand::Connect,
+            user: None,
+        };
+        let limits = Limits {
+            daily_quota: Some(4),
+            ..Limits::default()
+        };
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let (outbound, mut messages) = mpsc::channel(OUTBOUND_QUEUE);
+        let send_window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        let unacked = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(handle_proxy_stream(1, request, session(limits), data_rx, send_window, unacked, outbound));
+
+        let (mut target, _) = listener.accept().await.unwrap();
+        match messages.recv().await.unwrap() {
+            WsMessage::ProxyResponse { response, .. } => assert!(response.success),
+            other => panic!("意外的消息 {:?}", other),
+        }
+
+        // 第一块数据用完配额，之后的数据不再转发
+        data_tx.send(b"ping".to_vec()).unwrap();
+        let mut buf = [0u8; 4];
+        target.read_exact(&mut buf).await.unwrap();
+        data_tx.send(b"more".to_vec()).unwrap();
+
+        // 客户端从 Close 消息中得知原因
+        match messages.recv().await.unwrap() {
+            WsMessage::Close { stream_id, reason } => {
+                assert_eq!(stream_id, 1);
+                let reason = reason.unwrap();
+                assert!(reason.contains("alice") && reason.contains("每日流量配额"), "{}", reason);
+            }
+            other => panic!("意外的消息 {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,49 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use leaf_protocol::auth::SocksAuth;
+use leaf_protocol::config;
+
+/// 配置文件，配置项与同名的命令行参数一致，命令行参数优先
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// 监听地址，可以写一个或多个
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub listen_addr: Vec<SocketAddr>,
+    /// 用户凭据，格式为 `用户名:密码`
+    pub users: Vec<String>,
+    pub require_auth: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: vec!["127.0.0.1:1080".parse().unwrap()],
+            users: Vec::new(),
+            require_auth: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        config::load(path)
+    }
+
+    /// `--print-default-config` 输出的内容
+    pub fn default_toml() -> Result<String> {
+        config::to_toml(&Self::default())
+    }
+
+    /// 检查合并命令行参数后的配置
+    pub fn validate(&self) -> Result<()> {
+        if self.listen_addr.is_empty() {
+            return Err(anyhow!("listen_addr 至少需要一个地址"));
+        }
+        SocksAuth::from_entries(&self.users).context("配置项 users 无效")?;
+        Ok(())
+    }
+}
@@ -1,39 +1,102 @@
-use anyhow::{anyhow, Result};
-use bytes::{Buf, BufMut, BytesMut};
-use log::{error, info, warn};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use log::{error, info};
+use leaf_protocol::auth::SocksAuth;
+use leaf_protocol::socks5::{self, Reply};
+use leaf_protocol::{ProxyCommand, TargetAddr};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    task::JoinSet,
 };
 
-const SOCKS_VERSION: u8 = 0x05;
-const NO_AUTHENTICATION: u8 = 0x00;
-const CONNECT_COMMAND: u8 = 0x01;
-const IPV4_ADDRESS: u8 = 0x01;
-const DOMAIN_NAME: u8 = 0x03;
-const IPV6_ADDRESS: u8 = 0x04;
-
-#[derive(Debug)]
-enum AddressType {
-    Ipv4(Ipv4Addr, u16),
-    Ipv6(Ipv6Addr, u16),
-    Domain(String, u16),
+mod config;
+
+use config::Config;
+
+#[derive(Parser)]
+#[command(name = "socks5")]
+#[command(about = "Standalone SOCKS5 proxy server")]
+struct Args {
+    /// Configuration file (TOML); command-line flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Print the default configuration file and exit
+    #[arg(long)]
+    print_default_config: bool,
+
+    /// Listen address, repeatable [default: 127.0.0.1:1080]
+    #[arg(short = 'l', long)]
+    listen_addr: Vec<SocketAddr>,
+
+    /// User credentials as user:password (repeatable); enables RFC 1929 auth
+    #[arg(short = 'u', long = "user")]
+    users: Vec<String>,
+
+    /// Refuse to listen on a non-loopback address without credentials
+    #[arg(long)]
+    require_auth: bool,
+}
+
+impl Args {
+    /// 读取配置文件 (如果指定了) 并用命令行参数覆盖
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen_addr.is_empty() {
+            config.listen_addr = self.listen_addr;
+        }
+        if !self.users.is_empty() {
+            config.users = self.users;
+        }
+        config.require_auth |= self.require_auth;
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
-    
-    let listener = TcpListener::bind("127.0.0.1:1080").await?;
-    info!("SOCKS5 代理服务器启动在 127.0.0.1:1080");
+    let args = Args::parse();
 
+    if args.print_default_config {
+        print!("{}", Config::default_toml()?);
+        return Ok(());
+    }
+
+    let config = args.into_config()?;
+    let auth = Arc::new(SocksAuth::from_entries(&config.users)?);
+    
+    let mut listeners = JoinSet::new();
+    for listen_addr in &config.listen_addr {
+        let listener = TcpListener::bind(listen_addr)
+            .await
+            .with_context(|| format!("无法监听 {}", listen_addr))?;
+        auth.check_listener(listener.local_addr()?, config.require_auth)?;
+        info!("SOCKS5 代理服务器启动在 {}", listen_addr);
+        listeners.spawn(accept_loop(listener, auth.clone()));
+    }
+
+    listeners.join_all().await;
+    Ok(())
+}
+
+async fn accept_loop(listener: TcpListener, auth: Arc<SocksAuth>) {
     loop {
         match listener.accept().await {
             Ok((socket, addr)) => {
                 info!("新连接来自: {}", addr);
+                let auth = auth.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_connection(socket).await {
+                    if let Err(e) = handle_connection(socket, auth).await {
                         error!("处理连接时出错: {}", e);
                     }
                 });
@@ -45,18 +108,33 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn handle_connection(mut client: TcpStream) -> Result<()> {
-    // 处理握手
-    handle_handshake(&mut client).await?;
+async fn handle_connection(mut client: TcpStream, auth: Arc<SocksAuth>) -> Result<()> {
+    // 处理握手，启用认证时得到用户名
+    let user = socks5::handshake(&mut client, &auth).await?;
     
     // 处理请求
-    let target_addr = handle_request(&mut client).await?;
+    let request = socks5::read_request(&mut client).await?;
+    if request.command != ProxyCommand::Connect {
+        socks5::send_failure(&mut client, Reply::CommandNotSupported).await?;
+        return Err(anyhow!("不支持的命令: {:?}", request.command));
+    }
+    let target_addr = request.target_addr;
+    info!("目标地址: {}", target_addr);
     
     // 连接到目标服务器
-    let mut target = TcpStream::connect(target_addr).await?;
+    let target = match connect_target(&target_addr).await {
+        Ok(target) => target,
+        Err(e) => {
+            socks5::send_failure(&mut client, Reply::GeneralFailure).await?;
+            return Err(anyhow!("连接目标服务器失败: {} - {}", target_addr, e));
+        }
+    };
+    if let Some(user) = user {
+        info!("用户 {} 连接到 {}", user, target_addr);
+    }
     
     // 发送成功响应
-    send_success_response(&mut client).await?;
+    socks5::send_reply(&mut client, Reply::Succeeded, &socks5::unspecified_addr()).await?;
     
     // 开始转发数据
     forward_data(client, target).await?;
@@ -64,115 +142,13 @@ async fn handle_connection(mut client: TcpStream) -> Result<()> {
     Ok(())
 }
 
-async fn handle_handshake(client: &mut TcpStream) -> Result<()> {
-    let mut buf = [0u8; 2];
-    client.read_exact(&mut buf).await?;
-    
-    let version = buf[0];
-    let nmethods = buf[1];
-    
-    if version != SOCKS_VERSION {
-        return Err(anyhow!("不支持的SOCKS版本: {}", version));
-    }
-    
-    let mut methods = vec![0u8; nmethods as usize];
-    client.read_exact(&mut methods).await?;
-    
-    // 检查是否支持无认证方法
-    if !methods.contains(&NO_AUTHENTICATION) {
-        // 发送不支持认证方法的响应
-        let response = [SOCKS_VERSION, 0xFF];
-        client.write_all(&response).await?;
-        return Err(anyhow!("客户端不支持无认证方法"));
+/// 连接到目标地址；域名在本地解析，依次尝试解析出的每个地址
+async fn connect_target(target_addr: &TargetAddr) -> std::io::Result<TcpStream> {
+    match target_addr {
+        TargetAddr::Ipv4(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Ipv6(ip, port) => TcpStream::connect((*ip, *port)).await,
+        TargetAddr::Domain(domain, port) => TcpStream::connect((domain.as_str(), *port)).await,
     }
-    
-    // 发送选择无认证方法的响应
-    let response = [SOCKS_VERSION, NO_AUTHENTICATION];
-    client.write_all(&response).await?;
-    
-    info!("握手成功");
-    Ok(())
-}
-
-async fn handle_request(client: &mut TcpStream) -> Result<SocketAddr> {
-    let mut buf = [0u8; 4];
-    client.read_exact(&mut buf).await?;
-    
-    let version = buf[0];
-    let command = buf[1];
-    let _reserved = buf[2];
-    let address_type = buf[3];
-    
-    if version != SOCKS_VERSION {
-        return Err(anyhow!("不支持的SOCKS版本: {}", version));
-    }
-    
-    if command != CONNECT_COMMAND {
-        return Err(anyhow!("不支持的命令: {}", command));
-    }
-    
-    let target_addr = match address_type {
-        IPV4_ADDRESS => {
-            let mut addr_buf = [0u8; 4];
-            client.read_exact(&mut addr_buf).await?;
-            let ip = Ipv4Addr::new(addr_buf[0], addr_buf[1], addr_buf[2], addr_buf[3]);
-            
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-            
-            SocketAddr::from((ip, port))
-        }
-        DOMAIN_NAME => {
-            let mut len_buf = [0u8; 1];
-            client.read_exact(&mut len_buf).await?;
-            let domain_len = len_buf[0] as usize;
-            
-            let mut domain_buf = vec![0u8; domain_len];
-            client.read_exact(&mut domain_buf).await?;
-            let domain = String::from_utf8(domain_buf)?;
-            
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-            
-            info!("连接到域名: {}:{}", domain, port);
-            
-            // 解析域名
-            let addrs = tokio::net::lookup_host(format!("{}:{}", domain, port)).await?;
-            addrs.into_iter().next().ok_or_else(|| anyhow!("无法解析域名: {}", domain))?
-        }
-        IPV6_ADDRESS => {
-            let mut addr_buf = [0u8; 16];
-            client.read_exact(&mut addr_buf).await?;
-            let ip = Ipv6Addr::from(addr_buf);
-            
-            let mut port_buf = [0u8; 2];
-            client.read_exact(&mut port_buf).await?;
-            let port = u16::from_be_bytes(port_buf);
-            
-            SocketAddr::from((ip, port))
-        }
-        _ => return Err(anyhow!("不支持的地址类型: {}", address_type)),
-    };
-    
-    info!("目标地址: {}", target_addr);
-    Ok(target_addr)
-}
-
-async fn send_success_response(client: &mut TcpStream) -> Result<()> {
-    // SOCKS5 成功响应格式
-    let response = [
-        SOCKS_VERSION,  // 版本
-        0x00,           // 状态码 (成功)
-        0x00,           // 保留字段
-        0x01,           // 地址类型 (IPv4)
-        0x00, 0x00, 0x00, 0x00,  // IP地址 (0.0.0.0)
-        0x00, 0x00,     // 端口 (0)
-    ];
-    
-    client.write_all(&response).await?;
-    Ok(())
 }
 
 async fn forward_data(mut client: TcpStream, mut target: TcpStream) -> Result<()> {
@@ -183,7 +159,7 @@ async fn forward_data(mut client: TcpStream, mut target: TcpStream) -> Result<()
         let mut buf = [0u8; 8192];
         loop {
             let n = match client_read.read(&mut buf).await {
-                Ok(n) if n == 0 => break,
+                Ok(0) => break,
                 Ok(n) => n,
                 Err(_) => break,
             };
@@ -197,7 +173,7 @@ async fn forward_data(mut client: TcpStream, mut target: TcpStream) -> Result<()
         let mut buf = [0u8; 8192];
         loop {
             let n = match target_read.read(&mut buf).await {
-                Ok(n) if n == 0 => break,
+                Ok(0) => break,
                 Ok(n) => n,
                 Err(_) => break,
             };
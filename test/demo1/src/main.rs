@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 use std::collections::HashMap;
 use std::io::{stdin, stdout, Write};
 
@@ -226,11 +228,11 @@ fn user_menu(db_map: &mut HashMap<String, User>, email: String) {
                         }
                         
                         // 先检查源用户余额（只读访问）
-                        if let Some(user) = db_map.get(&email) {
-                            if amount > user.balance {
-                                println!("余额不足");
-                                continue;
-                            }
+                        if let Some(user) = db_map.get(&email)
+                            && amount > user.balance
+                        {
+                            println!("余额不足");
+                            continue;
                         }
                         
                         // 使用分步骤的方式避免借用冲突
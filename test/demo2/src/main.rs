@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 
 fn ince(x: &mut i32) {
     *x += 1;